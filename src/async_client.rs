@@ -0,0 +1,128 @@
+// Copyright (C) 2025  Tassilo Horn <tsdh@gnu.org>
+//
+// This program is free software: you can redistribute it and/or modify it
+// under the terms of the GNU General Public License as published by the Free
+// Software Foundation, either version 3 of the License, or (at your option)
+// any later version.
+//
+// This program is distributed in the hope that it will be useful, but WITHOUT
+// ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or
+// FITNESS FOR A PARTICULAR PURPOSE.  See the GNU General Public License for
+// more details.
+//
+// You should have received a copy of the GNU General Public License along with
+// this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! The async variants of the functions in [`client`](crate::client) for
+//! programs using tokio, e.g., status bars.
+
+use std::time::Duration;
+
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader, Lines};
+use tokio::net::UnixStream;
+use tokio::time::timeout;
+
+use crate::client::{
+    connect_error, encode_request, event_of, io_error, join_part,
+    no_response_error, read_response, response_timeout,
+};
+use crate::cmds::NiriusCmd;
+use crate::protocol::{NiriusError, NiriusEvent, NiriusResponse, WireFormat};
+use crate::util;
+
+/// Runs `fut` with the given timeout, if any.
+async fn with_timeout<T>(
+    duration: Option<Duration>,
+    fut: impl Future<Output = std::io::Result<T>>,
+) -> Result<T, NiriusError> {
+    match duration {
+        Some(duration) => timeout(duration, fut)
+            .await
+            .map_err(|_| io_error(std::io::ErrorKind::TimedOut.into()))?,
+        None => fut.await,
+    }
+    .map_err(io_error)
+}
+
+/// Connects to niriusd and sends the command as JSON.  Returns the lines of
+/// the responses.
+async fn send_request(
+    cmd: NiriusCmd,
+    write_timeout: Option<Duration>,
+) -> Result<Lines<BufReader<UnixStream>>, NiriusError> {
+    let socket_path = util::get_nirius_socket_path();
+    let mut stream = UnixStream::connect(&socket_path)
+        .await
+        .map_err(|e| connect_error(&socket_path, e))?;
+    let json = encode_request(cmd, WireFormat::Json)?;
+    with_timeout(write_timeout, async {
+        stream.write_all(&json).await?;
+        stream.shutdown().await
+    })
+    .await?;
+    Ok(BufReader::new(stream).lines())
+}
+
+/// Sends the command to niriusd and returns its response joined from the
+/// parts niriusd sends.  If niriusd doesn't respond within the given timeout,
+/// the request fails.
+pub async fn send_nirius_cmd(
+    cmd: NiriusCmd,
+    timeout: Option<Duration>,
+) -> Result<NiriusResponse, NiriusError> {
+    let read_timeout = response_timeout(&cmd, timeout);
+    let mut lines = send_request(cmd, timeout).await?;
+    let mut resp = None;
+    loop {
+        match with_timeout(read_timeout, lines.next_line()).await? {
+            Some(line) if line.trim().is_empty() => (),
+            Some(line) => {
+                let received =
+                    read_response(WireFormat::Json, line.as_bytes())?;
+                if join_part(&mut resp, received).is_break() {
+                    return resp.ok_or_else(no_response_error);
+                }
+            }
+            None => return Err(no_response_error()),
+        }
+    }
+}
+
+/// The nirius events received from niriusd, see [`subscribe_events`].
+pub struct EventStream {
+    lines: Lines<BufReader<UnixStream>>,
+}
+
+impl EventStream {
+    /// Returns the next event or `None` if niriusd has stopped.
+    pub async fn next(&mut self) -> Option<Result<NiriusEvent, NiriusError>> {
+        loop {
+            let line = match self.lines.next_line().await {
+                Ok(Some(line)) => line,
+                Ok(None) => return None,
+                Err(err) => return Some(Err(io_error(err))),
+            };
+            if line.trim().is_empty() {
+                continue;
+            }
+            match read_response(WireFormat::Json, line.as_bytes()) {
+                Ok(resp) => {
+                    if let Some(event) = event_of(resp) {
+                        return Some(event);
+                    }
+                }
+                Err(err) => return Some(Err(err)),
+            }
+        }
+    }
+}
+
+/// Subscribes to nirius events.  The timeout only applies to sending the
+/// subscription since events may be rare.
+pub async fn subscribe_events(
+    timeout: Option<Duration>,
+) -> Result<EventStream, NiriusError> {
+    Ok(EventStream {
+        lines: send_request(NiriusCmd::Events, timeout).await?,
+    })
+}
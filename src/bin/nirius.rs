@@ -15,32 +15,335 @@
 
 //! The `nirius` binary.
 
-use clap::Parser;
-use nirius::cmds;
+use std::io::Write;
+use std::ops::ControlFlow;
+use std::process::ExitCode;
+use std::time::Duration;
+
+use clap::CommandFactory;
+use nirius::protocol::{ErrorCode, NiriusError, NiriusResponse};
+use nirius::{cmds, util};
 
 #[derive(clap::Parser)]
 #[clap(about, version, author)]
 struct Opts {
+    /// The socket of niriusd to talk to instead of the one derived from
+    /// XDG_RUNTIME_DIR and WAYLAND_DISPLAY
+    #[clap(long, global = true)]
+    socket: Option<String>,
+    /// Talk to the niriusd started with the same --niri-socket.  Can also be
+    /// given in NIRIUS_NIRI_SOCKET.
+    #[clap(long, global = true)]
+    niri_socket: Option<String>,
+    /// How long to wait for niriusd to respond, e.g., 500ms or 10s.  0
+    /// disables the timeout.  Commands like wait-for --timeout get this on
+    /// top of their own timeout.
+    #[clap(
+        long,
+        global = true,
+        default_value = "5s",
+        value_parser = util::parse_duration
+    )]
+    request_timeout: Duration,
+    /// If niriusd can't be reached, execute focus, focus-or-spawn, gather,
+    /// move-to-current-workspace and move-to-current-workspace-or-spawn by
+    /// querying niri directly
+    #[clap(long, global = true)]
+    no_daemon: bool,
+    /// Start niriusd if it's not running
+    #[clap(long, global = true)]
+    start_daemon: bool,
+    /// Don't print anything if the command succeeds.  Errors are still
+    /// printed on stderr.
+    #[clap(short = 'q', long, global = true)]
+    quiet: bool,
+    /// Talk to niriusd using MessagePack instead of JSON which is a bit
+    /// faster
+    #[cfg(feature = "msgpack")]
+    #[clap(long, global = true)]
+    msgpack: bool,
     #[clap(subcommand)]
     command: cmds::NiriusCmd,
 }
 
-fn main() -> Result<(), String> {
-    let opts: Opts = Opts::parse();
-    match nirius::client::send_nirius_cmd(opts.command) {
-        Ok(val) => {
-            let str = val.trim();
-            if !str.is_empty() {
-                println!("{str}");
+/// Maps the error code to the exit code of nirius.  These are documented in
+/// the README and must not change.
+fn exit_code(code: ErrorCode) -> u8 {
+    match code {
+        ErrorCode::Failed => 1,
+        // The same exit code clap uses for usage errors.
+        ErrorCode::BadArguments => 2,
+        ErrorCode::NoMatchingWindow => 3,
+        ErrorCode::NoFocusedWindow => 4,
+        ErrorCode::NiriIpc => 5,
+        ErrorCode::DaemonUnreachable => 6,
+        ErrorCode::InvalidRequest | ErrorCode::VersionMismatch => 7,
+    }
+}
+
+/// Prints nirius events as JSON lines until niriusd stops or stdout is
+/// closed.
+fn print_events() -> ExitCode {
+    let mut stdout = std::io::stdout().lock();
+    let result = nirius::client::subscribe_events(|event| {
+        match serde_json::to_string(&event) {
+            Ok(json) if writeln!(stdout, "{json}").is_ok() => {
+                ControlFlow::Continue(())
             }
-            Ok(())
+            _ => ControlFlow::Break(()),
         }
+    });
+    match result {
+        Ok(()) => ExitCode::SUCCESS,
         Err(err) => {
-            let str = err.trim();
-            if !str.is_empty() {
-                eprintln!("{str}");
+            eprintln!("{}", err.message.trim());
+            ExitCode::from(exit_code(err.code))
+        }
+    }
+}
+
+/// Prints the completion script for the shell.  It's the one of clap's
+/// dynamic completion which runs nirius with `COMPLETE` set for completing.
+fn print_completions(shell: &str) -> ExitCode {
+    let shells = clap_complete::env::Shells::builtins();
+    let Some(completer) = shells.completer(shell) else {
+        eprintln!("Unsupported shell {shell}.");
+        return ExitCode::from(exit_code(ErrorCode::BadArguments));
+    };
+    let mut stdout = std::io::stdout().lock();
+    match completer.write_registration(
+        "COMPLETE",
+        "nirius",
+        "nirius",
+        "nirius",
+        &mut stdout,
+    ) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(err) => {
+            eprintln!("{err}");
+            ExitCode::FAILURE
+        }
+    }
+}
+
+/// Writes the man pages of nirius and niriusd into the directory.
+fn generate_man(dir: &std::path::Path, quiet: bool) -> ExitCode {
+    let result = std::fs::create_dir_all(dir)
+        .and_then(|()| clap_mangen::generate_to(Opts::command(), dir))
+        .and_then(|()| {
+            clap_mangen::generate_to(nirius::daemon::DaemonOpts::command(), dir)
+        });
+    match result {
+        Ok(()) => {
+            if !quiet {
+                println!("Wrote man pages to {}.", dir.display());
             }
-            Err("Command failed".to_owned())
+            ExitCode::SUCCESS
+        }
+        Err(err) => {
+            eprintln!("Cannot write man pages to {}: {err}", dir.display());
+            ExitCode::FAILURE
         }
     }
 }
+
+/// Prints the problems of the config, or that it's valid.
+fn validate_config(path: Option<&std::path::Path>, quiet: bool) -> ExitCode {
+    match nirius::validate::validate_config(path) {
+        Ok(msg) => {
+            if !quiet {
+                println!("{msg}");
+            }
+            ExitCode::SUCCESS
+        }
+        Err(err) => {
+            eprintln!("{err}");
+            ExitCode::from(exit_code(ErrorCode::Failed))
+        }
+    }
+}
+
+/// Prints the output of `waybar` whenever it changes until niriusd stops.
+fn watch_waybar(timeout: Option<Duration>) -> ExitCode {
+    let mut last = None;
+    let mut print = || -> Result<ControlFlow<()>, NiriusError> {
+        let out = nirius::client::send_nirius_cmd(
+            cmds::NiriusCmd::Waybar { watch: false },
+            timeout,
+        )?
+        .into_result()
+        .map_err(|e| NiriusError::new(ErrorCode::Failed, e))?;
+        if last.as_ref() != Some(&out) {
+            let mut stdout = std::io::stdout().lock();
+            if writeln!(stdout, "{out}")
+                .and_then(|()| stdout.flush())
+                .is_err()
+            {
+                return Ok(ControlFlow::Break(()));
+            }
+            last = Some(out);
+        }
+        Ok(ControlFlow::Continue(()))
+    };
+    let result = match print() {
+        Ok(ControlFlow::Continue(())) => {
+            nirius::client::subscribe_events(|_| match print() {
+                Ok(flow) => flow,
+                Err(err) => {
+                    eprintln!("{}", err.message.trim());
+                    ControlFlow::Break(())
+                }
+            })
+        }
+        Ok(ControlFlow::Break(())) => Ok(()),
+        Err(err) => Err(err),
+    };
+    match result {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(err) => {
+            eprintln!("{}", err.message.trim());
+            ExitCode::from(exit_code(err.code))
+        }
+    }
+}
+
+fn main() -> ExitCode {
+    // Answers the completion requests of the scripts printed by completions.
+    clap_complete::CompleteEnv::with_factory(Opts::command).complete();
+    let opts: Opts = cmds::parse_with_defaults(std::env::args().collect())
+        .unwrap_or_else(|e| e.exit());
+    if let Some(socket) = opts.socket {
+        util::set_nirius_socket_path(socket);
+    }
+    if let Some(niri_socket) = opts.niri_socket {
+        util::set_niri_socket_path(niri_socket);
+    }
+    #[cfg(feature = "msgpack")]
+    if opts.msgpack {
+        nirius::client::set_wire_format(
+            nirius::protocol::WireFormat::MessagePack,
+        );
+    }
+    // Answer this one without the daemon so that it works when it's not
+    // running.
+    if opts.command == cmds::NiriusCmd::PrintSocketPath {
+        println!("{}", util::get_nirius_socket_path());
+        return ExitCode::SUCCESS;
+    }
+    if let cmds::NiriusCmd::Completions { shell } = &opts.command {
+        return print_completions(shell);
+    }
+    if let cmds::NiriusCmd::GenerateMan { dir } = &opts.command {
+        return generate_man(dir, opts.quiet);
+    }
+    if let cmds::NiriusCmd::ValidateConfig { path } = &opts.command {
+        return validate_config(path.as_deref(), opts.quiet);
+    }
+    if opts.start_daemon
+        && let Err(err) = nirius::client::ensure_daemon()
+    {
+        eprintln!("{}", err.message);
+    }
+    if opts.command == cmds::NiriusCmd::Events {
+        return print_events();
+    }
+    let timeout = Some(opts.request_timeout).filter(|t| !t.is_zero());
+    if opts.command == (cmds::NiriusCmd::Waybar { watch: true }) {
+        return watch_waybar(timeout);
+    }
+    let json = opts.command.wants_json();
+    let exists = matches!(opts.command, cmds::NiriusCmd::Exists { .. });
+    let fallback_cmd = (opts.no_daemon && opts.command.works_without_daemon())
+        .then(|| opts.command.clone());
+    let result = match opts.command {
+        cmds::NiriusCmd::Menu {
+            picker,
+            action,
+            match_opts,
+        } => nirius::menu::menu(picker, action, match_opts, timeout),
+        cmds::NiriusCmd::Pick {
+            stdin,
+            action,
+            match_opts,
+        } => nirius::menu::pick(stdin, action, match_opts, timeout),
+        cmds::NiriusCmd::Rofi {
+            action,
+            match_opts,
+            selection,
+        } => nirius::menu::rofi(selection, action, match_opts, timeout),
+        cmd => nirius::client::send_nirius_cmd(cmd, timeout),
+    };
+    let resp = match result {
+        Ok(resp) => resp,
+        Err(err) => match fallback_cmd {
+            Some(cmd) if err.code == ErrorCode::DaemonUnreachable => {
+                cmds::exec_without_daemon(&cmd)
+            }
+            _ => {
+                eprintln!("{}", err.message.trim());
+                return ExitCode::from(exit_code(err.code));
+            }
+        },
+    };
+    // Only a failure to check is an error for exists.
+    if exists && resp.error_code() == Some(ErrorCode::NoMatchingWindow) {
+        return ExitCode::FAILURE;
+    }
+    let is_error = matches!(resp, NiriusResponse::Error { .. });
+    let out = if json && !is_error {
+        match resp.format_json() {
+            Ok(out) => out,
+            Err(err) => {
+                eprintln!("{err}");
+                return ExitCode::FAILURE;
+            }
+        }
+    } else {
+        resp.format_text()
+    };
+    let str = out.trim();
+    if !str.is_empty() && (is_error || !opts.quiet) {
+        if is_error {
+            eprintln!("{str}");
+        } else {
+            println!("{str}");
+        }
+    }
+    match resp.error_code() {
+        None => ExitCode::SUCCESS,
+        Some(code) => ExitCode::from(exit_code(code)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn exit_codes_are_stable() {
+        let codes = [
+            (ErrorCode::Failed, 1),
+            (ErrorCode::BadArguments, 2),
+            (ErrorCode::NoMatchingWindow, 3),
+            (ErrorCode::NoFocusedWindow, 4),
+            (ErrorCode::NiriIpc, 5),
+            (ErrorCode::DaemonUnreachable, 6),
+            (ErrorCode::InvalidRequest, 7),
+            (ErrorCode::VersionMismatch, 7),
+        ];
+        for (code, expected) in codes {
+            assert_eq!(exit_code(code), expected, "{code:?}");
+        }
+    }
+
+    #[test]
+    fn bad_arguments_exit_like_usage_errors() {
+        let err = Opts::command()
+            .try_get_matches_from(["nirius", "--no-such-option"])
+            .unwrap_err();
+        assert_eq!(
+            i32::from(exit_code(ErrorCode::BadArguments)),
+            err.exit_code()
+        );
+    }
+}
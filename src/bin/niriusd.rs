@@ -15,11 +15,28 @@
 
 //! The `niriusd` binary.
 
-use env_logger::Env;
-use nirius::daemon;
+use std::io::IsTerminal;
 
-fn main() {
-    env_logger::Builder::from_env(Env::default().default_filter_or("warn"))
+use clap::Parser;
+use nirius::daemon::{self, DaemonOpts};
+use nirius::util;
+use tracing_subscriber::EnvFilter;
+
+fn main() -> Result<(), String> {
+    let opts = DaemonOpts::parse();
+    tracing_subscriber::fmt()
+        .with_writer(std::io::stderr)
+        .with_ansi(std::io::stderr().is_terminal())
+        .with_env_filter(
+            EnvFilter::try_from_default_env()
+                .unwrap_or_else(|_| EnvFilter::new("warn")),
+        )
         .init();
-    crate::daemon::run_daemon();
+    if let Some(socket) = opts.socket {
+        util::set_nirius_socket_path(socket);
+    }
+    if let Some(niri_socket) = opts.niri_socket {
+        util::set_niri_socket_path(niri_socket);
+    }
+    crate::daemon::run_daemon(opts.replace)
 }
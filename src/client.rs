@@ -13,15 +13,336 @@
 // You should have received a copy of the GNU General Public License along with
 // this program.  If not, see <https://www.gnu.org/licenses/>.
 
+use std::io::{BufReader, ErrorKind, Write};
+use std::ops::ControlFlow;
 use std::os::unix::net::UnixStream;
+use std::os::unix::process::CommandExt;
+use std::sync::OnceLock;
+use std::time::Duration;
 
-pub fn send_nirius_cmd(cmd: crate::cmds::NiriusCmd) -> Result<String, String> {
-    let stream = UnixStream::connect(crate::util::get_nirius_socket_path())
-        .map_err(|e| e.to_string())?;
-    serde_json::to_writer(&stream, &cmd).map_err(|e| e.to_string())?;
+use serde::de::IgnoredAny;
+
+use crate::cmds::NiriusCmd;
+use crate::protocol::{
+    self, ErrorCode, NiriusError, NiriusEvent, NiriusResponse,
+    PROTOCOL_VERSION, WireFormat,
+};
+
+/// The wire format given by `--msgpack`.
+static WIRE_FORMAT: OnceLock<WireFormat> = OnceLock::new();
+
+/// Sets the format requests are sent in.  The default is JSON.
+pub fn set_wire_format(format: WireFormat) {
+    let _ = WIRE_FORMAT.set(format);
+}
+
+/// Returns how long to wait for the response to the given command.  Waiting
+/// commands get the given timeout on top of their own.
+pub(crate) fn response_timeout(
+    cmd: &NiriusCmd,
+    timeout: Option<Duration>,
+) -> Option<Duration> {
+    match cmd {
+        NiriusCmd::WaitFor { timeout: wait, .. } => Some((*wait)? + timeout?),
+        NiriusCmd::Events => None,
+        _ => timeout,
+    }
+}
+
+pub(crate) fn io_error(e: std::io::Error) -> NiriusError {
+    match e.kind() {
+        ErrorKind::WouldBlock | ErrorKind::TimedOut => NiriusError::new(
+            ErrorCode::DaemonUnreachable,
+            "Timed out waiting for niriusd.  It seems to hang, so consider \
+             restarting it.",
+        ),
+        _ => NiriusError::new(ErrorCode::Failed, e.to_string()),
+    }
+}
+
+pub(crate) fn connect_error(
+    socket_path: &str,
+    e: std::io::Error,
+) -> NiriusError {
+    NiriusError::new(
+        ErrorCode::DaemonUnreachable,
+        format!("Cannot connect to niriusd at {socket_path}: {e}"),
+    )
+}
+
+pub(crate) fn encode_request(
+    cmd: NiriusCmd,
+    format: WireFormat,
+) -> Result<Vec<u8>, NiriusError> {
+    let req = protocol::Request {
+        version: PROTOCOL_VERSION,
+        cmd,
+    };
+    format
+        .encode(&req)
+        .map_err(|e| NiriusError::new(ErrorCode::Failed, e))
+}
+
+/// Reads a response message in the given format.
+pub(crate) fn read_response(
+    format: WireFormat,
+    msg: &[u8],
+) -> Result<NiriusResponse, NiriusError> {
+    // Daemons from before the protocol had a version answer without the
+    // envelope.
+    let resp: protocol::Response<IgnoredAny> =
+        format.decode(msg).map_err(|e| {
+            NiriusError::new(
+                ErrorCode::VersionMismatch,
+                format!(
+                    "Could not read response from niriusd: {e}\n\
+                     If you've upgraded nirius, please restart niriusd."
+                ),
+            )
+        })?;
+    if resp.version != PROTOCOL_VERSION {
+        return Err(NiriusError::new(
+            ErrorCode::VersionMismatch,
+            protocol::version_mismatch_message(PROTOCOL_VERSION, resp.version),
+        ));
+    }
+    let resp: protocol::Response<NiriusResponse> =
+        format.decode(msg).map_err(|e| {
+            NiriusError::new(
+                ErrorCode::Failed,
+                format!("Could not read response from niriusd: {e}"),
+            )
+        })?;
+    Ok(resp.response)
+}
+
+/// Sends the command to niriusd and calls `f` with every response it sends
+/// until niriusd closes the connection or `f` breaks.  If niriusd doesn't
+/// respond within the given timeout, the request fails.
+pub fn send_nirius_cmd_streaming(
+    cmd: NiriusCmd,
+    timeout: Option<Duration>,
+    mut f: impl FnMut(NiriusResponse) -> ControlFlow<()>,
+) -> Result<(), NiriusError> {
+    let socket_path = crate::util::get_nirius_socket_path();
+    let mut stream = UnixStream::connect(&socket_path)
+        .map_err(|e| connect_error(&socket_path, e))?;
+    stream.set_write_timeout(timeout).map_err(io_error)?;
+    stream
+        .set_read_timeout(response_timeout(&cmd, timeout))
+        .map_err(io_error)?;
+    let format = WIRE_FORMAT.get().copied().unwrap_or_default();
+    stream
+        .write_all(&encode_request(cmd, format)?)
+        .map_err(io_error)?;
     stream
         .shutdown(std::net::Shutdown::Write)
-        .map_err(|e| e.to_string())?;
-    serde_json::from_reader::<_, Result<String, String>>(&stream)
-        .expect("Could not read response from niriusd")
+        .map_err(io_error)?;
+
+    // niriusd answers in the request's format unless it doesn't support it.
+    let mut reader = BufReader::new(stream);
+    while let Some((format, msg)) =
+        protocol::read_message(&mut reader).map_err(io_error)?
+    {
+        if !msg.trim_ascii().is_empty()
+            && f(read_response(format, &msg)?).is_break()
+        {
+            break;
+        }
+    }
+    Ok(())
+}
+
+/// Sends the command to niriusd and returns its response joined from the
+/// parts niriusd sends.  If niriusd doesn't respond within the given timeout,
+/// the request fails.
+pub fn send_nirius_cmd(
+    cmd: NiriusCmd,
+    timeout: Option<Duration>,
+) -> Result<NiriusResponse, NiriusError> {
+    let mut resp = None;
+    send_nirius_cmd_streaming(cmd, timeout, |r| join_part(&mut resp, r))?;
+    resp.ok_or_else(no_response_error)
+}
+
+/// Joins a response received from niriusd with the parts received before.
+/// Breaks when the response is complete, i.e., `received` isn't a
+/// [`NiriusResponse::Part`].  Errors replace the parts received so far.
+pub(crate) fn join_part(
+    resp: &mut Option<NiriusResponse>,
+    received: NiriusResponse,
+) -> ControlFlow<()> {
+    let (received, complete) = match received {
+        NiriusResponse::Part(part) => (*part, false),
+        received => (received, true),
+    };
+    match resp {
+        Some(resp) if received.is_success() => resp.append(received),
+        _ => *resp = Some(received),
+    }
+    if complete {
+        ControlFlow::Break(())
+    } else {
+        ControlFlow::Continue(())
+    }
+}
+
+/// The error if niriusd closed the connection without answering like
+/// daemons from before the protocol had a version do.
+pub(crate) fn no_response_error() -> NiriusError {
+    NiriusError::new(
+        ErrorCode::VersionMismatch,
+        "niriusd closed the connection without answering.  If you've \
+         upgraded nirius, please restart niriusd.",
+    )
+}
+
+/// How long [`ensure_daemon`] waits for a started niriusd to listen.
+const DAEMON_START_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// Starts niriusd if nobody listens on its socket and waits until it does.
+/// The daemon is started from the same directory as the running executable
+/// if it's there, or from the `PATH` otherwise, and logs to `niriusd.log`
+/// in nirius' state directory.
+pub fn ensure_daemon() -> Result<(), NiriusError> {
+    let socket_path = crate::util::get_nirius_socket_path();
+    if UnixStream::connect(&socket_path).is_ok() {
+        return Ok(());
+    }
+    let failed =
+        |msg: String| NiriusError::new(ErrorCode::DaemonUnreachable, msg);
+
+    let niriusd = std::env::current_exe()
+        .ok()
+        .map(|exe| exe.with_file_name("niriusd"))
+        .filter(|path| path.exists())
+        .unwrap_or_else(|| "niriusd".into());
+    let mut cmd = std::process::Command::new(&niriusd);
+    cmd.arg("--socket").arg(&socket_path);
+    if let Some(niri_socket) = crate::util::get_niri_socket_override() {
+        cmd.arg("--niri-socket").arg(niri_socket);
+    }
+    let log = crate::util::get_nirius_state_dir()
+        .and_then(|dir| {
+            std::fs::create_dir_all(&dir).map_err(|e| e.to_string())?;
+            std::fs::OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(dir.join("niriusd.log"))
+                .map_err(|e| e.to_string())
+        })
+        .map(std::process::Stdio::from)
+        .unwrap_or_else(|_| std::process::Stdio::null());
+    // Its own process group so that it survives the terminal or keybinding
+    // which ran nirius.
+    let mut child = cmd
+        .stdin(std::process::Stdio::null())
+        .stdout(std::process::Stdio::null())
+        .stderr(log)
+        .process_group(0)
+        .spawn()
+        .map_err(|e| {
+            failed(format!("Cannot start {}: {e}", niriusd.display()))
+        })?;
+
+    let start = std::time::Instant::now();
+    while start.elapsed() < DAEMON_START_TIMEOUT {
+        if UnixStream::connect(&socket_path).is_ok() {
+            return Ok(());
+        }
+        if let Ok(Some(status)) = child.try_wait() {
+            return Err(failed(format!("niriusd exited with {status}.")));
+        }
+        std::thread::sleep(Duration::from_millis(20));
+    }
+    Err(failed(format!(
+        "niriusd didn't listen on {socket_path} within \
+         {DAEMON_START_TIMEOUT:?}."
+    )))
+}
+
+/// Returns the event of a response of the events subscription.
+pub(crate) fn event_of(
+    resp: NiriusResponse,
+) -> Option<Result<NiriusEvent, NiriusError>> {
+    match resp {
+        NiriusResponse::Event(event) => Some(Ok(event)),
+        NiriusResponse::Error { code, message } => {
+            Some(Err(NiriusError { code, message }))
+        }
+        resp => {
+            tracing::warn!("Ignoring unexpected response {resp:?}.");
+            None
+        }
+    }
+}
+
+/// Subscribes to nirius events and calls `f` with each of them until niriusd
+/// stops or `f` breaks.
+pub fn subscribe_events(
+    mut f: impl FnMut(NiriusEvent) -> ControlFlow<()>,
+) -> Result<(), NiriusError> {
+    let mut result = Ok(());
+    send_nirius_cmd_streaming(NiriusCmd::Events, None, |resp| match event_of(
+        resp,
+    ) {
+        Some(Ok(event)) => f(event),
+        Some(Err(err)) => {
+            result = Err(err);
+            ControlFlow::Break(())
+        }
+        None => ControlFlow::Continue(()),
+    })?;
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::WindowInfo;
+
+    fn window(id: u64) -> WindowInfo {
+        WindowInfo {
+            id,
+            app_id: None,
+            title: None,
+            workspace_id: None,
+            workspace_name: None,
+            output: None,
+            is_focused: false,
+            is_floating: false,
+            marks: vec![],
+            scratchpad: false,
+            follow_mode: false,
+        }
+    }
+
+    fn part(ids: &[u64]) -> NiriusResponse {
+        NiriusResponse::Part(Box::new(NiriusResponse::WindowList(
+            ids.iter().copied().map(window).collect(),
+        )))
+    }
+
+    #[test]
+    fn join_part_joins_parts_until_the_last_response() {
+        let mut resp = None;
+        assert!(join_part(&mut resp, part(&[1, 2])).is_continue());
+        assert!(join_part(&mut resp, part(&[3])).is_continue());
+        let last = NiriusResponse::WindowList(vec![window(4)]);
+        assert!(join_part(&mut resp, last).is_break());
+        let Some(NiriusResponse::WindowList(wins)) = resp else {
+            panic!("Unexpected response {resp:?}");
+        };
+        let ids: Vec<u64> = wins.iter().map(|w| w.id).collect();
+        assert_eq!(ids, [1, 2, 3, 4]);
+    }
+
+    #[test]
+    fn join_part_replaces_parts_by_errors() {
+        let mut resp = None;
+        assert!(join_part(&mut resp, part(&[1])).is_continue());
+        let err = NiriusResponse::failed("Boom.");
+        assert!(join_part(&mut resp, err).is_break());
+        assert_eq!(resp.and_then(|r| r.error_code()), Some(ErrorCode::Failed));
+    }
 }
@@ -13,14 +13,63 @@
 // You should have received a copy of the GNU General Public License along with
 // this program.  If not, see <https://www.gnu.org/licenses/>.
 
-use crate::{ipc, state::STATE};
-use niri_ipc::{Action, Request, Response, Window, WorkspaceReferenceArg};
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, PoisonError};
+use std::time::{Duration, Instant};
+
+use crate::{
+    completions,
+    config::{self, Corner, Length, get_config},
+    daemon, history, ipc,
+    layout::{self, LayoutSnapshot, WindowPlacement},
+    menu::{MenuAction, Picker},
+    protocol::{
+        self, ErrorCode, NiriusError, NiriusResponse, Status, WaybarModule,
+        WindowInfo, WorkspaceInfo,
+    },
+    state::{
+        self, MinimizedWindow, PendingPlacement, PipOrigin, Presentation,
+        State, StateImport, WINDOWS_CHANGED, Watcher,
+    },
+    stats, time_tracking, timers, undo, util,
+};
+use clap_complete::engine::ArgValueCandidates;
+use niri_ipc::{
+    Action, PositionChange, Request, Response, SizeChange, Window, Workspace,
+    WorkspaceReferenceArg,
+};
 use regex::Regex;
 use serde::{Deserialize, Serialize};
 
 static NO_MATCHING_WINDOW: &str = "No matching window.";
+static NO_FOCUSED_WINDOW: &str = "No focused window.";
+/// The prefix of errors caused by invalid arguments, e.g., of the nirius
+/// command line given to `after`.
+static BAD_ARGUMENTS: &str = "Invalid arguments";
+
+fn no_matching_window() -> NiriusError {
+    NiriusError::new(ErrorCode::NoMatchingWindow, NO_MATCHING_WINDOW)
+}
+
+fn no_focused_window() -> NiriusError {
+    NiriusError::new(ErrorCode::NoFocusedWindow, NO_FOCUSED_WINDOW)
+}
+
+fn bad_arguments(msg: impl std::fmt::Display) -> NiriusError {
+    NiriusError::new(ErrorCode::BadArguments, format!("{BAD_ARGUMENTS}: {msg}"))
+}
 
-#[derive(clap::Parser, PartialEq, Eq, Debug, Clone, Deserialize, Serialize)]
+#[derive(
+    clap::Parser,
+    PartialEq,
+    Eq,
+    Debug,
+    Clone,
+    Deserialize,
+    Serialize,
+    strum::IntoStaticStr,
+)]
 pub enum NiriusCmd {
     /// Focus the window matching the given options.  If there is more than one
     /// matching window, cycle through them.  If there is none, exit non-zero.
@@ -63,24 +112,467 @@ pub enum NiriusCmd {
         focus: bool,
         command: Vec<String>,
     },
+    /// Move all windows matching the given options to the current workspace.
+    Gather {
+        #[clap(flatten)]
+        match_opts: MatchOptions,
+    },
+    /// Applies the given niri action to every window matching the given
+    /// options, e.g., `nirius for-each -a pavucontrol --action
+    /// toggle-window-floating`.
+    ForEach {
+        #[clap(flatten)]
+        match_opts: MatchOptions,
+        #[clap(
+            long,
+            num_args = 1..,
+            allow_hyphen_values = true,
+            required = true,
+            help = "The niri action with its arguments like for `niri msg \
+                    action`, given last"
+        )]
+        action: Vec<String>,
+    },
+    /// Closes the focused window or the most recently focused window matching
+    /// the given options.
+    Close {
+        #[clap(flatten)]
+        match_opts: MatchOptions,
+        #[clap(long, help = "Close all matching windows")]
+        all: bool,
+    },
+    /// Executes the given nirius command after the given delay, e.g.,
+    /// `nirius after 5m focus -a firefox`.
+    After {
+        /// The delay, e.g., 500ms, 30s, 5m, or 1h.
+        #[clap(value_parser = util::parse_duration)]
+        delay: Duration,
+        #[clap(
+            trailing_var_arg = true,
+            allow_hyphen_values = true,
+            required = true
+        )]
+        command: Vec<String>,
+    },
+    /// Executes several commands in one go, e.g., `nirius batch 'toggle-mark
+    /// work ; focus -a slack'`.  The commands are executed one after the
+    /// other without any other command or niri event being handled in
+    /// between.  Execution stops at the first failing command.
+    Batch {
+        /// Commands separated by `;`.  Arguments containing spaces or `;` can
+        /// be quoted with single or double quotes.
+        #[clap(required_unless_present = "commands")]
+        script: Option<String>,
+        #[clap(
+            short = 'c',
+            long = "command",
+            help = "A command to execute after the ones of the script"
+        )]
+        commands: Vec<String>,
+    },
+    /// Executes the commands of an alias defined in the config file like a
+    /// batch.
+    Run {
+        /// The name of the alias.
+        alias: String,
+    },
+    /// Prints nirius events as JSON lines as they happen: windows being
+    /// opened, closed or focused, workspaces being focused, and changes of
+    /// marks and the scratchpad.  Runs until niriusd stops.
+    Events,
     /// Enables or disables follow-mode for the currently focused window.  A
     /// window in follow-mode moves automatically to whatever workspace that
     /// receives focus.
     ToggleFollowMode,
+    /// Pins or unpins the currently focused window to its current output.  A
+    /// pinned window which is moved to a workspace on another output is moved
+    /// back to the active workspace of its pinned output.
+    TogglePinOutput,
+    /// Puts the currently focused window into picture-in-picture mode or
+    /// reverts that if it's in picture-in-picture mode already.  A pip window
+    /// is floating, resized and positioned in a corner of its output as
+    /// configured, and in follow-mode.
+    TogglePip,
+    /// Enables or disables zen mode for the current workspace.  Enabling zen
+    /// mode stashes all windows except the focused one away to the bottom
+    /// workspace of the current output.  Disabling it moves them back.
+    ZenToggle,
+    /// Minimizes the currently focused window by moving it to the bottom
+    /// workspace of its output.  Use `restore` to bring it back.
+    Minimize,
+    /// Restores the most recently minimized window matching the given options
+    /// to the workspace it has been minimized from and focuses it.
+    Restore {
+        #[clap(flatten)]
+        match_opts: MatchOptions,
+    },
+    /// List all minimized windows on stdout.
+    ListMinimized,
+    /// Focuses the previously focused workspace.  Repeated invocations
+    /// toggle between the two most recently focused workspaces.
+    WorkspaceBackAndForth,
+    /// Focuses the workspace which has been focused before the current one in
+    /// the workspace history.
+    WorkspaceHistoryBack,
+    /// Focuses the workspace which has been focused after the current one in
+    /// the workspace history, i.e., undoes a `workspace-history-back`.
+    WorkspaceHistoryForward,
+    /// List the workspace history on stdout, the most recent workspace first.
+    /// The current position in the history is marked with a `*`.
+    ListWorkspaceHistory,
+    /// Focuses the first empty workspace on the current or given output.
+    FocusEmptyWorkspace {
+        #[clap(
+            short = 'o',
+            long,
+            help = "The output whose empty workspace should be focused"
+        )]
+        output: Option<String>,
+    },
+    /// Moves the focused window or the most recently focused window matching
+    /// the given options to the first empty workspace of its output.
+    MoveToEmptyWorkspace {
+        #[clap(flatten)]
+        match_opts: MatchOptions,
+        #[clap(
+            short = 'f',
+            long,
+            help = "Follow the window to the empty workspace."
+        )]
+        follow: bool,
+    },
+    /// Swaps the active workspaces of two outputs including their windows.
+    /// If only OUTPUT is given, swaps with the focused output.  If no output
+    /// is given, swaps the focused output with the other one which only works
+    /// on setups with exactly two outputs.
+    SwapWorkspacesBetweenOutputs {
+        output: Option<String>,
+        other_output: Option<String>,
+    },
+    /// Moves the focused window, or the most recently focused one matching
+    /// the given options, to the active workspace of the given output, or of
+    /// the next or previous output in the order of their names.
+    MoveToOutput {
+        #[clap(help = "The output's name, next, or previous")]
+        output: String,
+        #[clap(flatten)]
+        match_opts: MatchOptions,
+        #[clap(short = 'f', long, help = "Focus the window after moving it")]
+        focus: bool,
+    },
+    /// Focuses the active workspace of the next output in the order of their
+    /// names.
+    FocusNextOutput {
+        #[clap(
+            short = 't',
+            long,
+            help = "Move the focused window, if any, along to the next output"
+        )]
+        take_window: bool,
+    },
+    /// Swaps the focused window with the most recently focused other window
+    /// matching the given options.  The windows trade their workspaces,
+    /// floating state and, where possible, their positions.
+    SwapWindows {
+        #[clap(flatten)]
+        match_opts: MatchOptions,
+    },
+    /// Makes the focused window, or the most recently focused one matching
+    /// the given options, floating and centers it on the active workspace of
+    /// the focused output.
+    CenterFloat {
+        #[clap(flatten)]
+        match_opts: MatchOptions,
+        #[clap(
+            short = 's',
+            long,
+            value_parser = clap::value_parser!(u8).range(1..=100),
+            help = "The size in percent of the output's width and height"
+        )]
+        #[serde(default)]
+        size: Option<u8>,
+    },
+    /// Applies the size and position of a preset defined in the config file
+    /// to the focused window, or the most recently focused one matching the
+    /// given options, making it floating.
+    Preset {
+        /// The name of the preset.
+        name: String,
+        #[clap(flatten)]
+        match_opts: MatchOptions,
+    },
+    /// Moves the focused window, or the most recently focused one matching
+    /// the given options, to the active workspace of the given output and
+    /// makes it fullscreen.  With `--end`, the presented window goes back to
+    /// where it came from.
+    Present {
+        #[clap(flatten)]
+        match_opts: MatchOptions,
+        #[clap(
+            short = 'o',
+            long,
+            required_unless_present = "end",
+            help = "The output's name, next, or previous"
+        )]
+        output: Option<String>,
+        #[clap(
+            long,
+            conflicts_with = "output",
+            help = "End the presentation restoring the window's workspace"
+        )]
+        end: bool,
+    },
+    /// Commands for projects defined in the config file.
+    #[clap(subcommand)]
+    Project(ProjectCmd),
+    /// Commands for saving and restoring layout snapshots.
+    #[clap(subcommand)]
+    Layout(LayoutCmd),
+    /// Commands for the profiles defined in the config file.
+    #[clap(subcommand)]
+    Profile(ProfileCmd),
+    /// Commands for groups of related windows which are cycled through and
+    /// moved together.
+    #[clap(subcommand)]
+    Group(GroupCmd),
     /// Marks or unmarks the currently focused window with the given or default
     /// mark.  You can switch to the marked window or cycle trough all marked
     /// windows using the `focus-marked` command.
-    ToggleMark { mark: Option<String> },
+    ToggleMark {
+        #[clap(add = ArgValueCandidates::new(completions::marks))]
+        mark: Option<String>,
+        #[clap(
+            long,
+            help = "Toggle the mark of the window with this id instead of the \
+                    focused one"
+        )]
+        id: Option<u64>,
+    },
     /// Focuses the window with the given mark or the default mark, if no mark
     /// is given.  If there are multiple marked windows, cycles through all of
     /// them.  To mark a window, use the `toggle-mark` command.
-    FocusMarked { mark: Option<String> },
+    FocusMarked {
+        #[clap(add = ArgValueCandidates::new(completions::marks))]
+        mark: Option<String>,
+    },
     /// List all windows with the given or default mark, if no mark is given,
     /// on stdout.
     ListMarked {
+        #[clap(add = ArgValueCandidates::new(completions::marks))]
         mark: Option<String>,
         #[clap(short = 'a', long, help = "List all marks with their windows")]
         all: bool,
+        #[clap(long, value_enum, help = "The order of the windows")]
+        #[serde(default)]
+        sort: Option<WindowOrder>,
+    },
+    /// List all windows in follow-mode on stdout.
+    ListFollow,
+    /// Prints the daemon's uptime, whether it can reach niri, and how many
+    /// windows, workspaces, marks, scratchpad and follow-mode windows it
+    /// tracks.  Fails if niri can't be reached.
+    #[clap(alias = "ping")]
+    Status {
+        #[clap(short = 'j', long, help = "Print JSON instead of text")]
+        json: bool,
+    },
+    /// Prints how often each command has been executed and each niri event
+    /// has been handled, and how long niri queries took with latency
+    /// histograms.
+    Stats {
+        #[clap(short = 'j', long, help = "Print JSON instead of text")]
+        json: bool,
+    },
+    /// Prints how long the windows of each app-id had focus since the given
+    /// point in time.  Requires `track_focus_time` in the config.
+    TimeReport {
+        #[clap(
+            long,
+            default_value = "today",
+            value_parser = parse_since,
+            help = "today, yesterday, or a duration like 2h or 7d"
+        )]
+        since: String,
+        #[clap(short = 'j', long, help = "Print JSON instead of text")]
+        json: bool,
+    },
+    /// Prints the most recent niri events, commands and timers the daemon
+    /// handled together with what it did, the oldest first, e.g., to find
+    /// out why a window moved.
+    History {
+        #[clap(
+            short = 'n',
+            long,
+            default_value_t = 50,
+            help = "How many entries to print"
+        )]
+        limit: usize,
+        #[clap(short = 'j', long, help = "Print JSON instead of text")]
+        json: bool,
+    },
+    /// Moves the windows which nirius moved with the most recent command,
+    /// event or timer back to the workspaces they were on, and restores
+    /// whether they were floating.
+    Undo,
+    /// Reloads the config file of the daemon.
+    ReloadConfig,
+    /// Prints the path of the socket nirius and niriusd use to communicate.
+    PrintSocketPath,
+    /// Prints the completion script for the given shell.  The script
+    /// completes mark names and app-ids by asking niriusd.
+    Completions {
+        #[clap(value_parser = ["bash", "zsh", "fish", "elvish", "powershell"])]
+        shell: String,
+    },
+    /// Writes the man pages of nirius, its subcommands and niriusd into the
+    /// given directory.
+    GenerateMan {
+        #[clap(default_value = ".")]
+        dir: std::path::PathBuf,
+    },
+    /// Checks the config file and its drop-in files, or only the given
+    /// file, for syntax errors, unknown keys, invalid regexes, conflicting
+    /// rules and references to unknown commands, aliases and outputs.
+    ValidateConfig { path: Option<std::path::PathBuf> },
+    /// Commands for controlling the niriusd daemon.
+    #[clap(subcommand)]
+    Daemon(DaemonCmd),
+    /// Prints which rules of the config file match the currently known
+    /// windows and what they would do without applying anything.
+    CheckRules,
+    /// List all workspaces with their windows and nirius annotations such as
+    /// marks, scratchpad and follow-mode on stdout.
+    ListWorkspaces {
+        #[clap(short = 'j', long, help = "Print JSON instead of text")]
+        json: bool,
+    },
+    /// Prints everything nirius knows about the window with the given id,
+    /// the most recently focused one matching the given options, or the
+    /// focused one: niri's data about it, its marks, whether it's in the
+    /// scratchpad or follow-mode, its position in the focus history, etc.
+    DescribeWindow {
+        #[clap(flatten)]
+        match_opts: MatchOptions,
+        #[clap(short = 'j', long, help = "Print JSON instead of text")]
+        json: bool,
+    },
+    /// Prints the number of windows matching the given options, or of all
+    /// windows if none is given.
+    Count {
+        #[clap(flatten)]
+        match_opts: MatchOptions,
+        #[clap(short = 'j', long, help = "Print JSON instead of text")]
+        json: bool,
+    },
+    /// Shows the windows matching the given options, or all windows, in a
+    /// menu program such as fuzzel and focuses, moves or marks the selected
+    /// one.
+    Menu {
+        #[clap(short = 'p', long, value_enum, default_value_t = Picker::Fuzzel)]
+        picker: Picker,
+        #[clap(long, value_enum, default_value_t = MenuAction::Focus)]
+        action: MenuAction,
+        #[clap(flatten)]
+        match_opts: MatchOptions,
+    },
+    /// Prints the windows matching the given options, or all windows, on
+    /// stdout, one per line as `<id> <app-id>: <title>`.  With --stdin, reads
+    /// a selected line from stdin then and focuses, moves or marks its
+    /// window.
+    Pick {
+        #[clap(long, help = "Read the selected window from stdin")]
+        stdin: bool,
+        #[clap(long, value_enum, default_value_t = MenuAction::Focus)]
+        action: MenuAction,
+        #[clap(flatten)]
+        match_opts: MatchOptions,
+    },
+    /// Implements rofi's script mode listing the windows matching the given
+    /// options, or all windows, and focusing, moving or marking the selected
+    /// one, e.g., `rofi -show windows -modi 'windows:nirius rofi'`.
+    Rofi {
+        #[clap(long, value_enum, default_value_t = MenuAction::Focus)]
+        action: MenuAction,
+        #[clap(flatten)]
+        match_opts: MatchOptions,
+        /// The selected entry which rofi passes
+        #[clap(hide = true)]
+        selection: Option<String>,
+    },
+    /// Prints JSON for a waybar custom module showing how many windows are
+    /// in the scratchpad, urgent or marked, with the windows in the tooltip.
+    Waybar {
+        #[clap(
+            short = 'w',
+            long,
+            help = "Print a new line whenever the output changes"
+        )]
+        watch: bool,
+    },
+    /// Prints the daemon's whole state as JSON: windows, workspaces, marks,
+    /// scratchpad, follow-mode, histories, etc.
+    DumpState,
+    /// Adds the marks, scratchpad and follow-mode assignments of a state
+    /// dumped with `dump-state`.  Windows which don't exist anymore are
+    /// re-associated with windows of the same app-id and title.  Windows
+    /// added to the scratchpad are moved there.
+    ImportState {
+        #[clap(value_name = "FILE", value_parser = read_state_dump)]
+        dump: StateImport,
+    },
+    /// Prints nothing and exits with 0 if a window matching the given
+    /// options exists, or with 1 otherwise.
+    Exists {
+        #[clap(flatten)]
+        match_opts: MatchOptions,
+    },
+    /// List all windows or the ones matching the given options with their
+    /// workspace, output and nirius annotations such as marks, scratchpad
+    /// and follow-mode on stdout.
+    ListWindows {
+        #[clap(flatten)]
+        match_opts: MatchOptions,
+        #[clap(short = 'j', long, help = "Print JSON instead of text")]
+        json: bool,
+        #[clap(long, value_enum, help = "The order of the windows")]
+        #[serde(default)]
+        sort: Option<WindowOrder>,
+    },
+    /// List the urgent windows or the urgent ones matching the given options
+    /// on stdout, the one which became urgent most recently last.
+    ListUrgent {
+        #[clap(flatten)]
+        match_opts: MatchOptions,
+        #[clap(short = 'j', long, help = "Print JSON instead of text")]
+        json: bool,
+        #[clap(long, value_enum, help = "The order of the windows")]
+        #[serde(default)]
+        sort: Option<WindowOrder>,
+    },
+    /// Runs the given command for every new window matching the given
+    /// options until `unwatch` is called with the printed watcher id.
+    Watch {
+        #[clap(flatten)]
+        match_opts: MatchOptions,
+        #[clap(last = true, required = true)]
+        command: Vec<String>,
+    },
+    /// Removes the watcher with the given id.
+    Unwatch { id: u64 },
+    /// Lists the watchers registered with `watch` on stdout.
+    ListWatchers,
+    /// Blocks until a window matching the given options exists and prints its
+    /// id.  Fails if no such window appears within the timeout.
+    WaitFor {
+        #[clap(flatten)]
+        match_opts: MatchOptions,
+        #[clap(short = 'n', long, help = "Ignore windows which exist already")]
+        new: bool,
+        /// The maximum time to wait, e.g., 500ms, 30s, 5m, or 1h.
+        #[clap(long, value_parser = util::parse_duration)]
+        timeout: Option<Duration>,
+        #[clap(short = 'j', long, help = "Print JSON instead of the id")]
+        json: bool,
     },
     /// Toggles the scratchpad state of the current window or a window matching
     /// the given app-id.
@@ -106,93 +598,1620 @@ pub enum NiriusCmd {
     },
 }
 
-#[derive(clap::Parser, PartialEq, Eq, Debug, Clone, Deserialize, Serialize)]
-pub struct MatchOptions {
-    #[clap(short = 'a', long, help = "A regex  matched on window app-ids")]
-    app_id: Option<String>,
+#[derive(
+    clap::Subcommand, PartialEq, Eq, Debug, Clone, Deserialize, Serialize,
+)]
+pub enum ProjectCmd {
+    /// Focuses the project's workspace (naming the first empty workspace
+    /// after the project if there is none yet) and spawns all apps of the
+    /// project which aren't running already.
+    Open { name: String },
+}
+
+#[derive(
+    clap::Subcommand, PartialEq, Eq, Debug, Clone, Deserialize, Serialize,
+)]
+pub enum ProfileCmd {
+    /// Makes the given profile the active one until the daemon is restarted
+    /// or another profile is switched to.
+    Switch { name: String },
+    /// Lists the profiles and marks the active one.
+    List,
+}
+
+#[derive(
+    clap::Subcommand, PartialEq, Eq, Debug, Clone, Deserialize, Serialize,
+)]
+pub enum DaemonCmd {
+    /// Saves the daemon's state such as marks and the scratchpad, deletes
+    /// its socket and exits.  A restarted daemon picks up the saved state.
+    Stop,
+}
+
+#[derive(
+    clap::Subcommand, PartialEq, Eq, Debug, Clone, Deserialize, Serialize,
+)]
+pub enum LayoutCmd {
+    /// Saves which windows are on which workspaces and outputs under the
+    /// given name.
+    Save { name: String },
+    /// Moves all windows back to the workspaces and outputs recorded in the
+    /// layout snapshot with the given name.
+    Restore { name: String },
+}
+
+#[derive(
+    clap::Subcommand, PartialEq, Eq, Debug, Clone, Deserialize, Serialize,
+)]
+pub enum GroupCmd {
+    /// Creates an empty window group with the given name.
+    Create { name: String },
+    /// Adds the focused window, or the most recently focused one matching
+    /// the given options, to the given group.
+    Add {
+        name: String,
+        #[clap(flatten)]
+        match_opts: MatchOptions,
+    },
+    /// Focuses the window of the group which has been focused least
+    /// recently.  Repeated invocations cycle through the group's windows.
+    Cycle { name: String },
+    /// Moves all windows of the group to the given workspace.
+    Move {
+        name: String,
+        #[clap(long, help = "The workspace's index or name")]
+        to_workspace: WorkspaceReferenceArg,
+    },
+}
+
+/// The orders windows can be listed in.  Without one, they are listed in
+/// the order nirius knows them.
+#[derive(
+    clap::ValueEnum, Clone, Copy, PartialEq, Eq, Debug, Serialize, Deserialize,
+)]
+pub enum WindowOrder {
+    /// The most recently focused window first.
+    Mru,
+    /// By output, workspace and position in the workspace.
+    Workspace,
+    /// By app-id.
+    AppId,
+    /// By title.
+    Title,
+}
+
+#[derive(
+    clap::Parser, PartialEq, Eq, Debug, Clone, Default, Deserialize, Serialize,
+)]
+pub struct MatchOptions {
+    /// A regex  matched on window app-ids
+    #[clap(
+        short = 'a',
+        long,
+        value_parser = parse_regex,
+        add = ArgValueCandidates::new(completions::app_ids)
+    )]
+    app_id: Option<String>,
+
+    /// A regex matched on window titles
+    #[clap(short = 't', long, value_parser = parse_regex)]
+    title: Option<String>,
+
+    /// The id of the window
+    #[clap(long)]
+    #[serde(default)]
+    id: Option<u64>,
+}
+
+fn read_state_dump(path: &str) -> Result<StateImport, String> {
+    let file = std::fs::File::open(path).map_err(|e| e.to_string())?;
+    serde_json::from_reader(std::io::BufReader::new(file))
+        .map_err(|e| e.to_string())
+}
+
+fn parse_since(s: &str) -> Result<String, String> {
+    time_tracking::parse_since(s).map(|_| s.to_owned())
+}
+
+fn parse_regex(s: &str) -> Result<String, String> {
+    Regex::new(s)
+        .map(|_| s.to_owned())
+        .map_err(|e| e.to_string())
+}
+
+impl MatchOptions {
+    /// Returns an error if the app-id or title regex is invalid.
+    pub(crate) fn check_regexes(&self) -> Result<(), String> {
+        for regex in [&self.app_id, &self.title].into_iter().flatten() {
+            parse_regex(regex)?;
+        }
+        Ok(())
+    }
+
+    /// Returns true if no option is given, i.e., all windows match.
+    pub fn is_empty(&self) -> bool {
+        self.app_id.is_none() && self.title.is_none() && self.id.is_none()
+    }
+
+    /// Returns the options matching only the window with the given id.
+    pub fn id(id: u64) -> Self {
+        MatchOptions {
+            app_id: None,
+            title: None,
+            id: Some(id),
+        }
+    }
+}
+
+pub(crate) static DEFAULT_MARK: &str = "__default__";
+
+/// Returns the given mark or the configured default mark.
+fn mark_or_default(mark: &Option<String>) -> String {
+    mark.clone()
+        .unwrap_or_else(|| get_config().get_default_mark().to_owned())
+}
+
+impl NiriusCmd {
+    /// Returns true if the command doesn't need nirius' state such as marks,
+    /// so it can be executed without the daemon, see [`exec_without_daemon`].
+    pub fn works_without_daemon(&self) -> bool {
+        matches!(
+            self,
+            NiriusCmd::Focus { .. }
+                | NiriusCmd::FocusOrSpawn { .. }
+                | NiriusCmd::MoveToCurrentWorkspace { .. }
+                | NiriusCmd::MoveToCurrentWorkspaceOrSpawn { .. }
+                | NiriusCmd::Gather { .. }
+        )
+    }
+
+    /// Returns true if the command is executed on the state, i.e., it can be
+    /// part of a batch.
+    pub fn runs_on_state(&self) -> bool {
+        !matches!(
+            self,
+            NiriusCmd::After { .. }
+                | NiriusCmd::ReloadConfig
+                | NiriusCmd::Profile(_)
+                | NiriusCmd::PrintSocketPath
+                | NiriusCmd::Completions { .. }
+                | NiriusCmd::GenerateMan { .. }
+                | NiriusCmd::ValidateConfig { .. }
+                | NiriusCmd::Daemon(_)
+                | NiriusCmd::Stats { .. }
+                | NiriusCmd::TimeReport { .. }
+                | NiriusCmd::History { .. }
+                | NiriusCmd::Undo
+                | NiriusCmd::WaitFor { .. }
+                | NiriusCmd::Batch { .. }
+                | NiriusCmd::Run { .. }
+                | NiriusCmd::Events
+                | NiriusCmd::Menu { .. }
+                | NiriusCmd::Pick { .. }
+                | NiriusCmd::Rofi { .. }
+        )
+    }
+
+    /// Returns true if the command's result should be printed as JSON.
+    pub fn wants_json(&self) -> bool {
+        match self {
+            NiriusCmd::ListWorkspaces { json }
+            | NiriusCmd::ListWindows { json, .. }
+            | NiriusCmd::ListUrgent { json, .. }
+            | NiriusCmd::DescribeWindow { json, .. }
+            | NiriusCmd::Count { json, .. }
+            | NiriusCmd::Status { json }
+            | NiriusCmd::Stats { json }
+            | NiriusCmd::TimeReport { json, .. }
+            | NiriusCmd::History { json, .. }
+            | NiriusCmd::WaitFor { json, .. } => *json,
+            _ => false,
+        }
+    }
+
+    /// Returns true if the command only queries something, so it's not
+    /// worth recording in the history.
+    pub fn is_query(&self) -> bool {
+        matches!(
+            self,
+            NiriusCmd::ListMinimized
+                | NiriusCmd::ListMarked { .. }
+                | NiriusCmd::ListFollow
+                | NiriusCmd::ListWorkspaces { .. }
+                | NiriusCmd::ListWindows { .. }
+                | NiriusCmd::ListUrgent { .. }
+                | NiriusCmd::DescribeWindow { .. }
+                | NiriusCmd::Count { .. }
+                | NiriusCmd::Exists { .. }
+                | NiriusCmd::Waybar { .. }
+                | NiriusCmd::DumpState
+                | NiriusCmd::Status { .. }
+                | NiriusCmd::Stats { .. }
+                | NiriusCmd::TimeReport { .. }
+                | NiriusCmd::History { .. }
+                | NiriusCmd::CheckRules
+                | NiriusCmd::PrintSocketPath
+                | NiriusCmd::WaitFor { .. }
+                | NiriusCmd::Events
+        )
+    }
+}
+
+pub fn exec_nirius_cmd(cmd: NiriusCmd) -> NiriusResponse {
+    exec_nirius_cmd_cancellable(cmd, &AtomicBool::new(false), None)
+}
+
+/// The receiver of the parts of a list response which are streamed to the
+/// client before the rest of the response.
+pub type Parts = tokio::sync::mpsc::UnboundedSender<NiriusResponse>;
+
+/// How many windows or workspaces are sent in one part of a list response.
+const PART_SIZE: usize = 100;
+
+/// Executes the command like [`exec_nirius_cmd`].  Waiting commands such as
+/// `wait-for` give up as soon as `cancelled` is set and
+/// [`state::notify_windows_changed`] is called, e.g., because the client
+/// has gone away.  If `parts` is given, list commands send their windows or
+/// workspaces there in parts of [`PART_SIZE`] while they are collected from
+/// the state, and only return the last part.
+pub fn exec_nirius_cmd_cancellable(
+    cmd: NiriusCmd,
+    cancelled: &AtomicBool,
+    parts: Option<Parts>,
+) -> NiriusResponse {
+    let start = Instant::now();
+    let name: &'static str = (&cmd).into();
+    let result = if cmd == NiriusCmd::Undo {
+        dispatch_nirius_cmd(cmd, cancelled, parts)
+    } else {
+        undo::in_group(name.to_owned(), || {
+            dispatch_nirius_cmd(cmd, cancelled, parts)
+        })
+    };
+    stats::record_command(name, start.elapsed());
+    result.unwrap_or_else(error_response)
+}
+
+/// Executes the command in the calling process on the windows and workspaces
+/// queried from niri.  That's the fallback of `nirius --no-daemon` if niriusd
+/// can't be reached.
+pub fn exec_without_daemon(cmd: &NiriusCmd) -> NiriusResponse {
+    let exec = || {
+        if !cmd.works_without_daemon() {
+            return Err(bad_arguments(format!(
+                "{} needs niriusd.",
+                <&str>::from(cmd)
+            )));
+        }
+        let wins = match ipc::query_niri(Request::Windows)? {
+            Response::Windows(wins) => wins,
+            x => return Err(ipc::unexpected_reply(x)),
+        };
+        let workspaces = match ipc::query_niri(Request::Workspaces)? {
+            Response::Workspaces(workspaces) => workspaces,
+            x => return Err(ipc::unexpected_reply(x)),
+        };
+        let mut state = State::from_niri(wins, workspaces)?;
+        exec_message_cmd(&mut state, cmd)
+    };
+    exec()
+        .map(NiriusResponse::Ok)
+        .unwrap_or_else(error_response)
+}
+
+fn error_response(err: NiriusError) -> NiriusResponse {
+    NiriusResponse::Error {
+        code: err.code,
+        message: err.message,
+    }
+}
+
+fn dispatch_nirius_cmd(
+    cmd: NiriusCmd,
+    cancelled: &AtomicBool,
+    parts: Option<Parts>,
+) -> Result<NiriusResponse, NiriusError> {
+    if cmd.runs_on_state() {
+        return state::with_state(move |state| {
+            exec_state_cmd(state, &cmd, parts.as_ref())
+        });
+    }
+    match &cmd {
+        // These commands don't work on the state or wait for it to change.
+        NiriusCmd::After { delay, command } => {
+            after(*delay, command).map(NiriusResponse::Ok)
+        }
+        NiriusCmd::ReloadConfig => {
+            Ok(NiriusResponse::Ok(daemon::reload_config()?))
+        }
+        NiriusCmd::Profile(ProfileCmd::Switch { name }) => {
+            Ok(NiriusResponse::Ok(daemon::switch_profile(name)?))
+        }
+        NiriusCmd::Profile(ProfileCmd::List) => {
+            Ok(NiriusResponse::Ok(config::list_profiles()))
+        }
+        NiriusCmd::PrintSocketPath => {
+            Ok(NiriusResponse::Ok(util::get_nirius_socket_path()))
+        }
+        NiriusCmd::Daemon(DaemonCmd::Stop) => {
+            Ok(NiriusResponse::Ok(daemon::stop_daemon()?))
+        }
+        NiriusCmd::Stats { .. } => Ok(NiriusResponse::Stats(stats::snapshot())),
+        NiriusCmd::TimeReport { since, .. } => {
+            Ok(NiriusResponse::TimeReport(time_tracking::report(since)?))
+        }
+        NiriusCmd::History { limit, .. } => {
+            Ok(NiriusResponse::History(history::recent(*limit)))
+        }
+        NiriusCmd::Undo => undo::undo().map(NiriusResponse::Ok),
+        NiriusCmd::WaitFor {
+            match_opts,
+            new,
+            timeout,
+            ..
+        } => wait_for(match_opts, *new, *timeout, cancelled),
+        NiriusCmd::Batch { script, commands } => {
+            batch(script.as_deref(), commands)
+        }
+        NiriusCmd::Run { alias } => match get_config().aliases.get(alias) {
+            Some(commands) => batch(None, commands),
+            None => Err(bad_arguments(format!("No alias {alias}."))),
+        },
+        NiriusCmd::Events => Err(bad_arguments(
+            "events can only be subscribed to by clients.",
+        )),
+        NiriusCmd::Completions { .. } => {
+            Err(bad_arguments("completions can only be printed by clients."))
+        }
+        NiriusCmd::GenerateMan { .. } => {
+            Err(bad_arguments("man pages can only be generated by clients."))
+        }
+        NiriusCmd::ValidateConfig { .. } => Err(bad_arguments(
+            "the config can only be validated by clients.",
+        )),
+        NiriusCmd::Menu { .. }
+        | NiriusCmd::Pick { .. }
+        | NiriusCmd::Rofi { .. } => {
+            Err(bad_arguments("menus can only be shown by clients."))
+        }
+        _ => Err(format!("{cmd:?} must be executed on the state.").into()),
+    }
+}
+
+/// Executes the commands which work on the state and return data.
+fn exec_state_cmd(
+    state: &mut State,
+    cmd: &NiriusCmd,
+    parts: Option<&Parts>,
+) -> Result<NiriusResponse, NiriusError> {
+    match cmd {
+        NiriusCmd::ListMinimized => Ok(list_minimized(state)),
+        NiriusCmd::ListMarked { mark, all, sort } => {
+            if *all {
+                Ok(list_all_marked(state, *sort, parts))
+            } else {
+                list_marked(state, mark_or_default(mark), *sort, parts)
+            }
+        }
+        NiriusCmd::ListFollow => Ok(list_follow(state)),
+        NiriusCmd::ListWorkspaces { .. } => Ok(list_workspaces(state, parts)),
+        NiriusCmd::ListWindows {
+            match_opts, sort, ..
+        } => Ok(list_windows(state, match_opts, *sort, parts)),
+        NiriusCmd::ListUrgent {
+            match_opts, sort, ..
+        } => Ok(list_urgent(state, match_opts, *sort, parts)),
+        NiriusCmd::DescribeWindow { match_opts, .. } => {
+            describe_window(state, match_opts)
+        }
+        NiriusCmd::Waybar { .. } => Ok(NiriusResponse::Waybar(waybar(state))),
+        NiriusCmd::DumpState => serde_json::to_value(&*state)
+            .map(NiriusResponse::StateDump)
+            .map_err(|e| format!("Could not serialize state: {e}").into()),
+        NiriusCmd::ImportState { dump } => {
+            let scratchpad_len = state.scratchpad_win_ids.len();
+            let mut msg = state.import(dump);
+            // Windows imported into the scratchpad are hidden there, too.
+            if state.scratchpad_win_ids.len() > scratchpad_len {
+                msg = format!("{msg}  {}", scratchpad_move(state)?);
+            }
+            Ok(NiriusResponse::Ok(msg))
+        }
+        NiriusCmd::Exists { match_opts } => {
+            if state
+                .all_windows
+                .iter()
+                .any(|w| window_matches(w, match_opts))
+            {
+                Ok(NiriusResponse::Ok(String::new()))
+            } else {
+                Err(no_matching_window())
+            }
+        }
+        NiriusCmd::Count { match_opts, .. } => Ok(NiriusResponse::Count(
+            state
+                .all_windows
+                .iter()
+                .filter(|w| window_matches(w, match_opts))
+                .count(),
+        )),
+        NiriusCmd::Status { .. } => Ok(status(state)),
+        _ => exec_message_cmd(state, cmd).map(NiriusResponse::Ok),
+    }
+}
+
+/// Executes the commands which work on the state and return a message.
+fn exec_message_cmd(
+    state: &mut State,
+    cmd: &NiriusCmd,
+) -> Result<String, NiriusError> {
+    match cmd {
+        NiriusCmd::Focus { match_opts } => focus(state, match_opts),
+        NiriusCmd::FocusOrSpawn {
+            match_opts,
+            command,
+        } => focus_or_spawn(state, match_opts, command),
+        NiriusCmd::MoveToCurrentWorkspace { match_opts, focus } => {
+            move_to_current_workspace(state, match_opts, *focus)
+        }
+        NiriusCmd::MoveToCurrentWorkspaceOrSpawn {
+            match_opts,
+            focus,
+            command,
+        } => move_to_current_workspace_or_spawn(
+            state, match_opts, *focus, command,
+        ),
+        NiriusCmd::Gather { match_opts } => gather(state, match_opts),
+        NiriusCmd::ToggleFollowMode => toggle_follow_mode(state),
+        NiriusCmd::TogglePinOutput => toggle_pin_output(state),
+        NiriusCmd::TogglePip => toggle_pip(state),
+        NiriusCmd::ZenToggle => zen_toggle(state),
+        NiriusCmd::Minimize => minimize(state),
+        NiriusCmd::Restore { match_opts } => restore(state, match_opts),
+        NiriusCmd::ToggleMark { mark, id } => {
+            toggle_mark(state, mark_or_default(mark), *id)
+        }
+        NiriusCmd::FocusMarked { mark } => {
+            focus_marked(state, mark_or_default(mark))
+        }
+        NiriusCmd::CheckRules => check_rules(state),
+        NiriusCmd::Watch {
+            match_opts,
+            command,
+        } => watch(state, match_opts, command),
+        NiriusCmd::Unwatch { id } => unwatch(state, *id),
+        NiriusCmd::ListWatchers => list_watchers(state),
+        NiriusCmd::WorkspaceBackAndForth => workspace_back_and_forth(state),
+        NiriusCmd::WorkspaceHistoryBack => workspace_history_go(state, -1),
+        NiriusCmd::WorkspaceHistoryForward => workspace_history_go(state, 1),
+        NiriusCmd::ListWorkspaceHistory => list_workspace_history(state),
+        NiriusCmd::FocusEmptyWorkspace { output } => {
+            focus_empty_workspace(state, output.as_deref())
+        }
+        NiriusCmd::MoveToEmptyWorkspace { match_opts, follow } => {
+            move_to_empty_workspace(state, match_opts, *follow)
+        }
+        NiriusCmd::SwapWorkspacesBetweenOutputs {
+            output,
+            other_output,
+        } => swap_workspaces_between_outputs(
+            state,
+            output.as_deref(),
+            other_output.as_deref(),
+        ),
+        NiriusCmd::MoveToOutput {
+            output,
+            match_opts,
+            focus,
+        } => move_to_output(state, output, match_opts, *focus),
+        NiriusCmd::FocusNextOutput { take_window } => {
+            focus_next_output(state, *take_window)
+        }
+        NiriusCmd::SwapWindows { match_opts } => {
+            swap_windows(state, match_opts)
+        }
+        NiriusCmd::CenterFloat { match_opts, size } => {
+            center_float(state, match_opts, *size)
+        }
+        NiriusCmd::ForEach { match_opts, action } => {
+            for_each(state, match_opts, action)
+        }
+        NiriusCmd::Close { match_opts, all } => close(state, match_opts, *all),
+        NiriusCmd::Preset { name, match_opts } => {
+            apply_preset(state, name, match_opts)
+        }
+        NiriusCmd::Present { end: true, .. } => present_end(state),
+        NiriusCmd::Present {
+            match_opts, output, ..
+        } => present(state, match_opts, output.as_deref()),
+        NiriusCmd::Project(ProjectCmd::Open { name }) => {
+            project_open(state, name)
+        }
+        NiriusCmd::Group(GroupCmd::Create { name }) => {
+            group_create(state, name)
+        }
+        NiriusCmd::Group(GroupCmd::Add { name, match_opts }) => {
+            group_add(state, name, match_opts)
+        }
+        NiriusCmd::Group(GroupCmd::Cycle { name }) => group_cycle(state, name),
+        NiriusCmd::Group(GroupCmd::Move { name, to_workspace }) => {
+            group_move(state, name, to_workspace)
+        }
+        NiriusCmd::Layout(LayoutCmd::Save { name }) => layout_save(state, name),
+        NiriusCmd::Layout(LayoutCmd::Restore { name }) => {
+            layout_restore(state, name)
+        }
+        NiriusCmd::ScratchpadToggle { app_id, no_move } => {
+            scratchpad_toggle(state, app_id.as_deref(), *no_move)
+        }
+        NiriusCmd::ScratchpadShow { app_id } => {
+            scratchpad_show(state, app_id.as_deref())
+        }
+        NiriusCmd::ListMinimized
+        | NiriusCmd::ListMarked { .. }
+        | NiriusCmd::ListFollow
+        | NiriusCmd::ListWorkspaces { .. }
+        | NiriusCmd::ListWindows { .. }
+        | NiriusCmd::ListUrgent { .. }
+        | NiriusCmd::DescribeWindow { .. }
+        | NiriusCmd::Count { .. }
+        | NiriusCmd::Exists { .. }
+        | NiriusCmd::Waybar { .. }
+        | NiriusCmd::DumpState
+        | NiriusCmd::ImportState { .. }
+        | NiriusCmd::Status { .. } => {
+            Err(format!("{cmd:?} doesn't return a message.").into())
+        }
+        // The commands for which NiriusCmd::runs_on_state() is false.
+        _ => Err(format!("{cmd:?} must not be executed on the state.").into()),
+    }
+}
+
+/// Parses the given command line whose first element is the program name.
+/// The default arguments configured for the command are inserted right after
+/// its name so that the given arguments override them.  Commands having
+/// subcommands themselves get no defaults.
+pub fn parse_with_defaults<T: clap::Parser>(
+    mut args: Vec<String>,
+) -> Result<T, clap::Error> {
+    let mut cmd = T::command();
+    let found = args.iter().enumerate().skip(1).find_map(|(idx, arg)| {
+        let sub = cmd.find_subcommand(arg)?;
+        Some((idx, sub.get_name().to_owned(), sub.has_subcommands()))
+    });
+    if let Some((idx, name, false)) = found
+        && let Some(defaults) = get_config().defaults.get(&name)
+    {
+        args.splice(idx + 1..idx + 1, defaults.iter().cloned());
+        cmd = cmd.mut_subcommand(name, |sub| sub.args_override_self(true));
+    }
+    let mut matches = cmd.try_get_matches_from(args)?;
+    T::from_arg_matches_mut(&mut matches)
+}
+
+/// Parses the given nirius command line (without the leading `nirius`).
+pub fn parse_nirius_cmd(args: &[String]) -> Result<NiriusCmd, NiriusError> {
+    parse_with_defaults(
+        std::iter::once("nirius".to_owned())
+            .chain(args.iter().cloned())
+            .collect(),
+    )
+    .map_err(|e| {
+        let msg = e.to_string();
+        bad_arguments(msg.trim_start_matches("error: "))
+    })
+}
+
+fn after(delay: Duration, command: &[String]) -> Result<String, NiriusError> {
+    let cmd = parse_nirius_cmd(command)?;
+    let name = command.join(" ");
+    timers::schedule(
+        delay,
+        None,
+        &name,
+        Arc::new(move || exec_nirius_cmd(cmd.clone()).into_result()),
+    );
+    Ok(format!("Scheduled {name:?} in {delay:?}."))
+}
+
+fn batch(
+    script: Option<&str>,
+    commands: &[String],
+) -> Result<NiriusResponse, NiriusError> {
+    let mut cmds = vec![];
+    for line in script
+        .into_iter()
+        .chain(commands.iter().map(String::as_str))
+    {
+        let cmd_lines =
+            util::split_command_lines(line).map_err(bad_arguments)?;
+        for args in cmd_lines {
+            let cmd = parse_nirius_cmd(&args)?;
+            if !cmd.runs_on_state() {
+                return Err(bad_arguments(format!(
+                    "{} can't be part of a batch.",
+                    args[0]
+                )));
+            }
+            cmds.push(cmd);
+        }
+    }
+    if cmds.is_empty() {
+        return Err(bad_arguments("No commands given."));
+    }
+
+    // All commands are executed in one state job so that nothing else
+    // happens in between.
+    state::with_state(move |state| {
+        let mut resps = vec![];
+        for cmd in cmds {
+            let start = Instant::now();
+            let name = (&cmd).into();
+            let resp = exec_state_cmd(state, &cmd, None)
+                .unwrap_or_else(error_response);
+            stats::record_command(name, start.elapsed());
+            let success = resp.is_success();
+            resps.push(resp);
+            if !success {
+                break;
+            }
+        }
+        Ok(NiriusResponse::Batch(resps))
+    })
+}
+
+fn toggle_follow_mode(w_state: &mut State) -> Result<String, NiriusError> {
+    if let Some(focused_win_id) = w_state.get_focused_win_id() {
+        if w_state.follow_mode_win_ids.contains(&focused_win_id) {
+            if let Some(index) = w_state
+                .follow_mode_win_ids
+                .iter()
+                .position(|id| *id == focused_win_id)
+            {
+                // swap_remove() would be more efficient but I think we
+                // want to retain the order.
+                w_state.follow_mode_win_ids.remove(index);
+            }
+            Ok(format!("Disabled follow mode for window {focused_win_id}"))
+        } else {
+            w_state.follow_mode_win_ids.push(focused_win_id);
+            Ok(format!("Enabled follow mode for window {focused_win_id}"))
+        }
+    } else {
+        Err(no_focused_window())
+    }
+}
+
+fn toggle_pin_output(state: &mut State) -> Result<String, NiriusError> {
+    let win = state
+        .all_windows
+        .iter()
+        .find(|w| w.is_focused)
+        .ok_or_else(no_focused_window)?;
+    let id = win.id;
+    let current_output = state.get_window_output(win).map(str::to_owned);
+    if let Some(output) = state.pinned_win_id_to_output.remove(&id) {
+        Ok(format!("Unpinned window {id} from output {output}."))
+    } else {
+        let output = current_output.ok_or("Focused window is on no output.")?;
+        let msg = format!("Pinned window {id} to output {output}.");
+        state.pinned_win_id_to_output.insert(id, output);
+        Ok(msg)
+    }
+}
+
+fn toggle_pip(state: &mut State) -> Result<String, NiriusError> {
+    let win = state
+        .all_windows
+        .iter()
+        .find(|w| w.is_focused)
+        .ok_or_else(no_focused_window)?
+        .clone();
+
+    if let Some(origin) = state.pip_win_id_to_origin.remove(&win.id) {
+        if origin.was_floating {
+            restore_floating_geometry(state, win.id)?;
+        } else {
+            exec_window_action(
+                state,
+                Action::MoveWindowToTiling { id: Some(win.id) },
+            )?;
+        }
+        if !origin.was_following {
+            state.follow_mode_win_ids.retain(|id| *id != win.id);
+        }
+        return Ok(format!("Disabled pip mode for window {}.", win.id));
+    }
+
+    let pip = &get_config().pip;
+    let output = state
+        .get_window_output(&win)
+        .ok_or("Focused window is on no output.")?;
+    let (out_width, out_height) = get_output_logical_size(output)?;
+    let x = match pip.corner {
+        Corner::TopLeft | Corner::BottomLeft => pip.margin,
+        Corner::TopRight | Corner::BottomRight => {
+            out_width - pip.width - pip.margin
+        }
+    };
+    let y = match pip.corner {
+        Corner::TopLeft | Corner::TopRight => pip.margin,
+        Corner::BottomLeft | Corner::BottomRight => {
+            out_height - pip.height - pip.margin
+        }
+    };
+
+    if !win.is_floating {
+        exec_window_action(
+            state,
+            Action::MoveWindowToFloating { id: Some(win.id) },
+        )?;
+    }
+    exec_niri_action(Action::SetWindowWidth {
+        id: Some(win.id),
+        change: SizeChange::SetFixed(pip.width),
+    })?;
+    exec_niri_action(Action::SetWindowHeight {
+        id: Some(win.id),
+        change: SizeChange::SetFixed(pip.height),
+    })?;
+    exec_niri_action(Action::MoveFloatingWindow {
+        id: Some(win.id),
+        x: PositionChange::SetFixed(x.into()),
+        y: PositionChange::SetFixed(y.into()),
+    })?;
+
+    let was_following = state.follow_mode_win_ids.contains(&win.id);
+    if !was_following {
+        state.follow_mode_win_ids.push(win.id);
+    }
+    state.pip_win_id_to_origin.insert(
+        win.id,
+        PipOrigin {
+            was_floating: win.is_floating,
+            was_following,
+        },
+    );
+    Ok(format!("Enabled pip mode for window {}.", win.id))
+}
+
+fn zen_toggle(state: &mut State) -> Result<String, NiriusError> {
+    let ws = state
+        .get_focused_workspace()
+        .ok_or("No focused workspace.")?;
+    let ws_id = ws.id;
+    let output = ws.output.clone().ok_or("Workspace without output.")?;
+
+    if let Some(win_ids) = state.zen_ws_id_to_stashed_win_ids.remove(&ws_id) {
+        for id in &win_ids {
+            move_window_to_workspace(
+                state,
+                *id,
+                WorkspaceReferenceArg::Id(ws_id),
+                false,
+            )?;
+        }
+        return Ok(format!(
+            "Disabled zen mode, restored {} windows.",
+            win_ids.len()
+        ));
+    }
+
+    let (bottom_ws_id, _) = state
+        .get_bottom_workspace_id_and_idx_of_output(&output)
+        .ok_or("No bottom workspace.")?;
+    let focused_win_id = state.get_focused_win_id();
+    let mut wins: Vec<&Window> = state
+        .all_windows
+        .iter()
+        .filter(|w| {
+            w.workspace_id == Some(ws_id) && Some(w.id) != focused_win_id
+        })
+        .collect();
+    // Stash them in column order so that restoring them keeps the order.
+    wins.sort_by_key(|w| w.layout.pos_in_scrolling_layout);
+    let win_ids: Vec<u64> = wins.iter().map(|w| w.id).collect();
+    for id in &win_ids {
+        move_window_to_workspace(
+            state,
+            *id,
+            WorkspaceReferenceArg::Id(bottom_ws_id),
+            false,
+        )?;
+    }
+    let msg = format!("Enabled zen mode, stashed {} windows.", win_ids.len());
+    state.zen_ws_id_to_stashed_win_ids.insert(ws_id, win_ids);
+    Ok(msg)
+}
+
+fn minimize(state: &mut State) -> Result<String, NiriusError> {
+    let win = state
+        .all_windows
+        .iter()
+        .find(|w| w.is_focused)
+        .ok_or_else(no_focused_window)?;
+    let id = win.id;
+    let was_floating = win.is_floating;
+    let workspace_id = win.workspace_id.ok_or("Window has no workspace.")?;
+    let output = state
+        .get_workspace_output(workspace_id)
+        .ok_or("Workspace without output.")?;
+    let (bottom_ws_id, _) = state
+        .get_bottom_workspace_id_and_idx_of_output(output)
+        .ok_or("No bottom workspace.")?;
+
+    move_window_to_workspace(
+        state,
+        id,
+        WorkspaceReferenceArg::Id(bottom_ws_id),
+        false,
+    )?;
+    state.minimized_windows.push(MinimizedWindow {
+        id,
+        workspace_id,
+        was_floating,
+    });
+    Ok(format!("Minimized window {id}."))
+}
+
+fn restore(
+    state: &mut State,
+    match_opts: &MatchOptions,
+) -> Result<String, NiriusError> {
+    let (idx, win) = state
+        .minimized_windows
+        .iter()
+        .enumerate()
+        .rev()
+        .find_map(|(idx, m)| {
+            state
+                .all_windows
+                .iter()
+                .find(|w| w.id == m.id && window_matches(w, match_opts))
+                .map(|w| (idx, w))
+        })
+        .ok_or_else(no_matching_window)?;
+    let is_floating = win.is_floating;
+    let minimized = state.minimized_windows.remove(idx);
+
+    // The original workspace might be gone in the meantime.
+    let ws_id = if state
+        .all_workspaces
+        .iter()
+        .any(|ws| ws.id == minimized.workspace_id)
+    {
+        minimized.workspace_id
+    } else {
+        state
+            .get_focused_workspace_id()
+            .ok_or("No focused workspace.")?
+    };
+    move_window_to_workspace(
+        state,
+        minimized.id,
+        WorkspaceReferenceArg::Id(ws_id),
+        true,
+    )?;
+    if is_floating != minimized.was_floating {
+        exec_window_action(
+            state,
+            Action::ToggleWindowFloating {
+                id: Some(minimized.id),
+            },
+        )?;
+        if minimized.was_floating {
+            restore_floating_geometry(state, minimized.id)?;
+        }
+    }
+    focus_window_by_id(minimized.id)
+}
+
+fn list_minimized(state: &State) -> NiriusResponse {
+    NiriusResponse::Windows(
+        state
+            .minimized_windows
+            .iter()
+            .filter_map(|m| state.all_windows.iter().find(|w| w.id == m.id))
+            .map(|w| state.window_info(w))
+            .collect(),
+    )
+}
+
+fn workspace_back_and_forth(state: &State) -> Result<String, NiriusError> {
+    let ws_id = state
+        .previous_workspace_id
+        .filter(|id| state.all_workspaces.iter().any(|ws| ws.id == *id))
+        .ok_or("No previous workspace.")?;
+    focus_workspace(WorkspaceReferenceArg::Id(ws_id))
+}
+
+fn workspace_history_go(
+    state: &mut State,
+    offset: isize,
+) -> Result<String, NiriusError> {
+    let pos = state
+        .workspace_history_pos
+        .checked_add_signed(offset)
+        .filter(|pos| *pos < state.workspace_history.len())
+        .ok_or("No more workspaces in the history.")?;
+    let ws_id = state.workspace_history[pos];
+    state.workspace_history_pos = pos;
+    focus_workspace(WorkspaceReferenceArg::Id(ws_id))
+}
+
+fn list_workspace_history(state: &State) -> Result<String, NiriusError> {
+    let mut str = String::new();
+    for (pos, id) in state.workspace_history.iter().enumerate().rev() {
+        if let Some(ws) = state.all_workspaces.iter().find(|ws| ws.id == *id) {
+            let current = if pos == state.workspace_history_pos {
+                "* "
+            } else {
+                "  "
+            };
+            str.push_str(&format!(
+                "{current}id: {}, idx: {}, name: {:?}, on output: {:?}\n",
+                ws.id, ws.idx, ws.name, ws.output
+            ));
+        }
+    }
+    Ok(str)
+}
+
+fn focus_empty_workspace(
+    state: &State,
+    output: Option<&str>,
+) -> Result<String, NiriusError> {
+    let output = match output {
+        Some(o) => o,
+        None => state
+            .get_focused_workspace()
+            .and_then(|ws| ws.output.as_deref())
+            .ok_or("No focused output.")?,
+    };
+    let ws_id = state
+        .get_first_empty_workspace_id_of_output(output)
+        .ok_or_else(|| format!("No empty workspace on output {output}."))?;
+    focus_workspace(WorkspaceReferenceArg::Id(ws_id))
+}
+
+fn move_to_empty_workspace(
+    state: &State,
+    match_opts: &MatchOptions,
+    follow: bool,
+) -> Result<String, NiriusError> {
+    let win_id = get_focused_or_matching_win_id(state, match_opts)?;
+    let output = state
+        .all_windows
+        .iter()
+        .find(|w| w.id == win_id)
+        .and_then(|w| state.get_window_output(w))
+        .ok_or("Window is on no output.")?;
+    let ws_id = state
+        .get_first_empty_workspace_id_of_output(output)
+        .ok_or_else(|| format!("No empty workspace on output {output}."))?;
+    move_window_to_workspace(
+        state,
+        win_id,
+        WorkspaceReferenceArg::Id(ws_id),
+        follow,
+    )
+}
+
+/// Returns the id of the focused window if no match options are given,
+/// otherwise the id of the most recently focused matching window.
+fn get_focused_or_matching_win_id(
+    state: &State,
+    match_opts: &MatchOptions,
+) -> Result<u64, NiriusError> {
+    if match_opts.is_empty() {
+        state.get_focused_win_id().ok_or_else(no_focused_window)
+    } else {
+        state
+            .get_last_focused_matching(|w| window_matches(w, match_opts))
+            .ok_or_else(no_matching_window)
+    }
+}
+
+fn move_to_output(
+    state: &State,
+    output: &str,
+    match_opts: &MatchOptions,
+    focus: bool,
+) -> Result<String, NiriusError> {
+    let win_id = get_focused_or_matching_win_id(state, match_opts)?;
+    let current = state
+        .all_windows
+        .iter()
+        .find(|w| w.id == win_id)
+        .and_then(|w| state.get_window_output(w));
+    let target = resolve_output(state, output, current)
+        .ok_or("The window isn't on any output.")??;
+    if current == Some(target) {
+        return Err(
+            format!("Window {win_id} is already on output {target}.").into()
+        );
+    }
+    let ws = state
+        .get_active_workspace_of_output(target)
+        .ok_or_else(|| format!("No active workspace on output {target}."))?;
+    move_window_to_workspace(
+        state,
+        win_id,
+        WorkspaceReferenceArg::Id(ws.id),
+        focus,
+    )?;
+    if focus {
+        focus_window_by_id(win_id)?;
+    }
+    Ok(format!("Moved window {win_id} to output {target}."))
+}
+
+fn center_float(
+    state: &State,
+    match_opts: &MatchOptions,
+    size: Option<u8>,
+) -> Result<String, NiriusError> {
+    let win_id = get_focused_or_matching_win_id(state, match_opts)?;
+    let win = state
+        .all_windows
+        .iter()
+        .find(|w| w.id == win_id)
+        .ok_or_else(|| format!("Unknown window {win_id}."))?;
+    let ws = state
+        .get_focused_workspace()
+        .ok_or("No focused workspace.")?;
+    let output = ws.output.as_deref().ok_or("Workspace without output.")?;
+    let (out_width, out_height) = get_output_logical_size(output)?;
+    let (width, height) = match size {
+        Some(percent) => (
+            out_width * i32::from(percent) / 100,
+            out_height * i32::from(percent) / 100,
+        ),
+        None => win.layout.window_size,
+    };
+
+    if win.workspace_id != Some(ws.id) {
+        move_window_to_workspace(
+            state,
+            win_id,
+            WorkspaceReferenceArg::Id(ws.id),
+            false,
+        )?;
+    }
+    if !win.is_floating {
+        exec_window_action(
+            state,
+            Action::MoveWindowToFloating { id: Some(win_id) },
+        )?;
+    }
+    exec_niri_action(Action::SetWindowWidth {
+        id: Some(win_id),
+        change: SizeChange::SetFixed(width),
+    })?;
+    exec_niri_action(Action::SetWindowHeight {
+        id: Some(win_id),
+        change: SizeChange::SetFixed(height),
+    })?;
+    exec_niri_action(Action::MoveFloatingWindow {
+        id: Some(win_id),
+        x: PositionChange::SetFixed(((out_width - width) / 2).into()),
+        y: PositionChange::SetFixed(((out_height - height) / 2).into()),
+    })?;
+    Ok(format!("Centered window {win_id} on output {output}."))
+}
+
+fn apply_preset(
+    state: &State,
+    name: &str,
+    match_opts: &MatchOptions,
+) -> Result<String, NiriusError> {
+    let config = get_config();
+    let preset = config
+        .presets
+        .get(name)
+        .ok_or_else(|| format!("No preset {name}."))?;
+    let win_id = get_focused_or_matching_win_id(state, match_opts)?;
+    let win = state
+        .all_windows
+        .iter()
+        .find(|w| w.id == win_id)
+        .ok_or_else(|| format!("Unknown window {win_id}."))?;
+    let output = state
+        .get_window_output(win)
+        .ok_or("The window isn't on any output.")?;
+    let (out_width, out_height) = get_output_logical_size(output)?;
+
+    if !win.is_floating {
+        exec_window_action(
+            state,
+            Action::MoveWindowToFloating { id: Some(win_id) },
+        )?;
+    }
+    if let Some(width) = preset.width {
+        exec_niri_action(Action::SetWindowWidth {
+            id: Some(win_id),
+            change: SizeChange::SetFixed(width.to_pixels(out_width)),
+        })?;
+    }
+    if let Some(height) = preset.height {
+        exec_niri_action(Action::SetWindowHeight {
+            id: Some(win_id),
+            change: SizeChange::SetFixed(height.to_pixels(out_height)),
+        })?;
+    }
+    if preset.x.is_some() || preset.y.is_some() {
+        let position = |length: Option<Length>, output_length| {
+            length.map_or(PositionChange::AdjustFixed(0.0), |l| {
+                PositionChange::SetFixed(l.to_pixels(output_length).into())
+            })
+        };
+        exec_niri_action(Action::MoveFloatingWindow {
+            id: Some(win_id),
+            x: position(preset.x, out_width),
+            y: position(preset.y, out_height),
+        })?;
+    }
+    Ok(format!("Applied preset {name} to window {win_id}."))
+}
+
+fn present(
+    state: &mut State,
+    match_opts: &MatchOptions,
+    output: Option<&str>,
+) -> Result<String, NiriusError> {
+    if let Some(presentation) = &state.presentation {
+        return Err(format!(
+            "Window {} is presented already, end that with present --end.",
+            presentation.id
+        )
+        .into());
+    }
+    let output = output.ok_or("No output to present on is given.")?;
+    let win_id = get_focused_or_matching_win_id(state, match_opts)?;
+    let win = state
+        .all_windows
+        .iter()
+        .find(|w| w.id == win_id)
+        .ok_or_else(|| format!("Unknown window {win_id}."))?;
+    let workspace_id = win.workspace_id.ok_or("Window has no workspace.")?;
+    let current = state.get_window_output(win);
+    let target = resolve_output(state, output, current)
+        .ok_or("The window isn't on any output.")??;
+    let ws = state
+        .get_active_workspace_of_output(target)
+        .ok_or_else(|| format!("No active workspace on output {target}."))?;
+
+    let was_fullscreen = is_fullscreen(state, win)?;
+    if ws.id != workspace_id {
+        move_window_to_workspace(
+            state,
+            win_id,
+            WorkspaceReferenceArg::Id(ws.id),
+            false,
+        )?;
+    }
+    // A fullscreen window stays fullscreen on the other output.
+    if !was_fullscreen {
+        exec_niri_action(Action::FullscreenWindow { id: Some(win_id) })?;
+    }
+    let msg = format!("Presenting window {win_id} on output {target}.");
+    state.presentation = Some(Presentation {
+        id: win_id,
+        workspace_id,
+        was_floating: win.is_floating,
+        was_fullscreen,
+    });
+    Ok(msg)
+}
+
+fn present_end(state: &mut State) -> Result<String, NiriusError> {
+    let presentation =
+        state.presentation.take().ok_or("No window is presented.")?;
+    let id = presentation.id;
+    // The user might have left or entered fullscreen in the meantime.
+    if let Some(win) = state.all_windows.iter().find(|w| w.id == id)
+        && is_fullscreen(state, win)? != presentation.was_fullscreen
+    {
+        exec_niri_action(Action::FullscreenWindow { id: Some(id) })?;
+    }
+    // The original workspace might be gone in the meantime.
+    if state
+        .all_workspaces
+        .iter()
+        .any(|ws| ws.id == presentation.workspace_id)
+    {
+        move_window_to_workspace(
+            state,
+            id,
+            WorkspaceReferenceArg::Id(presentation.workspace_id),
+            false,
+        )?;
+    }
+    if presentation.was_floating
+        && state
+            .all_windows
+            .iter()
+            .any(|w| w.id == id && !w.is_floating)
+    {
+        exec_window_action(
+            state,
+            Action::MoveWindowToFloating { id: Some(id) },
+        )?;
+    }
+    Ok(format!("Ended the presentation of window {id}."))
+}
+
+/// Resolves `next` and `previous` relative to the `current` output, or checks
+/// that an output with the given name exists.  Returns `None` if `next` or
+/// `previous` is given without a current output.
+fn resolve_output<'a>(
+    state: &'a State,
+    output: &'a str,
+    current: Option<&str>,
+) -> Option<Result<&'a str, NiriusError>> {
+    let outputs = state.get_outputs();
+    match output {
+        "next" | "previous" => {
+            let pos = outputs.iter().position(|o| Some(*o) == current)?;
+            let len = outputs.len();
+            Some(Ok(if output == "next" {
+                outputs[(pos + 1) % len]
+            } else {
+                outputs[(pos + len - 1) % len]
+            }))
+        }
+        name => Some(
+            outputs
+                .into_iter()
+                .find(|o| *o == name)
+                .ok_or_else(|| format!("No output {name}.").into()),
+        ),
+    }
+}
+
+fn focus_next_output(
+    state: &State,
+    take_window: bool,
+) -> Result<String, NiriusError> {
+    // Without a focused window, e.g., on an empty workspace, there's nothing
+    // to take along.
+    if take_window && state.get_focused_win_id().is_some() {
+        return move_to_output(state, "next", &MatchOptions::default(), true);
+    }
+    let current = state
+        .get_focused_workspace()
+        .and_then(|ws| ws.output.as_deref());
+    let target = resolve_output(state, "next", current)
+        .ok_or("No output is focused.")??;
+    exec_niri_action(Action::FocusMonitor {
+        output: target.to_owned(),
+    })?;
+    Ok(format!("Focused output {target}."))
+}
+
+fn swap_windows(
+    state: &State,
+    match_opts: &MatchOptions,
+) -> Result<String, NiriusError> {
+    if match_opts.is_empty() {
+        return Err("No window to swap with is specified.".into());
+    }
+    let a = state.get_focused_win_id().ok_or_else(no_focused_window)?;
+    let b = state
+        .get_last_focused_matching(|w| {
+            w.id != a && window_matches(w, match_opts)
+        })
+        .ok_or_else(no_matching_window)?;
+    let find = |id| {
+        state
+            .all_windows
+            .iter()
+            .find(|w| w.id == id)
+            .ok_or_else(|| format!("Unknown window {id}."))
+    };
+    let (a, b) = (find(a)?, find(b)?);
+
+    if a.workspace_id != b.workspace_id {
+        for (win, other) in [(a, b), (b, a)] {
+            if let Some(ws_id) = other.workspace_id {
+                move_window_to_workspace(
+                    state,
+                    win.id,
+                    WorkspaceReferenceArg::Id(ws_id),
+                    false,
+                )?;
+            }
+        }
+    }
+    if a.is_floating != b.is_floating {
+        for win in [a, b] {
+            exec_window_action(
+                state,
+                Action::ToggleWindowFloating { id: Some(win.id) },
+            )?;
+        }
+    }
+    // Each window takes the column or floating position of the other one.
+    // Moving a column requires focusing it.
+    for (win, other) in [(a, b), (b, a)] {
+        if other.is_floating {
+            if let Some((x, y)) = other.layout.tile_pos_in_workspace_view {
+                exec_niri_action(Action::MoveFloatingWindow {
+                    id: Some(win.id),
+                    x: PositionChange::SetFixed(x),
+                    y: PositionChange::SetFixed(y),
+                })?;
+            }
+        } else if let Some((column, _)) = other.layout.pos_in_scrolling_layout {
+            focus_window_by_id(win.id)?;
+            exec_niri_action(Action::MoveColumnToIndex { index: column })?;
+        }
+    }
+    focus_window_by_id(a.id)?;
+    Ok(format!("Swapped windows {} and {}.", a.id, b.id))
+}
+
+fn swap_workspaces_between_outputs(
+    state: &State,
+    output: Option<&str>,
+    other_output: Option<&str>,
+) -> Result<String, NiriusError> {
+    let focused_output = state
+        .get_focused_workspace()
+        .and_then(|ws| ws.output.as_deref())
+        .ok_or("No focused output.")?;
+    let (a, b) = match (output, other_output) {
+        (Some(a), Some(b)) => (a, b),
+        (Some(a), None) => (focused_output, a),
+        _ => match state.get_outputs().as_slice() {
+            [x, y] => {
+                (focused_output, if *x == focused_output { *y } else { *x })
+            }
+            _ => return Err("There are not exactly two outputs.".into()),
+        },
+    };
+    if a == b {
+        return Err("Can't swap an output with itself.".into());
+    }
+    let ws_a = state
+        .get_active_workspace_of_output(a)
+        .ok_or_else(|| format!("No active workspace on output {a}."))?;
+    let ws_b = state
+        .get_active_workspace_of_output(b)
+        .ok_or_else(|| format!("No active workspace on output {b}."))?;
+
+    exec_niri_action(Action::MoveWorkspaceToMonitor {
+        output: b.to_owned(),
+        reference: Some(WorkspaceReferenceArg::Id(ws_a.id)),
+    })?;
+    exec_niri_action(Action::MoveWorkspaceToMonitor {
+        output: a.to_owned(),
+        reference: Some(WorkspaceReferenceArg::Id(ws_b.id)),
+    })?;
+    // Make both active on their new outputs ending with focus on the
+    // originally focused output.
+    let (first, last) = if a == focused_output {
+        (ws_a.id, ws_b.id)
+    } else {
+        (ws_b.id, ws_a.id)
+    };
+    focus_workspace(WorkspaceReferenceArg::Id(first))?;
+    focus_workspace(WorkspaceReferenceArg::Id(last))?;
+    Ok(format!("Swapped workspaces of outputs {a} and {b}."))
+}
+
+/// Creates a workspace with the given name if there is none yet by naming the
+/// first empty workspace of the focused output.
+pub(crate) fn ensure_named_workspace(
+    state: &State,
+    name: &str,
+) -> Result<(), NiriusError> {
+    if state
+        .all_workspaces
+        .iter()
+        .any(|ws| ws.name.as_deref() == Some(name))
+    {
+        return Ok(());
+    }
+    let output = state
+        .get_focused_workspace()
+        .and_then(|ws| ws.output.as_deref())
+        .ok_or("No focused output.")?;
+    let ws_id = state
+        .get_first_empty_workspace_id_of_output(output)
+        .ok_or_else(|| format!("No empty workspace on output {output}."))?;
+    exec_niri_action(Action::SetWorkspaceName {
+        name: name.to_owned(),
+        workspace: Some(WorkspaceReferenceArg::Id(ws_id)),
+    })
+}
+
+fn project_open(state: &mut State, name: &str) -> Result<String, NiriusError> {
+    let config = get_config();
+    let project = config
+        .projects
+        .iter()
+        .find(|p| p.name == name)
+        .ok_or_else(|| format!("No such project {name}."))?;
+    let ws_name = project.workspace.as_ref().unwrap_or(&project.name);
+
+    ensure_named_workspace(state, ws_name)?;
+    focus_workspace(WorkspaceReferenceArg::Name(ws_name.clone()))?;
+
+    let mut spawned = 0;
+    for app in &project.apps {
+        if !app.match_opts.is_empty() {
+            if state
+                .all_windows
+                .iter()
+                .any(|w| window_matches(w, &app.match_opts))
+            {
+                continue;
+            }
+            // Place the window once it appears no matter which workspace is
+            // focused at that time.
+            state.pending_placements.push(PendingPlacement {
+                match_opts: app.match_opts.clone(),
+                workspace: ws_name.clone(),
+                floating: app.floating,
+            });
+        }
+        spawn(&app.command)?;
+        spawned += 1;
+    }
+    Ok(format!("Opened project {name}, spawned {spawned} apps."))
+}
+
+fn group_create(state: &mut State, name: &str) -> Result<String, NiriusError> {
+    if state.group_to_win_ids.contains_key(name) {
+        return Err(format!("Group {name} exists already.").into());
+    }
+    state.group_to_win_ids.insert(name.to_owned(), vec![]);
+    Ok(format!("Created group {name}."))
+}
+
+fn group_add(
+    state: &mut State,
+    name: &str,
+    match_opts: &MatchOptions,
+) -> Result<String, NiriusError> {
+    let win_id = get_focused_or_matching_win_id(state, match_opts)?;
+    let win_ids = state
+        .group_to_win_ids
+        .get_mut(name)
+        .ok_or_else(|| format!("No group {name}."))?;
+    if win_ids.contains(&win_id) {
+        return Ok(format!("Window {win_id} is in group {name} already."));
+    }
+    win_ids.push(win_id);
+    Ok(format!("Added window {win_id} to group {name}."))
+}
+
+fn get_group<'a>(
+    state: &'a State,
+    name: &str,
+) -> Result<&'a [u64], NiriusError> {
+    let win_ids = state
+        .group_to_win_ids
+        .get(name)
+        .ok_or_else(|| format!("No group {name}."))?;
+    if win_ids.is_empty() {
+        Err(format!("Group {name} has no windows.").into())
+    } else {
+        Ok(win_ids)
+    }
+}
+
+fn group_cycle(state: &State, name: &str) -> Result<String, NiriusError> {
+    let win_ids = get_group(state, name)?;
+    // The least recently focused window comes first.
+    let win = state
+        .all_windows
+        .iter()
+        .find(|w| win_ids.contains(&w.id))
+        .ok_or_else(|| format!("Group {name} has no windows."))?;
+    focus_window_by_id(win.id)
+}
+
+fn group_move(
+    state: &State,
+    name: &str,
+    workspace: &WorkspaceReferenceArg,
+) -> Result<String, NiriusError> {
+    let win_ids = get_group(state, name)?;
+    for id in win_ids {
+        move_window_to_workspace(state, *id, workspace.clone(), false)?;
+    }
+    Ok(format!(
+        "Moved {} windows of group {name} to workspace {workspace:?}.",
+        win_ids.len()
+    ))
+}
 
-    #[clap(short = 't', long, help = "A regex matched on window titles")]
-    title: Option<String>,
+fn layout_save(state: &State, name: &str) -> Result<String, NiriusError> {
+    let snapshot = LayoutSnapshot::of_state(state);
+    layout::save_layout(name, &snapshot)?;
+    Ok(format!(
+        "Saved layout {name} with {} windows.",
+        snapshot.windows.len()
+    ))
 }
 
-static DEFAULT_MARK: &str = "__default__";
+fn layout_restore(state: &State, name: &str) -> Result<String, NiriusError> {
+    let snapshot = layout::load_layout(name)?;
 
-pub fn exec_nirius_cmd(cmd: NiriusCmd) -> Result<String, String> {
-    match &cmd {
-        NiriusCmd::Focus { match_opts } => focus(match_opts),
-        NiriusCmd::FocusOrSpawn {
-            match_opts,
-            command,
-        } => focus_or_spawn(match_opts, command),
-        NiriusCmd::MoveToCurrentWorkspace { match_opts, focus } => {
-            move_to_current_workspace(match_opts, *focus)
-        }
-        NiriusCmd::MoveToCurrentWorkspaceOrSpawn {
-            match_opts,
-            focus,
-            command,
-        } => move_to_current_workspace_or_spawn(match_opts, *focus, command),
-        NiriusCmd::ToggleFollowMode => toggle_follow_mode(),
-        NiriusCmd::ToggleMark { mark } => {
-            toggle_mark(mark.clone().unwrap_or(DEFAULT_MARK.to_owned()))
-        }
-        NiriusCmd::FocusMarked { mark } => {
-            focus_marked(mark.clone().unwrap_or(DEFAULT_MARK.to_owned()))
-        }
-        NiriusCmd::ListMarked { mark, all } => {
-            if *all {
-                list_all_marked()
-            } else {
-                list_marked(mark.clone().unwrap_or(DEFAULT_MARK.to_owned()))
+    // First assign windows with the same app-id and title, then the remaining
+    // ones only by app-id because titles change frequently.
+    let mut assigned: Vec<(&Window, &WindowPlacement)> = vec![];
+    for check_title in [true, false] {
+        for placement in &snapshot.windows {
+            if assigned.iter().any(|(_, p)| std::ptr::eq(*p, placement)) {
+                continue;
+            }
+            if let Some(win) = state.all_windows.iter().find(|w| {
+                placement.is_for(w, check_title)
+                    && !assigned.iter().any(|(a, _)| a.id == w.id)
+            }) {
+                assigned.push((win, placement));
             }
         }
-        NiriusCmd::ScratchpadToggle { app_id, no_move } => scratchpad_toggle(app_id.as_deref(), *no_move),
-        NiriusCmd::ScratchpadShow { app_id } => scratchpad_show(app_id.as_deref()),
     }
-}
 
-fn toggle_follow_mode() -> Result<String, String> {
-    let mut w_state = STATE.write().expect("Could not write() STATE.");
-    if let Some(focused_win_id) = w_state.get_focused_win_id() {
-        if w_state.follow_mode_win_ids.contains(&focused_win_id) {
-            if let Some(index) = w_state
-                .follow_mode_win_ids
-                .iter()
-                .position(|id| *id == focused_win_id)
-            {
-                // swap_remove() would be more efficient but I think we
-                // want to retain the order.
-                w_state.follow_mode_win_ids.remove(index);
-            }
-            Ok(format!("Disabled follow mode for window {focused_win_id}"))
-        } else {
-            w_state.follow_mode_win_ids.push(focused_win_id);
-            Ok(format!("Enabled follow mode for window {focused_win_id}"))
+    let mut moved = 0;
+    for (win, placement) in assigned {
+        let ws = placement
+            .workspace_name
+            .as_ref()
+            .and_then(|name| {
+                state
+                    .all_workspaces
+                    .iter()
+                    .find(|ws| ws.name.as_ref() == Some(name))
+            })
+            .or_else(|| {
+                state.all_workspaces.iter().find(|ws| {
+                    ws.output.as_ref() == Some(&placement.output)
+                        && ws.idx == placement.workspace_idx
+                })
+            });
+        let Some(ws) = ws else {
+            tracing::debug!("No workspace for {placement:?}.");
+            continue;
+        };
+        if win.workspace_id != Some(ws.id) {
+            move_window_to_workspace(
+                state,
+                win.id,
+                WorkspaceReferenceArg::Id(ws.id),
+                false,
+            )?;
+            moved += 1;
+        }
+        if win.is_floating != placement.is_floating {
+            exec_window_action(
+                state,
+                Action::ToggleWindowFloating { id: Some(win.id) },
+            )?;
         }
-    } else {
-        Err("No focused window".to_owned())
     }
+    Ok(format!("Restored layout {name}, moved {moved} windows."))
 }
 
 fn focus_or_spawn(
+    state: &State,
     match_opts: &MatchOptions,
     command: &[String],
-) -> Result<String, String> {
-    match focus(match_opts) {
-        Err(str) if NO_MATCHING_WINDOW == str => {
-            match ipc::query_niri(Request::Action(Action::Spawn {
-                command: command.to_vec(),
-            }))? {
-                Response::Handled => Ok("Spawned successfully".to_string()),
-                x => Err(format!("Received unexpected reply {x:?}")),
-            }
-        }
+) -> Result<String, NiriusError> {
+    match focus(state, match_opts) {
+        Err(err) if err.code == ErrorCode::NoMatchingWindow => spawn(command),
         x => x,
     }
 }
 
-fn focus(match_opts: &MatchOptions) -> Result<String, String> {
-    let state = STATE.read().expect("Could not read() STATE.");
+fn focus(
+    state: &State,
+    match_opts: &MatchOptions,
+) -> Result<String, NiriusError> {
     let currently_focused = state.get_focused_win_id();
 
     let find_any_match = || {
@@ -221,34 +2240,50 @@ fn focus(match_opts: &MatchOptions) -> Result<String, String> {
 
     match window_id {
         Some(id) => focus_window_by_id(id),
-        None => Err(NO_MATCHING_WINDOW.to_owned()),
+        None => Err(no_matching_window()),
     }
 }
 
-fn focus_window_by_id(id: u64) -> Result<String, String> {
+fn focus_window_by_id(id: u64) -> Result<String, NiriusError> {
     match ipc::query_niri(Request::Action(Action::FocusWindow { id }))? {
         Response::Handled => Ok(format!("Focused window with id {id}")),
-        x => Err(format!("Received unexpected reply {x:?}")),
+        x => Err(ipc::unexpected_reply(x)),
     }
 }
 
-fn window_matches(w: &Window, match_opts: &MatchOptions) -> bool {
-    log::debug!("Matching window {w:?}");
-    if w.app_id.is_none() && match_opts.app_id.is_some()
-        || match_opts.app_id.as_ref().is_some_and(|rx| {
-            !Regex::new(rx).unwrap().is_match(w.app_id.as_ref().unwrap())
-        })
+/// Returns true if `value` is given and matches the regex `rx`.  Invalid
+/// regexes, e.g., from the config, match nothing.
+fn regex_matches(rx: &str, value: Option<&String>) -> bool {
+    match Regex::new(rx) {
+        Ok(regex) => value.is_some_and(|v| regex.is_match(v)),
+        Err(err) => {
+            tracing::error!("Invalid regex {rx}: {err}");
+            false
+        }
+    }
+}
+
+pub(crate) fn window_matches(w: &Window, match_opts: &MatchOptions) -> bool {
+    tracing::debug!("Matching window {w:?}");
+    if match_opts.id.is_some_and(|id| id != w.id) {
+        tracing::debug!("id does not match.");
+        return false;
+    }
+    if match_opts
+        .app_id
+        .as_ref()
+        .is_some_and(|rx| !regex_matches(rx, w.app_id.as_ref()))
     {
-        log::debug!("app-id does not match.");
+        tracing::debug!("app-id does not match.");
         return false;
     }
 
-    if w.title.is_none() && match_opts.title.is_some()
-        || match_opts.title.as_ref().is_some_and(|rx| {
-            !Regex::new(rx).unwrap().is_match(w.title.as_ref().unwrap())
-        })
+    if match_opts
+        .title
+        .as_ref()
+        .is_some_and(|rx| !regex_matches(rx, w.title.as_ref()))
     {
-        log::debug!("title does not match.");
+        tracing::debug!("title does not match.");
         return false;
     }
 
@@ -256,10 +2291,10 @@ fn window_matches(w: &Window, match_opts: &MatchOptions) -> bool {
 }
 
 fn move_to_current_workspace(
+    state: &State,
     match_opts: &MatchOptions,
     focus: bool,
-) -> Result<String, String> {
-    let state = STATE.read().expect("Could not read() STATE");
+) -> Result<String, NiriusError> {
     let focused_ws_id = state
         .get_focused_workspace_id()
         .ok_or("No focused workspace.")?;
@@ -268,6 +2303,7 @@ fn move_to_current_workspace(
             && window_matches(w, match_opts)
     }) {
         let move_result = move_window_to_workspace(
+            state,
             win.id,
             niri_ipc::WorkspaceReferenceArg::Id(focused_ws_id),
             focus,
@@ -277,46 +2313,374 @@ fn move_to_current_workspace(
         }
         move_result
     } else {
-        Err(NO_MATCHING_WINDOW.to_owned())
+        Err(no_matching_window())
     }
 }
 
 fn move_to_current_workspace_or_spawn(
+    state: &State,
     match_opts: &MatchOptions,
     focus: bool,
     command: &[String],
-) -> Result<String, String> {
-    match move_to_current_workspace(match_opts, focus) {
-        Err(str) if NO_MATCHING_WINDOW == str => {
-            match ipc::query_niri(Request::Action(Action::Spawn {
-                command: command.to_vec(),
-            }))? {
-                Response::Handled => Ok("Spawned successfully".to_string()),
-                x => Err(format!("Received unexpected reply {x:?}")),
+) -> Result<String, NiriusError> {
+    match move_to_current_workspace(state, match_opts, focus) {
+        Err(err) if err.code == ErrorCode::NoMatchingWindow => spawn(command),
+        x => x,
+    }
+}
+
+pub fn focus_workspace(
+    reference: WorkspaceReferenceArg,
+) -> Result<String, NiriusError> {
+    exec_niri_action(Action::FocusWorkspace {
+        reference: reference.clone(),
+    })?;
+    Ok(format!("Focused workspace {reference:?}"))
+}
+
+fn gather(
+    state: &State,
+    match_opts: &MatchOptions,
+) -> Result<String, NiriusError> {
+    if match_opts.is_empty() {
+        return Err("Refusing to gather all windows.".into());
+    }
+    let focused_ws_id = state
+        .get_focused_workspace_id()
+        .ok_or("No focused workspace.")?;
+    let mut i = 0;
+    for win in state.all_windows.iter().filter(|w| {
+        w.workspace_id != Some(focused_ws_id) && window_matches(w, match_opts)
+    }) {
+        move_window_to_workspace(
+            state,
+            win.id,
+            WorkspaceReferenceArg::Id(focused_ws_id),
+            false,
+        )?;
+        i += 1;
+    }
+    if i == 0 {
+        Err(no_matching_window())
+    } else {
+        Ok(format!("Gathered {i} windows."))
+    }
+}
+
+/// Returns the action applied to the window with the given id if the action
+/// has a window id argument, or `None` if it only acts on the focused window
+/// or its column.  Actions which don't act on a window are refused.
+fn action_for_window(
+    action: &Action,
+    id: u64,
+) -> Result<Option<Action>, NiriusError> {
+    let id = Some(id);
+    Ok(Some(match action.clone() {
+        Action::CloseWindow { .. } => Action::CloseWindow { id },
+        Action::FullscreenWindow { .. } => Action::FullscreenWindow { id },
+        Action::ToggleWindowedFullscreen { .. } => {
+            Action::ToggleWindowedFullscreen { id }
+        }
+        Action::ScreenshotWindow { write_to_disk, .. } => {
+            Action::ScreenshotWindow { id, write_to_disk }
+        }
+        Action::ConsumeOrExpelWindowLeft { .. } => {
+            Action::ConsumeOrExpelWindowLeft { id }
+        }
+        Action::ConsumeOrExpelWindowRight { .. } => {
+            Action::ConsumeOrExpelWindowRight { id }
+        }
+        Action::CenterWindow { .. } => Action::CenterWindow { id },
+        Action::MoveWindowToWorkspace {
+            reference, focus, ..
+        } => Action::MoveWindowToWorkspace {
+            window_id: id,
+            reference,
+            focus,
+        },
+        Action::MoveWindowToMonitor { output, .. } => {
+            Action::MoveWindowToMonitor { id, output }
+        }
+        Action::SetWindowWidth { change, .. } => {
+            Action::SetWindowWidth { id, change }
+        }
+        Action::SetWindowHeight { change, .. } => {
+            Action::SetWindowHeight { id, change }
+        }
+        Action::ResetWindowHeight { .. } => Action::ResetWindowHeight { id },
+        Action::SwitchPresetWindowWidth { .. } => {
+            Action::SwitchPresetWindowWidth { id }
+        }
+        Action::SwitchPresetWindowWidthBack { .. } => {
+            Action::SwitchPresetWindowWidthBack { id }
+        }
+        Action::SwitchPresetWindowHeight { .. } => {
+            Action::SwitchPresetWindowHeight { id }
+        }
+        Action::SwitchPresetWindowHeightBack { .. } => {
+            Action::SwitchPresetWindowHeightBack { id }
+        }
+        Action::ToggleWindowFloating { .. } => {
+            Action::ToggleWindowFloating { id }
+        }
+        Action::MoveWindowToFloating { .. } => {
+            Action::MoveWindowToFloating { id }
+        }
+        Action::MoveWindowToTiling { .. } => Action::MoveWindowToTiling { id },
+        Action::MoveFloatingWindow { x, y, .. } => {
+            Action::MoveFloatingWindow { id, x, y }
+        }
+        Action::ToggleWindowRuleOpacity { .. } => {
+            Action::ToggleWindowRuleOpacity { id }
+        }
+        Action::MoveColumnLeft { .. }
+        | Action::MoveColumnRight { .. }
+        | Action::MoveColumnToFirst { .. }
+        | Action::MoveColumnToLast { .. }
+        | Action::MoveColumnLeftOrToMonitorLeft { .. }
+        | Action::MoveColumnRightOrToMonitorRight { .. }
+        | Action::MoveColumnToIndex { .. }
+        | Action::MoveWindowDown { .. }
+        | Action::MoveWindowUp { .. }
+        | Action::MoveWindowDownOrToWorkspaceDown { .. }
+        | Action::MoveWindowUpOrToWorkspaceUp { .. }
+        | Action::ConsumeWindowIntoColumn { .. }
+        | Action::ExpelWindowFromColumn { .. }
+        | Action::SwapWindowRight { .. }
+        | Action::SwapWindowLeft { .. }
+        | Action::ToggleColumnTabbedDisplay { .. }
+        | Action::SetColumnDisplay { .. }
+        | Action::CenterColumn { .. }
+        | Action::MoveWindowToWorkspaceDown { .. }
+        | Action::MoveWindowToWorkspaceUp { .. }
+        | Action::MoveColumnToWorkspaceDown { .. }
+        | Action::MoveColumnToWorkspaceUp { .. }
+        | Action::MoveColumnToWorkspace { .. }
+        | Action::MoveWindowToMonitorLeft { .. }
+        | Action::MoveWindowToMonitorRight { .. }
+        | Action::MoveWindowToMonitorDown { .. }
+        | Action::MoveWindowToMonitorUp { .. }
+        | Action::MoveWindowToMonitorPrevious { .. }
+        | Action::MoveWindowToMonitorNext { .. }
+        | Action::MoveColumnToMonitorLeft { .. }
+        | Action::MoveColumnToMonitorRight { .. }
+        | Action::MoveColumnToMonitorDown { .. }
+        | Action::MoveColumnToMonitorUp { .. }
+        | Action::MoveColumnToMonitorPrevious { .. }
+        | Action::MoveColumnToMonitorNext { .. }
+        | Action::MoveColumnToMonitor { .. }
+        | Action::SwitchPresetColumnWidth { .. }
+        | Action::SwitchPresetColumnWidthBack { .. }
+        | Action::MaximizeColumn { .. }
+        | Action::SetColumnWidth { .. }
+        | Action::ExpandColumnToAvailableWidth { .. } => return Ok(None),
+        action => {
+            let name = format!("{action:?}");
+            let name = name.split([' ', '{', '(']).next().unwrap_or_default();
+            return Err(bad_arguments(format!(
+                "{name} doesn't act on a window."
+            )));
+        }
+    }))
+}
+
+fn for_each(
+    state: &State,
+    match_opts: &MatchOptions,
+    args: &[String],
+) -> Result<String, NiriusError> {
+    let action = <Action as clap::Parser>::try_parse_from(
+        std::iter::once("action").chain(args.iter().map(String::as_str)),
+    )
+    .map_err(|e| {
+        let msg = e.to_string();
+        let msg = msg.lines().next().unwrap_or_default();
+        bad_arguments(msg.trim_start_matches("error: "))
+    })?;
+    action_for_window(&action, 0)?;
+    let win_ids: Vec<u64> = state
+        .all_windows
+        .iter()
+        .filter(|w| window_matches(w, match_opts))
+        .map(|w| w.id)
+        .collect();
+    if win_ids.is_empty() {
+        return Err(no_matching_window());
+    }
+    // Actions without window argument need each window to be focused, so
+    // the focus goes back to where it was afterwards, even if the action
+    // failed for some window.
+    let mut refocus = false;
+    let mut result = Ok(());
+    for id in &win_ids {
+        let applied = match action_for_window(&action, *id)? {
+            Some(action) => exec_window_action(state, action),
+            None => {
+                refocus = true;
+                focus_window_by_id(*id)
+                    .and_then(|_| exec_niri_action(action.clone()))
             }
+        };
+        if result.is_ok() {
+            result = applied;
         }
-        x => x,
     }
+    if refocus && let Some(id) = state.get_focused_win_id() {
+        // Refocusing always happens, but the first error is reported.
+        result = result.and(focus_window_by_id(id).map(|_| ()));
+    }
+    result?;
+    Ok(format!("Applied the action to {} windows.", win_ids.len()))
+}
+
+fn close(
+    state: &State,
+    match_opts: &MatchOptions,
+    all: bool,
+) -> Result<String, NiriusError> {
+    let win_ids = if all {
+        if match_opts.is_empty() {
+            return Err("Refusing to close all windows.".into());
+        }
+        state
+            .all_windows
+            .iter()
+            .filter(|w| window_matches(w, match_opts))
+            .map(|w| w.id)
+            .collect()
+    } else {
+        vec![get_focused_or_matching_win_id(state, match_opts)?]
+    };
+    if win_ids.is_empty() {
+        return Err(no_matching_window());
+    }
+    for id in &win_ids {
+        exec_niri_action(Action::CloseWindow { id: Some(*id) })?;
+    }
+    Ok(format!("Closed {} windows.", win_ids.len()))
 }
 
 pub fn move_window_to_workspace(
+    state: &State,
     window_id: u64,
     workspace_ref: niri_ipc::WorkspaceReferenceArg,
     focus: bool,
-) -> Result<String, String> {
-    match ipc::query_niri(Request::Action(Action::MoveWindowToWorkspace {
+) -> Result<String, NiriusError> {
+    let action = Action::MoveWindowToWorkspace {
         window_id: Some(window_id),
         reference: workspace_ref,
         focus,
-    }))? {
+    };
+    undo::before_action(&action, state);
+    match ipc::query_niri(Request::Action(action))? {
+        Response::Handled => Ok("Moved successfully".to_string()),
+        x => Err(ipc::unexpected_reply(x)),
+    }
+}
+
+pub fn move_window_to_output(
+    state: &State,
+    window_id: u64,
+    output: String,
+) -> Result<String, NiriusError> {
+    let action = Action::MoveWindowToMonitor {
+        id: Some(window_id),
+        output,
+    };
+    undo::before_action(&action, state);
+    match ipc::query_niri(Request::Action(action))? {
         Response::Handled => Ok("Moved successfully".to_string()),
-        x => Err(format!("Received unexpected reply {x:?}")),
+        x => Err(ipc::unexpected_reply(x)),
+    }
+}
+
+pub fn spawn(command: &[String]) -> Result<String, NiriusError> {
+    exec_niri_action(Action::Spawn {
+        command: command.to_vec(),
+    })?;
+    Ok("Spawned successfully".to_string())
+}
+
+/// Executes the niri action like [`exec_niri_action`] but records where the
+/// window is for `undo` first if the action moves it.
+pub(crate) fn exec_window_action(
+    state: &State,
+    action: Action,
+) -> Result<(), NiriusError> {
+    undo::before_action(&action, state);
+    exec_niri_action(action)
+}
+
+pub(crate) fn exec_niri_action(action: Action) -> Result<(), NiriusError> {
+    match ipc::query_niri(Request::Action(action))? {
+        Response::Handled => Ok(()),
+        x => Err(ipc::unexpected_reply(x)),
+    }
+}
+
+/// Gives the window with the given id the size and position it had when it
+/// was floating the last time (if it was floating before).
+fn restore_floating_geometry(
+    state: &State,
+    id: u64,
+) -> Result<(), NiriusError> {
+    let Some(geometry) = state.floating_win_id_to_geometry.get(&id) else {
+        return Ok(());
+    };
+    exec_niri_action(Action::SetWindowWidth {
+        id: Some(id),
+        change: SizeChange::SetFixed(geometry.width),
+    })?;
+    exec_niri_action(Action::SetWindowHeight {
+        id: Some(id),
+        change: SizeChange::SetFixed(geometry.height),
+    })?;
+    exec_niri_action(Action::MoveFloatingWindow {
+        id: Some(id),
+        x: PositionChange::SetFixed(geometry.x),
+        y: PositionChange::SetFixed(geometry.y),
+    })
+}
+
+/// Returns true if the window is fullscreen.  niri doesn't tell that
+/// directly, but the tile of a fullscreen window covers its whole output.
+pub(crate) fn is_fullscreen(
+    state: &State,
+    win: &Window,
+) -> Result<bool, NiriusError> {
+    let Some(output) = state.get_window_output(win) else {
+        return Ok(false);
+    };
+    let (width, height) = get_output_logical_size(output)?;
+    let (tile_width, tile_height) = win.layout.tile_size;
+    Ok(!win.is_floating
+        && tile_width.round() as i32 == width
+        && tile_height.round() as i32 == height)
+}
+
+/// Returns the logical width and height of the output with the given name.
+fn get_output_logical_size(output: &str) -> Result<(i32, i32), NiriusError> {
+    match ipc::query_niri(Request::Outputs)? {
+        Response::Outputs(outputs) => outputs
+            .get(output)
+            .and_then(|o| o.logical)
+            .map(|l| (l.width as i32, l.height as i32))
+            .ok_or_else(|| format!("Output {output} is not active.").into()),
+        x => Err(ipc::unexpected_reply(x)),
     }
 }
 
-fn toggle_mark(mark: String) -> Result<String, String> {
-    let mut state = STATE.write().expect("Could not write() STATE.");
-    if let Some(focused_win_id) = state.get_focused_win_id() {
+fn toggle_mark(
+    state: &mut State,
+    mark: String,
+    id: Option<u64>,
+) -> Result<String, NiriusError> {
+    let win_id = match id {
+        Some(id) if state.all_windows.iter().any(|w| w.id == id) => Some(id),
+        Some(_) => return Err(no_matching_window()),
+        None => state.get_focused_win_id(),
+    };
+    if let Some(focused_win_id) = win_id {
         let ids = state.mark_to_win_ids.entry(mark).or_default();
         if ids.contains(&focused_win_id) {
             if let Some(index) = ids.iter().position(|id| *id == focused_win_id)
@@ -331,13 +2695,11 @@ fn toggle_mark(mark: String) -> Result<String, String> {
             Ok(format!("Set mark for window {focused_win_id:?}"))
         }
     } else {
-        Err("No focused window.".to_owned())
+        Err(no_focused_window())
     }
 }
 
-fn focus_marked(mark: String) -> Result<String, String> {
-    let state = STATE.read().expect("Could not read() STATE.");
-
+fn focus_marked(state: &State, mark: String) -> Result<String, NiriusError> {
     if let Some(marked_windows) = state.mark_to_win_ids.get(&mark).cloned() {
         if let Some(win) = state
             .all_windows
@@ -346,68 +2708,478 @@ fn focus_marked(mark: String) -> Result<String, String> {
         {
             focus_window_by_id(win.id)
         } else {
-            Err("No marked window.".to_owned())
+            Err("No marked window.".into())
         }
     } else {
-        Err("No such mark.".to_owned())
+        Err("No such mark.".into())
     }
 }
 
-fn list_marked(mark: String) -> Result<String, String> {
-    let state = STATE.read().expect("Could not read() STATE.");
+fn list_marked(
+    state: &State,
+    mark: String,
+    sort: Option<WindowOrder>,
+    parts: Option<&Parts>,
+) -> Result<NiriusResponse, NiriusError> {
+    let marked_windows = state
+        .mark_to_win_ids
+        .get(&mark)
+        .ok_or_else(|| format!("No such mark: {mark}."))?;
+    Ok(send_parts(
+        get_marked_windows(state, marked_windows, sort)
+            .into_iter()
+            .map(|w| state.window_info(w)),
+        parts,
+        NiriusResponse::Windows,
+    ))
+}
 
-    if let Some(marked_windows) = state.mark_to_win_ids.get(&mark).cloned() {
+fn get_marked_windows<'a>(
+    state: &'a State,
+    win_ids: &[u64],
+    sort: Option<WindowOrder>,
+) -> Vec<&'a Window> {
+    sort_windows(
+        state,
+        state
+            .all_windows
+            .iter()
+            .filter(|w| win_ids.contains(&w.id))
+            .collect(),
+        sort,
+    )
+}
+
+/// Sends the response made of `items` by `make` to `parts` in parts of
+/// [`PART_SIZE`] items and returns the last part.  Without `parts`, the
+/// response of all items is returned.
+fn send_parts<T>(
+    items: impl Iterator<Item = T>,
+    parts: Option<&Parts>,
+    make: impl Fn(Vec<T>) -> NiriusResponse,
+) -> NiriusResponse {
+    let Some(parts) = parts else {
+        return make(items.collect());
+    };
+    let mut part = Vec::with_capacity(PART_SIZE);
+    for item in items {
+        part.push(item);
+        if part.len() == PART_SIZE {
+            // The client has gone away if sending fails, and then it doesn't
+            // matter.
+            let _ = parts.send(make(std::mem::replace(
+                &mut part,
+                Vec::with_capacity(PART_SIZE),
+            )));
+        }
+    }
+    make(part)
+}
+
+/// Returns the windows, which must be in the order of `state.all_windows`,
+/// in the given order.
+fn sort_windows<'a>(
+    state: &State,
+    mut wins: Vec<&'a Window>,
+    sort: Option<WindowOrder>,
+) -> Vec<&'a Window> {
+    let lowercase = |s: &Option<String>| s.as_deref().map(str::to_lowercase);
+    match sort {
+        None => (),
+        Some(WindowOrder::Mru) => wins.reverse(),
+        Some(WindowOrder::Workspace) => wins.sort_by_cached_key(|w| {
+            let ws = w.workspace_id.and_then(|id| {
+                state.all_workspaces.iter().find(|ws| ws.id == id)
+            });
+            (
+                ws.is_none(),
+                ws.and_then(|ws| ws.output.clone()),
+                ws.map(|ws| ws.idx),
+                w.is_floating,
+                w.layout.pos_in_scrolling_layout,
+            )
+        }),
+        Some(WindowOrder::AppId) => {
+            wins.sort_by_cached_key(|w| lowercase(&w.app_id))
+        }
+        Some(WindowOrder::Title) => {
+            wins.sort_by_cached_key(|w| lowercase(&w.title))
+        }
+    }
+    wins
+}
+
+fn list_follow(state: &State) -> NiriusResponse {
+    NiriusResponse::Windows(
+        state
+            .all_windows
+            .iter()
+            .filter(|w| state.follow_mode_win_ids.contains(&w.id))
+            .map(|w| state.window_info(w))
+            .collect(),
+    )
+}
+
+fn check_rules(state: &State) -> Result<String, NiriusError> {
+    let config = get_config();
+    let mut str = String::new();
+    for win in &state.all_windows {
+        str.push_str(&protocol::format_window_list(&[state.window_info(win)]));
+        let mut matched = false;
+        let mut add = |line: String| {
+            str.push_str(&format!("  {line}\n"));
+            matched = true;
+        };
+
+        if let Some(rule) = config.get_follow_mode_rule(win) {
+            add(format!(
+                "follow_mode_rule {:?}: follow-mode with focus {} and \
+                 scope {:?}",
+                rule.match_opts,
+                config.get_follow_mode_focus(win),
+                config.get_follow_mode_scope(win)
+            ));
+        }
+        for rule in config
+            .rules
+            .iter()
+            .filter(|r| window_matches(win, &r.match_opts))
         {
-            let wins: Vec<&Window> = state
-                .all_windows
-                .iter()
-                .filter(|w| marked_windows.contains(&w.id))
-                .collect();
-            let mut str = String::new();
-            for win in wins {
-                let line = format!(
-                    "id: {}, app-id: {:?}, title: {:?}, on workspace: {:?}",
-                    win.id, win.app_id, win.title, win.workspace_id
-                );
-                str.push_str(line.as_str());
-                str.push('\n');
-            }
-            Ok(str)
+            add(format!("rule {:?}: {:?}", rule.match_opts, rule.actions));
+        }
+        if let Some(rule) = config
+            .workspace_rules
+            .iter()
+            .find(|r| window_matches(win, &r.match_opts))
+        {
+            add(format!(
+                "workspace_rule {:?}: move to workspace {}",
+                rule.match_opts, rule.workspace
+            ));
+        }
+        for rule in config
+            .urgency_rules
+            .iter()
+            .filter(|r| window_matches(win, &r.match_opts))
+        {
+            add(format!(
+                "urgency_rule {:?}: notify {}, command {:?}",
+                rule.match_opts, rule.notify, rule.command
+            ));
+        }
+        for hook in config
+            .close_hooks
+            .iter()
+            .filter(|h| window_matches(win, &h.match_opts))
+        {
+            add(format!(
+                "close_hook {:?}: command {:?}",
+                hook.match_opts, hook.command
+            ));
         }
+
+        if !matched {
+            str.push_str("  no matching rules\n");
+        }
+    }
+    Ok(str)
+}
+
+fn watch(
+    state: &mut State,
+    match_opts: &MatchOptions,
+    command: &[String],
+) -> Result<String, NiriusError> {
+    let id = state.next_watcher_id;
+    state.next_watcher_id += 1;
+    state.watchers.push(Watcher {
+        id,
+        match_opts: match_opts.clone(),
+        command: command.to_vec(),
+    });
+    Ok(id.to_string())
+}
+
+fn unwatch(state: &mut State, id: u64) -> Result<String, NiriusError> {
+    let len = state.watchers.len();
+    state.watchers.retain(|w| w.id != id);
+    if state.watchers.len() < len {
+        Ok(format!("Removed watcher {id}."))
     } else {
-        Err("No such mark.".to_owned())
+        Err(format!("No watcher with id {id}.").into())
     }
 }
 
-fn list_all_marked() -> Result<String, String> {
-    let keys: Vec<String>;
-    // In a block so that we drop the RwLock before calling list_marked().  Not
-    // strictly needed anymore since we switched from a Mutex to a RwLock, but
-    // anyway.
-    {
-        keys = STATE
-            .read()
-            .expect("Could not read() STATE.")
-            .mark_to_win_ids
-            .keys()
-            .cloned()
-            .collect::<Vec<String>>();
+fn status(state: &State) -> NiriusResponse {
+    let (niri_version, niri_error) = match ipc::query_niri(Request::Version) {
+        Ok(Response::Version(version)) => (Some(version), None),
+        Ok(x) => (None, Some(ipc::unexpected_reply(x).message)),
+        Err(err) => (None, Some(err.message)),
+    };
+    let mut marked_win_ids: Vec<u64> =
+        state.mark_to_win_ids.values().flatten().copied().collect();
+    marked_win_ids.sort_unstable();
+    marked_win_ids.dedup();
+    NiriusResponse::Status(Status {
+        uptime_secs: daemon::uptime().as_secs(),
+        niri_version,
+        niri_error,
+        windows: state.all_windows.len(),
+        workspaces: state.all_workspaces.len(),
+        marks: state.mark_to_win_ids.len(),
+        marked_windows: marked_win_ids.len(),
+        scratchpad_windows: state.scratchpad_win_ids.len(),
+        follow_mode_windows: state.follow_mode_win_ids.len(),
+    })
+}
+
+fn list_watchers(state: &State) -> Result<String, NiriusError> {
+    let mut str = String::new();
+    for w in &state.watchers {
+        str.push_str(&format!(
+            "id: {}, app-id: {:?}, title: {:?}, command: {:?}\n",
+            w.id, w.match_opts.app_id, w.match_opts.title, w.command
+        ));
+    }
+    Ok(str)
+}
+
+fn wait_for(
+    match_opts: &MatchOptions,
+    new: bool,
+    timeout: Option<Duration>,
+    cancelled: &AtomicBool,
+) -> Result<NiriusResponse, NiriusError> {
+    let deadline = timeout.map(|t| Instant::now() + t);
+    let (count, cvar) = &*WINDOWS_CHANGED;
+    let lock = || count.lock().unwrap_or_else(PoisonError::into_inner);
+    let known_win_ids: Arc<Vec<u64>> = Arc::new(if new {
+        state::with_state(|state| {
+            Ok::<_, String>(state.all_windows.iter().map(|w| w.id).collect())
+        })?
+    } else {
+        vec![]
+    });
+
+    loop {
+        if cancelled.load(Ordering::Relaxed) {
+            return Err("Cancelled waiting for a window.".into());
+        }
+        // Windows appearing after the state has been checked change the
+        // generation, so they aren't missed by waiting for that to change.
+        // The lock mustn't be held while waiting for the state actor.
+        let generation = *lock();
+        let known_win_ids = known_win_ids.clone();
+        let match_opts = match_opts.clone();
+        let found = state::with_state(move |state| {
+            Ok::<_, String>(
+                state
+                    .all_windows
+                    .iter()
+                    .find(|w| {
+                        !known_win_ids.contains(&w.id)
+                            && window_matches(w, &match_opts)
+                    })
+                    .map(|w| state.window_info(w)),
+            )
+        })?;
+        if let Some(found) = found {
+            return Ok(NiriusResponse::Window(found));
+        }
+
+        let unchanged = |count: &mut u64| *count == generation;
+        match deadline {
+            None => {
+                drop(
+                    cvar.wait_while(lock(), unchanged)
+                        .unwrap_or_else(PoisonError::into_inner),
+                );
+            }
+            Some(deadline) => {
+                let now = Instant::now();
+                if now >= deadline {
+                    return Err("Timed out waiting for a window.".into());
+                }
+                drop(
+                    cvar.wait_timeout_while(lock(), deadline - now, unchanged)
+                        .unwrap_or_else(PoisonError::into_inner),
+                );
+            }
+        }
     }
+}
+
+fn list_windows(
+    state: &State,
+    match_opts: &MatchOptions,
+    sort: Option<WindowOrder>,
+    parts: Option<&Parts>,
+) -> NiriusResponse {
+    let wins = sort_windows(
+        state,
+        state
+            .all_windows
+            .iter()
+            .filter(|w| window_matches(w, match_opts))
+            .collect(),
+        sort,
+    );
+    send_parts(
+        wins.into_iter().map(|w| state.window_info(w)),
+        parts,
+        NiriusResponse::WindowList,
+    )
+}
+
+fn list_urgent(
+    state: &State,
+    match_opts: &MatchOptions,
+    sort: Option<WindowOrder>,
+    parts: Option<&Parts>,
+) -> NiriusResponse {
+    let wins: Vec<&Window> = if sort.is_some() {
+        state
+            .all_windows
+            .iter()
+            .filter(|w| state.urgent_win_ids.contains(&w.id))
+            .collect()
+    } else {
+        state
+            .urgent_win_ids
+            .iter()
+            .filter_map(|id| state.all_windows.iter().find(|w| w.id == *id))
+            .collect()
+    };
+    let wins = sort_windows(
+        state,
+        wins.into_iter()
+            .filter(|w| window_matches(w, match_opts))
+            .collect(),
+        sort,
+    );
+    send_parts(
+        wins.into_iter().map(|w| state.window_info(w)),
+        parts,
+        NiriusResponse::WindowList,
+    )
+}
+
+fn waybar(state: &State) -> WaybarModule {
+    let label = |id: &u64| {
+        let win = state.all_windows.iter().find(|w| w.id == *id);
+        format!(
+            "  {}: {}",
+            win.and_then(|w| w.app_id.as_deref()).unwrap_or("?"),
+            win.and_then(|w| w.title.as_deref()).unwrap_or("")
+        )
+    };
+    let urgent: Vec<u64> = state
+        .all_windows
+        .iter()
+        .filter(|w| w.is_urgent)
+        .map(|w| w.id)
+        .collect();
+    let mut marked: Vec<u64> =
+        state.mark_to_win_ids.values().flatten().copied().collect();
+    marked.sort();
+    marked.dedup();
 
-    let mut s = String::new();
-    for mark in keys {
-        s.push_str(format!("-> {mark}:\n").as_str());
-        match list_marked(mark.to_string()) {
-            Ok(marks) => s.push_str(marks.as_str()),
-            err @ Err(_) => return err,
+    let mut text = vec![];
+    let mut tooltip = vec![];
+    let mut class = vec![];
+    for (name, ids) in [
+        ("scratchpad", &state.scratchpad_win_ids),
+        ("urgent", &urgent),
+        ("marked", &marked),
+    ] {
+        if ids.is_empty() {
+            continue;
         }
+        text.push(format!("{name}: {}", ids.len()));
+        tooltip.push(format!("{name}:"));
+        tooltip.extend(ids.iter().map(label));
+        class.push(name.to_owned());
     }
-    Ok(s)
+    WaybarModule {
+        text: text.join(", "),
+        tooltip: tooltip.join("\n"),
+        class,
+    }
+}
+
+fn describe_window(
+    state: &State,
+    match_opts: &MatchOptions,
+) -> Result<NiriusResponse, NiriusError> {
+    let id = get_focused_or_matching_win_id(state, match_opts)?;
+    let win = state
+        .all_windows
+        .iter()
+        .find(|w| w.id == id)
+        .ok_or_else(no_matching_window)?;
+    Ok(NiriusResponse::WindowDetails(Box::new(
+        state.window_details(win),
+    )))
 }
 
-fn scratchpad_toggle(app_id: Option<&str>, no_move: bool) -> Result<String, String> {
-    let mut state = STATE.write().expect("Could not write() STATE.");
+fn list_workspaces(state: &State, parts: Option<&Parts>) -> NiriusResponse {
+    let mut workspaces: Vec<&Workspace> = state.all_workspaces.iter().collect();
+    workspaces.sort_by_key(|ws| (&ws.output, ws.idx));
+    send_parts(
+        workspaces.into_iter().map(|ws| WorkspaceInfo {
+            id: ws.id,
+            idx: ws.idx,
+            name: ws.name.clone(),
+            output: ws.output.clone(),
+            is_focused: ws.is_focused,
+            is_active: ws.is_active,
+            windows: state
+                .all_windows
+                .iter()
+                .filter(|w| w.workspace_id == Some(ws.id))
+                .map(|w| state.window_info(w))
+                .collect(),
+        }),
+        parts,
+        NiriusResponse::Workspaces,
+    )
+}
+
+fn list_all_marked(
+    state: &State,
+    sort: Option<WindowOrder>,
+    parts: Option<&Parts>,
+) -> NiriusResponse {
+    // Every marked window is one item, and marks without windows are an
+    // item without window so that they are listed, too.
+    let mut items = vec![];
+    for (mark, win_ids) in &state.mark_to_win_ids {
+        let wins = get_marked_windows(state, win_ids, sort);
+        if wins.is_empty() {
+            items.push((mark, None));
+        }
+        items.extend(wins.into_iter().map(|w| (mark, Some(w))));
+    }
+    send_parts(
+        items
+            .into_iter()
+            .map(|(mark, w)| (mark, w.map(|w| state.window_info(w)))),
+        parts,
+        |items| {
+            let mut marks = BTreeMap::<String, Vec<WindowInfo>>::new();
+            for (mark, win) in items {
+                marks.entry(mark.clone()).or_default().extend(win);
+            }
+            NiriusResponse::Marks(marks)
+        },
+    )
+}
 
+fn scratchpad_toggle(
+    state: &mut State,
+    app_id: Option<&str>,
+    no_move: bool,
+) -> Result<String, NiriusError> {
     let window_id = if let Some(app_id_pattern) = app_id {
         let regex = Regex::new(app_id_pattern)
             .map_err(|e| format!("Invalid regex pattern: {}", e))?;
@@ -423,9 +3195,7 @@ fn scratchpad_toggle(app_id: Option<&str>, no_move: bool) -> Result<String, Stri
             .map(|w| w.id)
             .ok_or_else(|| format!("No window found matching app-id pattern: {}", app_id_pattern))?
     } else {
-        state
-            .get_focused_win_id()
-            .ok_or_else(|| "No focused window.".to_owned())?
+        state.get_focused_win_id().ok_or_else(no_focused_window)?
     };
 
     if state.scratchpad_win_ids.contains(&window_id) {
@@ -437,14 +3207,12 @@ fn scratchpad_toggle(app_id: Option<&str>, no_move: bool) -> Result<String, Stri
         if no_move {
             Ok(format!("Added window {} to scratchpad (no move).", window_id))
         } else {
-            drop(state);
-            scratchpad_move()
+            scratchpad_move(state)
         }
     }
 }
 
-pub(crate) fn scratchpad_move() -> Result<String, String> {
-    let state = STATE.read().expect("Could not read() STATE.");
+pub(crate) fn scratchpad_move(state: &State) -> Result<String, NiriusError> {
     if state.scratchpad_win_ids.is_empty() {
         return Ok("No scratchpad windows to move.".to_owned());
     }
@@ -456,17 +3224,20 @@ pub(crate) fn scratchpad_move() -> Result<String, String> {
         state.get_bottom_workspace_id_and_idx_of_output(output)
     {
         let mut i = 0;
-        for w in state
-            .all_windows
-            .iter()
-            .filter(|w| state.scratchpad_win_ids.contains(&w.id))
-        {
+        // Windows hidden there already don't need requests to niri.
+        for w in state.all_windows.iter().filter(|w| {
+            state.scratchpad_win_ids.contains(&w.id)
+                && !(w.is_floating && w.workspace_id == Some(ws_id))
+        }) {
             if !w.is_floating {
-                ipc::query_niri(Request::Action(
+                exec_window_action(
+                    state,
                     Action::ToggleWindowFloating { id: Some(w.id) },
-                ))?;
+                )?;
+                restore_floating_geometry(state, w.id)?;
             }
             move_window_to_workspace(
+                state,
                 w.id,
                 niri_ipc::WorkspaceReferenceArg::Id(ws_id),
                 false,
@@ -477,18 +3248,20 @@ pub(crate) fn scratchpad_move() -> Result<String, String> {
             "Moved {i} scratchpad windows to workspace with id {ws_id}."
         ))
     } else {
-        Err("Can't move scratchpad windows. No focused workspace.".to_owned())
+        Err("Can't move scratchpad windows. No focused workspace.".into())
     }
 }
 
-fn scratchpad_show(app_id: Option<&str>) -> Result<String, String> {
-    let state = STATE.read().expect("Could not read STATE.");
+fn scratchpad_show(
+    state: &State,
+    app_id: Option<&str>,
+) -> Result<String, NiriusError> {
     let opt_win_id = state.get_focused_win_id();
     if opt_win_id
         .as_ref()
         .is_some_and(|w| state.scratchpad_win_ids.contains(w))
     {
-        scratchpad_move()
+        scratchpad_move(state)
     } else {
         let focused_ws_id = state
             .get_focused_workspace_id()
@@ -524,6 +3297,7 @@ fn scratchpad_show(app_id: Option<&str>) -> Result<String, String> {
         };
 
         move_window_to_workspace(
+            state,
             window_id,
             WorkspaceReferenceArg::Id(focused_ws_id),
             true,
@@ -531,3 +3305,62 @@ fn scratchpad_show(app_id: Option<&str>) -> Result<String, String> {
         focus_window_by_id(window_id)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn send_parts_sends_full_parts_and_returns_the_rest() {
+        let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+        let resp = send_parts(0..PART_SIZE * 2 + 1, Some(&tx), |items| {
+            NiriusResponse::Count(items.len())
+        });
+        assert!(matches!(resp, NiriusResponse::Count(1)));
+        for _ in 0..2 {
+            let part = rx.try_recv();
+            assert!(matches!(part, Ok(NiriusResponse::Count(PART_SIZE))));
+        }
+        assert!(rx.try_recv().is_err());
+
+        let resp = send_parts(0..PART_SIZE * 2, None, |items| {
+            NiriusResponse::Count(items.len())
+        });
+        assert!(matches!(resp, NiriusResponse::Count(n) if n == PART_SIZE * 2));
+    }
+
+    #[test]
+    fn action_for_window_retargets_window_actions() {
+        let action = Action::MoveWindowToWorkspace {
+            window_id: None,
+            reference: WorkspaceReferenceArg::Name("mail".to_owned()),
+            focus: false,
+        };
+        assert!(matches!(
+            action_for_window(&action, 7),
+            Ok(Some(Action::MoveWindowToWorkspace {
+                window_id: Some(7),
+                reference: WorkspaceReferenceArg::Name(name),
+                focus: false,
+            })) if name == "mail"
+        ));
+        assert!(matches!(
+            action_for_window(&Action::CloseWindow { id: None }, 7),
+            Ok(Some(Action::CloseWindow { id: Some(7) }))
+        ));
+        assert!(matches!(
+            action_for_window(&Action::MoveColumnLeft {}, 7),
+            Ok(None)
+        ));
+    }
+
+    #[test]
+    fn action_for_window_refuses_other_actions() {
+        let action = Action::Quit {
+            skip_confirmation: true,
+        };
+        let err = action_for_window(&action, 7).unwrap_err();
+        assert_eq!(err.code, ErrorCode::BadArguments);
+        assert!(err.message.contains("Quit doesn't act on a window"));
+    }
+}
@@ -0,0 +1,70 @@
+// Copyright (C) 2025  Tassilo Horn <tsdh@gnu.org>
+//
+// This program is free software: you can redistribute it and/or modify it
+// under the terms of the GNU General Public License as published by the Free
+// Software Foundation, either version 3 of the License, or (at your option)
+// any later version.
+//
+// This program is distributed in the hope that it will be useful, but WITHOUT
+// ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or
+// FITNESS FOR A PARTICULAR PURPOSE.  See the GNU General Public License for
+// more details.
+//
+// You should have received a copy of the GNU General Public License along with
+// this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! Dynamic shell completions of values only niriusd knows, such as mark names
+//! and app-ids.  Without a running daemon, nothing is offered.
+
+use std::collections::BTreeSet;
+use std::time::Duration;
+
+use clap_complete::engine::CompletionCandidate;
+
+use crate::client;
+use crate::cmds::{MatchOptions, NiriusCmd};
+use crate::config::get_config;
+use crate::protocol::NiriusResponse;
+
+/// Completion shouldn't hang on a stuck daemon.
+const TIMEOUT: Duration = Duration::from_millis(500);
+
+/// Returns the names of all marks except the default mark.
+pub fn marks() -> Vec<CompletionCandidate> {
+    let cmd = NiriusCmd::ListMarked {
+        mark: None,
+        all: true,
+        sort: None,
+    };
+    let default_mark = get_config().get_default_mark().to_owned();
+    match client::send_nirius_cmd(cmd, Some(TIMEOUT)) {
+        Ok(NiriusResponse::Marks(marks)) => marks
+            .into_iter()
+            .filter(|(mark, wins)| *mark != default_mark && !wins.is_empty())
+            .map(|(mark, wins)| {
+                CompletionCandidate::new(mark)
+                    .help(Some(format!("{} window(s)", wins.len()).into()))
+            })
+            .collect(),
+        _ => vec![],
+    }
+}
+
+/// Returns the app-ids of all windows.
+pub fn app_ids() -> Vec<CompletionCandidate> {
+    let cmd = NiriusCmd::ListWindows {
+        match_opts: MatchOptions::default(),
+        json: false,
+        sort: None,
+    };
+    match client::send_nirius_cmd(cmd, Some(TIMEOUT)) {
+        Ok(NiriusResponse::WindowList(wins)) => wins
+            .into_iter()
+            .filter_map(|w| w.app_id)
+            .collect::<BTreeSet<_>>()
+            .into_iter()
+            .map(CompletionCandidate::new)
+            .collect(),
+        _ => vec![],
+    }
+}
@@ -0,0 +1,729 @@
+// Copyright (C) 2025  Tassilo Horn <tsdh@gnu.org>
+//
+// This program is free software: you can redistribute it and/or modify it
+// under the terms of the GNU General Public License as published by the Free
+// Software Foundation, either version 3 of the License, or (at your option)
+// any later version.
+//
+// This program is distributed in the hope that it will be useful, but WITHOUT
+// ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or
+// FITNESS FOR A PARTICULAR PURPOSE.  See the GNU General Public License for
+// more details.
+//
+// You should have received a copy of the GNU General Public License along with
+// this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! The niriusd configuration which is read from
+//! `$XDG_CONFIG_HOME/nirius/config.toml` and the drop-in files
+//! `$XDG_CONFIG_HOME/nirius/config.d/*.toml`.
+
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, LazyLock, PoisonError, RwLock};
+use std::time::Duration;
+
+use niri_ipc::Window;
+use serde::Deserialize;
+
+use crate::cmds::{self, MatchOptions};
+use crate::util;
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct Config {
+    /// Whether windows in follow-mode receive focus when they are moved to
+    /// a newly focused workspace.  Can be overridden per follow-mode rule.
+    pub follow_mode_focus: bool,
+    /// On which outputs workspace switches move windows in follow-mode.  Can
+    /// be overridden per follow-mode rule.
+    pub follow_mode_scope: FollowScope,
+    /// Windows matching any of these rules are put into follow-mode as soon
+    /// as they appear.
+    #[serde(rename = "follow_mode_rule")]
+    pub follow_mode_rules: Vec<FollowModeRule>,
+    /// Whether the daemon should remember the keyboard layout of each window
+    /// and switch back to it when the window receives focus.
+    pub per_window_keyboard_layout: bool,
+    /// Settings for the `toggle-pip` command.
+    pub pip: PipConfig,
+    /// Settings for automatically naming workspaces after their windows.
+    pub auto_name_workspaces: AutoNameWorkspacesConfig,
+    #[serde(rename = "project")]
+    pub projects: Vec<Project>,
+    /// New windows matching one of these rules are moved to the rule's named
+    /// workspace.
+    #[serde(rename = "workspace_rule")]
+    pub workspace_rules: Vec<WorkspaceRule>,
+    /// General window rules whose actions are applied to matching windows
+    /// when they appear.
+    #[serde(rename = "rule")]
+    pub rules: Vec<WindowRule>,
+    /// Settings for hiding terminals while the GUI apps they launched run.
+    pub swallow: SwallowConfig,
+    /// Shell commands run on certain events.
+    pub hooks: HooksConfig,
+    /// What to do when a matching window becomes urgent.
+    #[serde(rename = "urgency_rule")]
+    pub urgency_rules: Vec<UrgencyRule>,
+    /// Shell commands run when a matching window closes.
+    #[serde(rename = "close_hook")]
+    pub close_hooks: Vec<CloseHook>,
+    /// A Rhai script reacting on niri events.  Requires the `scripting`
+    /// feature.
+    pub script: Option<PathBuf>,
+    /// Scratchpad windows which are shown but haven't had focus for this long
+    /// are moved back to the scratchpad.
+    #[serde(deserialize_with = "deserialize_opt_duration")]
+    pub scratchpad_auto_hide: Option<Duration>,
+    /// Nirius commands run periodically.
+    #[serde(rename = "timer")]
+    pub timers: Vec<TimerConfig>,
+    /// Whether the daemon records how long windows of each app-id have focus
+    /// for `nirius time-report`.
+    pub track_focus_time: bool,
+    /// Where workspaces and windows belong depending on which outputs are
+    /// connected.
+    #[serde(rename = "output_profile")]
+    pub output_profiles: Vec<OutputProfile>,
+    /// Named sequences of nirius command lines run by `nirius run`.
+    pub aliases: HashMap<String, Vec<String>>,
+    /// The mark used by the mark commands if none is given.  Defaults to
+    /// [`cmds::DEFAULT_MARK`].
+    pub default_mark: Option<String>,
+    /// Default arguments per nirius command, e.g., `["--focus"]` for
+    /// `move-to-current-workspace`.
+    pub defaults: HashMap<String, Vec<String>>,
+    /// The profile whose settings are merged into the others unless another
+    /// one is switched to with `nirius profile switch`.
+    pub active_profile: Option<String>,
+    /// Named sets of settings which are merged into the others when the
+    /// profile is active.
+    pub profiles: HashMap<String, toml::Table>,
+    /// Named sizes and positions of floating windows applied by `nirius
+    /// preset`.
+    pub presets: HashMap<String, Preset>,
+}
+
+/// The size and position a floating window gets from `nirius preset`.
+/// Missing values aren't changed.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct Preset {
+    pub x: Option<Length>,
+    pub y: Option<Length>,
+    pub width: Option<Length>,
+    pub height: Option<Length>,
+}
+
+/// Given as logical pixels or as a string like `"50%"` meaning a percentage
+/// of the output's width or height.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(try_from = "LengthRepr")]
+pub enum Length {
+    Pixels(i32),
+    Percent(f64),
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum LengthRepr {
+    Pixels(i32),
+    Percent(String),
+}
+
+impl TryFrom<LengthRepr> for Length {
+    type Error = String;
+
+    fn try_from(repr: LengthRepr) -> Result<Self, Self::Error> {
+        match repr {
+            LengthRepr::Pixels(px) => Ok(Length::Pixels(px)),
+            LengthRepr::Percent(s) => s
+                .strip_suffix('%')
+                .and_then(|p| p.trim().parse::<f64>().ok())
+                .filter(|p| (0.0..=100.0).contains(p))
+                .map(Length::Percent)
+                .ok_or_else(|| {
+                    format!(
+                        "Invalid length {s}, expected pixels or 0% to 100%."
+                    )
+                }),
+        }
+    }
+}
+
+impl Length {
+    /// Returns the length in logical pixels given the output's width or
+    /// height.
+    pub fn to_pixels(self, output_length: i32) -> i32 {
+        match self {
+            Length::Pixels(px) => px,
+            Length::Percent(p) => (f64::from(output_length) * p / 100.0) as i32,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct TimerConfig {
+    /// The interval in which the command is run.
+    #[serde(deserialize_with = "deserialize_interval")]
+    pub every: Duration,
+    /// The nirius command line, e.g., `["scratchpad-toggle", "-a", "foot"]`.
+    pub command: Vec<String>,
+}
+
+fn deserialize_duration<'de, D>(deserializer: D) -> Result<Duration, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    let s = String::deserialize(deserializer)?;
+    util::parse_duration(&s).map_err(serde::de::Error::custom)
+}
+
+fn deserialize_interval<'de, D>(deserializer: D) -> Result<Duration, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    let every = deserialize_duration(deserializer)?;
+    if every.is_zero() {
+        return Err(serde::de::Error::custom("The interval must not be zero."));
+    }
+    Ok(every)
+}
+
+fn deserialize_opt_duration<'de, D>(
+    deserializer: D,
+) -> Result<Option<Duration>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    deserialize_duration(deserializer).map(Some)
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct HooksConfig {
+    pub on_window_open: Option<String>,
+    pub on_window_close: Option<String>,
+    pub on_workspace_focus: Option<String>,
+    pub on_output_connect: Option<String>,
+    pub on_output_disconnect: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct UrgencyRule {
+    #[serde(flatten)]
+    pub match_opts: MatchOptions,
+    /// Whether to send a desktop notification.  Clicking it focuses the
+    /// window.
+    #[serde(default)]
+    pub notify: bool,
+    /// A shell command run like the other hooks.
+    pub command: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct CloseHook {
+    #[serde(flatten)]
+    pub match_opts: MatchOptions,
+    pub command: String,
+}
+
+/// An output profile is applied when exactly its outputs are connected.
+#[derive(Debug, Deserialize)]
+pub struct OutputProfile {
+    pub name: String,
+    pub outputs: Vec<String>,
+    /// Maps names of workspaces to the outputs they belong on.
+    #[serde(default)]
+    pub workspaces: HashMap<String, String>,
+    #[serde(default, rename = "app")]
+    pub apps: Vec<OutputProfileApp>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct OutputProfileApp {
+    #[serde(flatten)]
+    pub match_opts: MatchOptions,
+    pub output: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct WorkspaceRule {
+    #[serde(flatten)]
+    pub match_opts: MatchOptions,
+    pub workspace: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct WindowRule {
+    #[serde(flatten)]
+    pub match_opts: MatchOptions,
+    pub actions: Vec<RuleAction>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum RuleAction {
+    /// Makes the window floating.
+    Float,
+    /// Makes the window tiled.
+    Tile,
+    /// Moves the window to the named workspace, creating it if needed.
+    Workspace(String),
+    /// Marks the window with the given mark.
+    Mark(String),
+    /// Makes the window a scratchpad window.
+    Scratchpad,
+    /// Enables follow-mode for the window.
+    Follow,
+    /// Sets the size of the window.
+    Resize { width: i32, height: i32 },
+    /// Moves the window to the active workspace of the given output.
+    Output(String),
+    /// Makes the window fullscreen.
+    Fullscreen,
+}
+
+/// A project is a named workspace with a set of apps, see the `project open`
+/// command.
+#[derive(Debug, Deserialize)]
+pub struct Project {
+    pub name: String,
+    /// The name of the project's workspace.  Defaults to the project name.
+    pub workspace: Option<String>,
+    #[serde(default, rename = "app")]
+    pub apps: Vec<ProjectApp>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ProjectApp {
+    /// Used for checking if the app is running already and for finding its
+    /// window once it appears.
+    #[serde(flatten)]
+    pub match_opts: MatchOptions,
+    pub command: Vec<String>,
+    #[serde(default)]
+    pub floating: bool,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct AutoNameWorkspacesConfig {
+    pub enabled: bool,
+    /// Maps app-ids to the label used as workspace name.  Apps without label
+    /// use their app-id.
+    pub labels: HashMap<String, String>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default)]
+pub struct SwallowConfig {
+    pub enabled: bool,
+    /// A regex matched on the app-ids of terminals which may be swallowed.
+    pub terminal_app_id: String,
+}
+
+impl Default for SwallowConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            terminal_app_id: concat!(
+                r"^(foot|Alacritty|kitty|org\.wezfurlong\.wezterm",
+                r"|com\.mitchellh\.ghostty)$"
+            )
+            .to_owned(),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default)]
+pub struct PipConfig {
+    pub width: i32,
+    pub height: i32,
+    pub corner: Corner,
+    /// The gap between the window and the edges of the output.
+    pub margin: i32,
+}
+
+impl Default for PipConfig {
+    fn default() -> Self {
+        Self {
+            width: 480,
+            height: 270,
+            corner: Corner::BottomRight,
+            margin: 16,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Corner {
+    TopLeft,
+    TopRight,
+    BottomLeft,
+    BottomRight,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct FollowModeRule {
+    #[serde(flatten)]
+    pub match_opts: MatchOptions,
+    /// Overrides [`Config::follow_mode_focus`] for matching windows.
+    pub focus: Option<bool>,
+    /// Overrides [`Config::follow_mode_scope`] for matching windows.
+    pub scope: Option<FollowScope>,
+}
+
+/// Given as `all`, `own-output`, or the name of an output.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+#[serde(from = "String")]
+pub enum FollowScope {
+    /// Windows follow to workspaces on all outputs.
+    #[default]
+    All,
+    /// Windows only follow to workspaces on the output they are on.
+    OwnOutput,
+    /// Windows only follow to workspaces on the given output.
+    Output(String),
+}
+
+impl From<String> for FollowScope {
+    fn from(s: String) -> Self {
+        match s.as_str() {
+            "all" => FollowScope::All,
+            "own-output" => FollowScope::OwnOutput,
+            _ => FollowScope::Output(s),
+        }
+    }
+}
+
+impl Config {
+    /// Returns the first follow-mode rule matching the given window.
+    pub fn get_follow_mode_rule(
+        &self,
+        win: &Window,
+    ) -> Option<&FollowModeRule> {
+        self.follow_mode_rules
+            .iter()
+            .find(|r| cmds::window_matches(win, &r.match_opts))
+    }
+
+    /// Returns on which outputs workspace switches move the given window if
+    /// it's in follow-mode.
+    pub fn get_follow_mode_scope(&self, win: &Window) -> &FollowScope {
+        self.get_follow_mode_rule(win)
+            .and_then(|r| r.scope.as_ref())
+            .unwrap_or(&self.follow_mode_scope)
+    }
+
+    /// Returns the mark used by the mark commands if none is given.
+    pub fn get_default_mark(&self) -> &str {
+        self.default_mark.as_deref().unwrap_or(cmds::DEFAULT_MARK)
+    }
+
+    /// Returns the output profile for exactly the given outputs.
+    pub fn get_output_profile(
+        &self,
+        outputs: &[&str],
+    ) -> Option<&OutputProfile> {
+        self.output_profiles.iter().find(|p| {
+            p.outputs.len() == outputs.len()
+                && p.outputs.iter().all(|o| outputs.contains(&o.as_str()))
+        })
+    }
+
+    /// Returns whether the given window should be focused when it's moved
+    /// due to follow-mode.
+    pub fn get_follow_mode_focus(&self, win: &Window) -> bool {
+        self.get_follow_mode_rule(win)
+            .and_then(|r| r.focus)
+            .unwrap_or(self.follow_mode_focus)
+    }
+}
+
+pub fn get_config_file_path() -> Option<PathBuf> {
+    directories::BaseDirs::new()
+        .map(|dirs| dirs.config_dir().join("nirius").join("config.toml"))
+}
+
+/// Returns the directory of config drop-in files which are merged into the
+/// config file in the order of their names.
+pub fn get_drop_in_dir_path() -> Option<PathBuf> {
+    get_config_file_path().map(|path| path.with_file_name("config.d"))
+}
+
+/// Returns the `*.toml` files in the drop-in directory sorted by name.
+fn get_drop_in_files(dir: &Path) -> Result<Vec<PathBuf>, String> {
+    let entries = match std::fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => {
+            return Ok(vec![]);
+        }
+        Err(err) => {
+            return Err(format!("Could not read {}.\n{err}", dir.display()));
+        }
+    };
+    let mut files: Vec<PathBuf> = entries
+        .filter_map(|e| e.ok().map(|e| e.path()))
+        .filter(|p| {
+            p.is_file() && p.extension().is_some_and(|ext| ext == "toml")
+        })
+        .collect();
+    files.sort();
+    Ok(files)
+}
+
+/// Returns the config file followed by the drop-in files.  The config file
+/// needn't exist.
+pub(crate) fn get_config_files() -> Result<Vec<PathBuf>, String> {
+    let path = get_config_file_path()
+        .ok_or("Couldn't determine the config directory!")?;
+    let drop_in_dir = get_drop_in_dir_path()
+        .ok_or("Couldn't determine the config directory!")?;
+    let mut files = vec![path];
+    files.extend(get_drop_in_files(&drop_in_dir)?);
+    Ok(files)
+}
+
+/// Merges `other` into `base`.  Tables are merged recursively, arrays of
+/// tables like `[[rule]]` are concatenated, and other values of `other`
+/// replace the ones of `base`.  So rules of a drop-in file or profile are
+/// appended to the ones of `config.toml` rather than replacing them.
+fn merge_tables(base: &mut toml::Table, other: toml::Table) {
+    for (key, value) in other {
+        match (base.get_mut(&key), value) {
+            (Some(toml::Value::Table(b)), toml::Value::Table(o)) => {
+                merge_tables(b, o)
+            }
+            (Some(toml::Value::Array(b)), toml::Value::Array(o))
+                if b.iter().chain(&o).all(toml::Value::is_table) =>
+            {
+                b.extend(o)
+            }
+            (_, value) => {
+                base.insert(key, value);
+            }
+        }
+    }
+}
+
+/// The profile switched to at runtime which overrides `active_profile`.
+static SWITCHED_PROFILE: RwLock<Option<String>> = RwLock::new(None);
+
+/// Merges the settings of the switched-to profile, or else the one given by
+/// `active_profile`, into the config.
+fn merge_active_profile(config: &mut toml::Table) -> Result<(), String> {
+    let switched = SWITCHED_PROFILE
+        .read()
+        .unwrap_or_else(PoisonError::into_inner)
+        .clone();
+    let name = match switched {
+        Some(name) => name,
+        None => match config.get("active_profile") {
+            Some(toml::Value::String(name)) => name.clone(),
+            _ => return Ok(()),
+        },
+    };
+    let profile = config
+        .get("profiles")
+        .and_then(|p| p.get(&name))
+        .and_then(toml::Value::as_table)
+        .ok_or_else(|| format!("No profile {name}."))?
+        .clone();
+    merge_tables(config, profile);
+    config.insert("active_profile".to_owned(), toml::Value::String(name));
+    Ok(())
+}
+
+/// Expands the environment variables in all strings of the value, see
+/// [`util::expand_env_vars`].
+fn expand_env_vars(value: &mut toml::Value) {
+    match value {
+        toml::Value::String(s) => *s = util::expand_env_vars(s),
+        toml::Value::Array(values) => {
+            values.iter_mut().for_each(expand_env_vars)
+        }
+        toml::Value::Table(table) => {
+            table.iter_mut().for_each(|(_, v)| expand_env_vars(v))
+        }
+        _ => (),
+    }
+}
+
+/// Parses a config file and expands the environment variables in its
+/// strings.  The file must be a valid config on its own after the expansion
+/// so that errors are reported for the file containing them.
+pub(crate) fn parse_config_file(content: &str) -> Result<toml::Table, String> {
+    let mut table =
+        toml::from_str::<toml::Table>(content).map_err(|e| e.to_string())?;
+    table.iter_mut().for_each(|(_, v)| expand_env_vars(v));
+    Config::deserialize(toml::Value::Table(table.clone()))
+        .map_err(|e| e.to_string())?;
+    Ok(table)
+}
+
+/// Reads and parses the config file and the drop-in files.  If there are
+/// none, the default config results.
+fn read_config() -> Result<Config, String> {
+    read_config_files(&get_config_files()?)
+}
+
+/// Reads, parses and merges the given config files.  Missing files are
+/// skipped.
+pub(crate) fn read_config_files(paths: &[PathBuf]) -> Result<Config, String> {
+    let mut merged = toml::Table::new();
+    let mut loaded = vec![];
+    for path in paths {
+        match std::fs::read_to_string(path) {
+            Ok(content) => {
+                let table = parse_config_file(&content).map_err(|err| {
+                    format!("Could not parse {}.\n{err}", path.display())
+                })?;
+                merge_tables(&mut merged, table);
+                loaded.push(path.display().to_string());
+            }
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => {
+                tracing::debug!("No config file at {}.", path.display());
+            }
+            Err(err) => {
+                return Err(format!(
+                    "Could not read {}.\n{err}",
+                    path.display()
+                ));
+            }
+        }
+    }
+    merge_active_profile(&mut merged)?;
+    if loaded.is_empty() {
+        return Ok(Config::default());
+    }
+    let config = toml::Value::Table(merged)
+        .try_into::<Config>()
+        .map_err(|e| format!("Could not merge {}.\n{e}", loaded.join(", ")))?;
+    tracing::info!("Loaded config from {}.", loaded.join(", "));
+    Ok(config)
+}
+
+fn load_config() -> Config {
+    read_config().unwrap_or_else(|err| {
+        tracing::error!("{err}\nUsing the default config.");
+        Config::default()
+    })
+}
+
+static CONFIG: LazyLock<RwLock<Arc<Config>>> =
+    LazyLock::new(|| RwLock::new(Arc::new(load_config())));
+
+/// Returns the current config.  It stays valid even if the config is
+/// reloaded in the meantime.
+pub fn get_config() -> Arc<Config> {
+    CONFIG
+        .read()
+        .unwrap_or_else(PoisonError::into_inner)
+        .clone()
+}
+
+/// Sets the profile which is active from the next reload of the config on,
+/// and returns the previously switched-to one.
+pub fn switch_profile(name: Option<String>) -> Option<String> {
+    std::mem::replace(
+        &mut *SWITCHED_PROFILE
+            .write()
+            .unwrap_or_else(PoisonError::into_inner),
+        name,
+    )
+}
+
+/// Returns the names of the profiles with the active one marked.
+pub fn list_profiles() -> String {
+    let config = get_config();
+    let mut names: Vec<&String> = config.profiles.keys().collect();
+    names.sort();
+    if names.is_empty() {
+        return "No profiles are defined.".to_owned();
+    }
+    names
+        .into_iter()
+        .map(|name| {
+            let active = config.active_profile.as_ref() == Some(name);
+            format!("{name}{}\n", if active { " (active)" } else { "" })
+        })
+        .collect()
+}
+
+/// Re-reads the config file.  If it contains errors, the current config is
+/// kept.
+pub fn reload_config() -> Result<String, String> {
+    let config = read_config()?;
+    *CONFIG.write().unwrap_or_else(PoisonError::into_inner) = Arc::new(config);
+    Ok("Reloaded the config.".to_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn timer_interval_must_not_be_zero() {
+        let timer: TimerConfig =
+            toml::from_str("every = \"5m\"\ncommand = [\"undo\"]").unwrap();
+        assert_eq!(timer.every, Duration::from_secs(300));
+        assert!(
+            toml::from_str::<TimerConfig>(
+                "every = \"0s\"\ncommand = [\"undo\"]"
+            )
+            .is_err()
+        );
+    }
+
+    fn table(s: &str) -> toml::Table {
+        toml::from_str(s).unwrap()
+    }
+
+    #[test]
+    fn merge_tables_merges_nested_tables() {
+        let mut base = table("[a]\nx = 1\ny = 2\n[a.b]\nz = 3");
+        merge_tables(&mut base, table("[a]\ny = 20\n[a.b]\nw = 4"));
+        assert_eq!(base, table("[a]\nx = 1\ny = 20\n[a.b]\nz = 3\nw = 4"));
+    }
+
+    #[test]
+    fn merge_tables_appends_arrays_of_tables() {
+        let mut base = table("[[rule]]\napp_id = \"foot\"");
+        merge_tables(&mut base, table("[[rule]]\napp_id = \"emacs\""));
+        assert_eq!(
+            base,
+            table("[[rule]]\napp_id = \"foot\"\n[[rule]]\napp_id = \"emacs\"")
+        );
+    }
+
+    #[test]
+    fn merge_tables_replaces_other_values() {
+        let mut base =
+            table("x = 1\nl = [1, 2]\nt = { a = 1 }\nr = [{ a = 1 }]");
+        merge_tables(&mut base, table("x = \"s\"\nl = [3]\nt = 5\nr = [1]"));
+        assert_eq!(base, table("x = \"s\"\nl = [3]\nt = 5\nr = [1]"));
+    }
+
+    #[test]
+    fn env_vars_are_expanded_before_validation() {
+        // Cargo sets `CARGO_PKG_VERSION_MAJOR` when running the tests, too.
+        let table = parse_config_file(
+            "[[timer]]\nevery = \"1${CARGO_PKG_VERSION_MAJOR}s\"\n\
+             command = [\"undo\"]",
+        )
+        .unwrap();
+        let config = Config::deserialize(toml::Value::Table(table)).unwrap();
+        let every = format!("1{}s", env!("CARGO_PKG_VERSION_MAJOR"));
+        assert_eq!(
+            config.timers[0].every,
+            util::parse_duration(&every).unwrap()
+        );
+        assert!(
+            parse_config_file("scratchpad_auto_hide = \"${NIRIUS_UNSET}\"")
+                .is_err()
+        );
+    }
+}
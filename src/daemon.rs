@@ -15,119 +15,526 @@
 
 //! Functions and data structures of the niriusd daemon.
 
-use std::io::ErrorKind;
-use std::os::unix::net::UnixListener;
-use std::os::unix::net::UnixStream;
+use std::os::fd::{AsFd, AsRawFd, FromRawFd};
+use std::os::unix::net::UnixListener as StdUnixListener;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, OnceLock};
+use std::time::{Duration, Instant};
 
+use inotify::{Inotify, WatchMask};
+use niri_ipc::Action;
+use niri_ipc::LayoutSwitchTarget;
 use niri_ipc::Request;
 use niri_ipc::Response;
+use niri_ipc::SizeChange;
 use niri_ipc::WorkspaceReferenceArg;
+use regex::Regex;
+use serde::de::IgnoredAny;
+use tokio::io::unix::AsyncFd;
+use tokio::io::{
+    AsyncBufReadExt, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader,
+    Interest,
+};
+use tokio::net::{UnixListener, UnixStream};
+use tokio::sync::{Notify, broadcast, mpsc};
+use tokio::task::JoinSet;
+use tokio::time::timeout;
+use tracing::{Instrument, field};
 
 use crate::cmds;
+use crate::config::{self, FollowScope, RuleAction, get_config};
+use crate::events;
+use crate::history;
+use crate::hooks;
 use crate::ipc;
-use crate::state::STATE;
+use crate::protocol::{
+    self, ErrorCode, NiriusError, NiriusResponse, WireFormat,
+};
+use crate::state::{self, State};
+use crate::stats;
+use crate::time_tracking;
+use crate::timers;
+use crate::undo;
 use crate::util;
 
-pub fn run_daemon() {
-    std::thread::spawn(init_then_process_events);
-    serve_client_requests();
-}
-
-fn init_then_process_events() -> std::io::Result<()> {
-    match ipc::query_niri(Request::Windows) {
-        Ok(response) => match response {
-            Response::Windows(wins) => {
-                let mut state =
-                    STATE.write().expect("Could not write() STATE.");
-                log::info!("Initializing state with {} windows.", wins.len());
-                for win in wins {
-                    let msg = state.register_window(win.clone()).unwrap();
-                    log::info!("{}", msg);
+/// The command line options of niriusd.  They are defined here so that
+/// `nirius generate-man` can document niriusd, too.
+#[derive(clap::Parser)]
+#[clap(name = "niriusd", about, version, author)]
+pub struct DaemonOpts {
+    /// Replace an already running niriusd instead of refusing to start
+    #[clap(long)]
+    pub replace: bool,
+    /// The socket to listen on instead of the one derived from
+    /// XDG_RUNTIME_DIR and WAYLAND_DISPLAY
+    #[clap(long)]
+    pub socket: Option<String>,
+    /// The socket of the niri instance to connect to instead of NIRI_SOCKET,
+    /// e.g., of a nested niri.  Can also be given in NIRIUS_NIRI_SOCKET.
+    #[clap(long)]
+    pub niri_socket: Option<String>,
+}
+
+/// When the daemon has been started.
+static STARTED: OnceLock<Instant> = OnceLock::new();
+
+/// Returns how long the daemon has been running.
+pub(crate) fn uptime() -> Duration {
+    STARTED.get().map(Instant::elapsed).unwrap_or_default()
+}
+
+/// Runs the daemon.  If another niriusd is already serving clients, it's
+/// terminated if `replace` is true, otherwise an error is returned.
+pub fn run_daemon(replace: bool) -> Result<(), String> {
+    STARTED.get_or_init(Instant::now);
+    // Load the config right away so that errors show up early in the log.
+    get_config();
+    // Take the socket before starting any threads because this modifies the
+    // environment.
+    let activated = take_activated_socket();
+    let runtime = tokio::runtime::Runtime::new()
+        .map_err(|e| format!("Could not start the tokio runtime: {e}"))?;
+    let result = runtime.block_on(run(activated, replace));
+    // Don't wait for blocking tasks such as the config file watcher which
+    // never finish.
+    runtime.shutdown_background();
+    result
+}
+
+/// Signals [`serve_client_requests`] to shut down the daemon.
+static SHUTDOWN: Notify = Notify::const_new();
+
+/// Signals the clients subscribed to events that the daemon shuts down.
+static STOP_SUBSCRIPTIONS: Notify = Notify::const_new();
+
+/// Saves the state and shuts down the daemon after it has answered the
+/// pending client requests.
+pub(crate) fn stop_daemon() -> Result<String, String> {
+    state::with_state(|state| state.save())?;
+    time_tracking::focus_changed(None);
+    time_tracking::flush();
+    SHUTDOWN.notify_one();
+    Ok("Stopping niriusd.".to_owned())
+}
+
+/// Runs the daemon's tasks: reading niri's event stream, handling the events,
+/// serving clients, timers and watching the config file.
+async fn run(
+    activated: Option<StdUnixListener>,
+    replace: bool,
+) -> Result<(), String> {
+    // Bind the socket first so that we don't do anything if another daemon
+    // is running.  Clients connecting until the state is initialized wait in
+    // the socket's backlog.  An activated socket belongs to systemd.
+    let owned_socket_path =
+        activated.is_none().then(util::get_nirius_socket_path);
+    let listener = match activated {
+        Some(listener) => listener
+            .set_nonblocking(true)
+            .and_then(|()| UnixListener::from_std(listener))
+            .map_err(|e| format!("Could not use activated socket: {e}"))?,
+        None => bind_socket(replace).await?,
+    };
+    tokio::spawn(run_watchdog());
+    tokio::spawn(flush_focus_time());
+    schedule_config_timers();
+    tokio::task::spawn_blocking(watch_config_file);
+    // Initialize the state before serving clients so that their commands
+    // can rely on it.
+    match tokio::task::spawn_blocking(init_state).await {
+        Ok(Ok(msg)) => tracing::info!("{msg}"),
+        Ok(Err(err)) => tracing::error!("Could not initialize state: {err}"),
+        Err(err) => tracing::error!("Could not initialize state: {err}"),
+    }
+    #[cfg(feature = "dbus")]
+    tokio::spawn(crate::dbus::serve());
+
+    let (tx, rx) = mpsc::unbounded_channel();
+    tokio::spawn(async move {
+        match read_events(tx).await {
+            Ok(()) => {
+                tracing::error!(
+                    "Received EOF, niri has quit and so do I. Goodbye!"
+                );
+                std::process::exit(0)
+            }
+            Err(err) => {
+                tracing::error!("Could not read niri's event stream: {err}");
+                std::process::exit(1)
+            }
+        }
+    });
+    // Event handling queries niri synchronously, so it runs on its own
+    // blocking thread where events are processed in order.
+    tokio::task::spawn_blocking(move || process_events(rx));
+    serve_client_requests(listener, owned_socket_path).await;
+    tracing::info!("Stopped niriusd.");
+    Ok(())
+}
+
+/// Pings systemd's watchdog if it's enabled for the niriusd service, i.e.,
+/// `WatchdogSec=` is set.  The pings stop as soon as the state actor hangs
+/// so that systemd can restart the daemon.
+async fn run_watchdog() {
+    let mut usec = 0;
+    if !sd_notify::watchdog_enabled(false, &mut usec) {
+        return;
+    }
+    // Ping twice per watchdog interval as recommended by systemd.
+    let period = Duration::from_micros(usec) / 2;
+    let mut interval = tokio::time::interval(period);
+    // A probe which timed out still waits for the state actor.  It's awaited
+    // again instead of starting more probes piling up on a hung actor.
+    let mut pending = None;
+    loop {
+        interval.tick().await;
+        let mut alive = pending.take().unwrap_or_else(|| {
+            tokio::task::spawn_blocking(|| {
+                state::with_state(|_| Ok::<_, String>(()))
+            })
+        });
+        match timeout(period, &mut alive).await {
+            Ok(Ok(Ok(()))) => {
+                if let Err(err) = sd_notify::notify(
+                    false,
+                    &[sd_notify::NotifyState::Watchdog],
+                ) {
+                    tracing::error!("Could not ping the watchdog: {err}");
                 }
             }
-            x => panic!("Received unexpected reply {x:?}"),
-        },
-        Err(err) => panic!("Could not query niri for windows: {err}"),
-    }
-
-    process_events()
-}
-
-fn process_events() -> std::io::Result<()> {
-    let mut socket = niri_ipc::socket::Socket::connect()?;
-
-    match socket.send(Request::EventStream) {
-        Ok(response) => match response {
-            Ok(Response::Handled) => {
-                let mut read_event = socket.read_events();
-                loop {
-                    match read_event() {
-                        Ok(event) => match handle_event(&event) {
-                            Ok(msg) => {
-                                log::info!(
-                                    "Handled event successfully: {event:?} => {msg}"
-                                )
-                            }
-                            Err(e) => {
-                                log::error!(
-                                    "Error during event-handling: {e:?}"
-                                )
-                            }
-                        },
-                        Err(err) => {
-                            if err.kind() == ErrorKind::UnexpectedEof {
-                                log::error!(
-                                    "Received EOF, niri has quit and so do I. Goodbye!"
-                                );
-                                std::process::exit(0)
-                            }
-                            log::error!("Could not read event: {err:?}")
-                        }
+            Ok(_) => tracing::error!("The state actor doesn't respond."),
+            Err(_) => {
+                tracing::error!("The state actor doesn't respond.");
+                pending = Some(alive);
+            }
+        }
+    }
+}
+
+/// Writes the focus times to their log now and then.
+async fn flush_focus_time() {
+    let mut interval = tokio::time::interval(time_tracking::FLUSH_INTERVAL);
+    loop {
+        interval.tick().await;
+        let _ = tokio::task::spawn_blocking(time_tracking::flush).await;
+    }
+}
+
+/// Tells systemd that niriusd is ready to serve clients.  Does nothing if
+/// niriusd hasn't been started as a `Type=notify` service.
+fn notify_ready() {
+    if let Err(err) = sd_notify::notify(false, &[sd_notify::NotifyState::Ready])
+    {
+        tracing::error!("Could not notify systemd: {err}");
+    }
+}
+
+const CONFIG_TIMER_PREFIX: &str = "[[timer]] ";
+
+/// (Re-)schedules the timers defined in the config.
+fn schedule_config_timers() {
+    timers::cancel(|name| name.starts_with(CONFIG_TIMER_PREFIX));
+    for timer in &get_config().timers {
+        match cmds::parse_nirius_cmd(&timer.command) {
+            Ok(cmd) => timers::schedule(
+                timer.every,
+                Some(timer.every),
+                &format!("{CONFIG_TIMER_PREFIX}{}", timer.command.join(" ")),
+                Arc::new(move || {
+                    cmds::exec_nirius_cmd(cmd.clone()).into_result()
+                }),
+            ),
+            Err(err) => tracing::error!("Invalid timer command: {err}"),
+        }
+    }
+}
+
+/// Reloads the config file and applies the changes.
+pub(crate) fn reload_config() -> Result<String, String> {
+    let msg = config::reload_config()?;
+    schedule_config_timers();
+    Ok(msg)
+}
+
+/// Switches to the given config profile and applies it.  If the config can't
+/// be reloaded, the previous profile stays active.
+pub(crate) fn switch_profile(name: &str) -> Result<String, String> {
+    let previous = config::switch_profile(Some(name.to_owned()));
+    if let Err(err) = reload_config() {
+        config::switch_profile(previous);
+        return Err(err);
+    }
+    Ok(format!("Switched to profile {name}."))
+}
+
+/// Reloads the config whenever the config file or a drop-in file is written.
+fn watch_config_file() {
+    let (Some(path), Some(drop_in_dir)) = (
+        config::get_config_file_path(),
+        config::get_drop_in_dir_path(),
+    ) else {
+        return;
+    };
+    let (Some(dir), Some(file_name)) = (path.parent(), path.file_name()) else {
+        return;
+    };
+    let mut inotify = match Inotify::init() {
+        Ok(inotify) => inotify,
+        Err(err) => {
+            tracing::error!("Could not initialize inotify: {err}");
+            return;
+        }
+    };
+    // Watch the directory because editors often replace the file with a new
+    // one instead of writing to it.
+    if let Err(err) = inotify
+        .watches()
+        .add(dir, WatchMask::CLOSE_WRITE | WatchMask::MOVED_TO)
+    {
+        tracing::warn!("Could not watch {}: {err}", dir.display());
+        return;
+    }
+    // The drop-in directory is only watched if it exists at startup.
+    let drop_in_wd = inotify
+        .watches()
+        .add(
+            &drop_in_dir,
+            WatchMask::CLOSE_WRITE | WatchMask::MOVED_TO | WatchMask::DELETE,
+        )
+        .ok();
+
+    let mut buffer = [0; 4096];
+    loop {
+        match inotify.read_events_blocking(&mut buffer) {
+            Ok(mut events) => {
+                if events.any(|e| {
+                    if Some(&e.wd) == drop_in_wd.as_ref() {
+                        e.name.is_some_and(|n| {
+                            std::path::Path::new(n)
+                                .extension()
+                                .is_some_and(|ext| ext == "toml")
+                        })
+                    } else {
+                        e.name == Some(file_name)
+                    }
+                }) {
+                    match reload_config() {
+                        Ok(msg) => tracing::info!("{msg}"),
+                        Err(err) => tracing::error!("{err}"),
                     }
                 }
             }
-            Ok(other) => {
-                let msg = format!(
-                    "Unexpected response for Request::EventStream: {other:?}"
-                );
-                log::error!("{msg}");
-                panic!("{msg}")
+            Err(err) => {
+                tracing::error!("Could not read inotify events: {err}");
+                return;
+            }
+        }
+    }
+}
+
+/// Seeds the state with the windows, workspaces and keyboard layouts niri
+/// currently has so that commands work right away, even before any event has
+/// been received.
+fn init_state() -> Result<String, String> {
+    let wins = match ipc::query_niri(Request::Windows)? {
+        Response::Windows(wins) => wins,
+        x => return Err(ipc::unexpected_reply(x).into()),
+    };
+    let workspaces = match ipc::query_niri(Request::Workspaces)? {
+        Response::Workspaces(workspaces) => workspaces,
+        x => return Err(ipc::unexpected_reply(x).into()),
+    };
+    let keyboard_layouts = match ipc::query_niri(Request::KeyboardLayouts)? {
+        Response::KeyboardLayouts(keyboard_layouts) => keyboard_layouts,
+        x => return Err(ipc::unexpected_reply(x).into()),
+    };
+
+    let msg = format!(
+        "Initialized state with {} windows and {} workspaces.",
+        wins.len(),
+        workspaces.len()
+    );
+    state::with_state(move |state| {
+        state.workspaces_changed(workspaces)?;
+        if let Some(id) = state.get_focused_workspace_id() {
+            state.workspace_focused(id);
+        }
+        state.keyboard_layouts_changed(keyboard_layouts.current_idx)?;
+        // Register the focused window last so that it's the most recently
+        // focused one.
+        let (focused, others): (Vec<_>, Vec<_>) =
+            wins.into_iter().partition(|w| w.is_focused);
+        for win in others.into_iter().chain(focused) {
+            apply_follow_mode_rules(state, &win);
+            state.register_window(win)?;
+        }
+        // Pick up marks, the scratchpad, etc. if the daemon has been stopped
+        // using `nirius daemon stop`.
+        if let Err(err) = state.restore() {
+            tracing::error!("Could not restore the saved state: {err}");
+        }
+        track_focus_time(state);
+        Ok(msg)
+    })
+}
+
+/// Reads events from niri's event stream and passes them on to
+/// [`process_events`].  Returns when niri closes the stream.
+async fn read_events(
+    tx: mpsc::UnboundedSender<niri_ipc::Event>,
+) -> Result<(), String> {
+    let socket_path = util::get_niri_socket_path()?;
+    let stream = tokio::net::UnixStream::connect(&socket_path)
+        .await
+        .map_err(|e| format!("Could not connect to {socket_path}: {e}"))?;
+    let (reader, mut writer) = stream.into_split();
+
+    let mut request = serde_json::to_string(&Request::EventStream)
+        .map_err(|e| e.to_string())?;
+    request.push('\n');
+    writer
+        .write_all(request.as_bytes())
+        .await
+        .map_err(|e| format!("Could not send Request::EventStream: {e}"))?;
+
+    let mut lines = BufReader::new(reader).lines();
+    let reply = lines
+        .next_line()
+        .await
+        .map_err(|e| e.to_string())?
+        .ok_or("No reply for Request::EventStream.")?;
+    match serde_json::from_str::<niri_ipc::Reply>(&reply) {
+        Ok(Ok(Response::Handled)) => (),
+        other => {
+            return Err(format!(
+                "Unexpected response for Request::EventStream: {other:?}"
+            ));
+        }
+    }
+
+    while let Some(line) = lines.next_line().await.map_err(|e| e.to_string())? {
+        match serde_json::from_str::<niri_ipc::Event>(&line) {
+            Ok(event) => {
+                if tx.send(event).is_err() {
+                    return Err("The event handler has stopped.".to_owned());
+                }
             }
+            Err(err) => tracing::error!("Could not parse event {line}: {err}"),
+        }
+    }
+    Ok(())
+}
+
+fn process_events(mut rx: mpsc::UnboundedReceiver<niri_ipc::Event>) {
+    #[cfg(feature = "scripting")]
+    let mut script = crate::scripting::ScriptEngine::load();
+    let mut seq: u64 = 0;
+    while let Some(event) = rx.blocking_recv() {
+        seq += 1;
+        // Everything logged while handling the event, e.g., by hooks, can be
+        // correlated using the span.
+        let span = tracing::info_span!("event", seq);
+        let _entered = span.enter();
+        let start = Instant::now();
+        tracing::debug!("Received event {event:?}");
+        let ev = event.clone();
+        let result =
+            undo::in_group(stats::event_name(&event).to_owned(), || {
+                state::with_state(move |state| {
+                    let result = handle_event(state, &ev);
+                    track_focus_time(state);
+                    result
+                })
+            });
+        history::record(history::Source::Event, &event, &result);
+        if matches!(
+            event,
+            niri_ipc::Event::WindowOpenedOrChanged { .. }
+                | niri_ipc::Event::WindowsChanged { .. }
+        ) {
+            state::notify_windows_changed();
+        }
+        let elapsed = start.elapsed();
+        stats::record_event(&event, elapsed);
+        match result {
+            Ok(msg) => tracing::info!(
+                ?elapsed,
+                "Handled event successfully: {event:?} => {msg}"
+            ),
             Err(e) => {
-                let msg = format!("Error when requesting EventStream: {e:?}");
-                log::error!("{msg}");
-                panic!("{msg}")
+                tracing::error!(?elapsed, "Error during event-handling: {e:?}")
             }
-        },
-        Err(e) => {
-            let msg = format!("Could not send Request::EventStream: {e:?}");
-            log::error!("{msg}");
-            panic!("{msg}")
+        }
+        #[cfg(feature = "scripting")]
+        if let Some(script) = &mut script {
+            script.handle_event(&event);
         }
     }
 }
 
-fn handle_event(event: &niri_ipc::Event) -> Result<String, String> {
+/// Records which app-id has focus if `track_focus_time` is enabled.
+fn track_focus_time(state: &State) {
+    let app_id = get_config()
+        .track_focus_time
+        .then(|| {
+            let win = state.all_windows.iter().find(|w| w.is_focused)?;
+            Some(win.app_id.clone().unwrap_or_default())
+        })
+        .flatten();
+    time_tracking::focus_changed(app_id);
+}
+
+fn handle_event(
+    state: &mut State,
+    event: &niri_ipc::Event,
+) -> Result<String, String> {
     match event {
         niri_ipc::Event::WorkspaceActivated { id, focused } if *focused => {
+            state.workspace_focused(*id);
+            if let Some(hook) = &get_config().hooks.on_workspace_focus
+                && let Some(ws) =
+                    state.all_workspaces.iter().find(|ws| ws.id == *id)
             {
-                let mut state =
-                    STATE.write().expect("Could not write() STATE.");
-                state.workspace_focused(*id);
+                hooks::run_hook(
+                    "on_workspace_focus",
+                    hook,
+                    hooks::workspace_env(ws),
+                );
             }
 
-            let state = STATE.read().expect("Could not read() STATE.");
             let mut str = String::new();
             if state.is_bottom_workspace_focused() {
-                str += &cmds::scratchpad_move()?;
+                str += &cmds::scratchpad_move(state)?;
             }
 
+            // Pinned windows only follow to workspaces on their own output.
+            // Every move is a request to niri on its own connection, so
+            // windows which are there already aren't moved unless they
+            // should get the focus.
+            let ws_output = state.get_workspace_output(*id);
+            let config = get_config();
             let mut i = 0;
-            for w in &state.follow_mode_win_ids {
+            for w in state.all_windows.iter().filter(|w| {
+                state.follow_mode_win_ids.contains(&w.id)
+                    && (w.workspace_id != Some(*id)
+                        || config.get_follow_mode_focus(w))
+                    && state
+                        .pinned_win_id_to_output
+                        .get(&w.id)
+                        .is_none_or(|o| Some(o.as_str()) == ws_output)
+                    && match config.get_follow_mode_scope(w) {
+                        FollowScope::All => true,
+                        FollowScope::OwnOutput => {
+                            state.get_window_output(w) == ws_output
+                        }
+                        FollowScope::Output(o) => Some(o.as_str()) == ws_output,
+                    }
+            }) {
                 cmds::move_window_to_workspace(
-                    *w,
+                    state,
+                    w.id,
                     WorkspaceReferenceArg::Id(*id),
-                    false,
+                    config.get_follow_mode_focus(w),
                 )?;
                 i += 1;
             }
@@ -142,85 +549,1216 @@ fn handle_event(event: &niri_ipc::Event) -> Result<String, String> {
             }
         }
         niri_ipc::Event::WindowOpenedOrChanged { window } => {
-            let mut state = STATE.write().expect("Could not write() STATE.");
             if !window.is_floating {
                 state.scratchpad_win_ids.retain(|w| *w != window.id)
             }
-            state.register_window(window.clone())
+            let is_new = !state.all_windows.iter().any(|w| w.id == window.id);
+            if is_new {
+                apply_follow_mode_rules(state, window);
+            }
+            let became_urgent =
+                window.is_urgent && !state.urgent_win_ids.contains(&window.id);
+            let mut msg = state.register_window(window.clone())?;
+            if became_urgent {
+                handle_urgent_window(state, window.id);
+            }
+            if is_new {
+                if let Some(hook) = &get_config().hooks.on_window_open {
+                    hooks::run_hook(
+                        "on_window_open",
+                        hook,
+                        hooks::window_env(state, window),
+                    );
+                }
+                msg += &apply_window_rules(state, window);
+                let placed = apply_pending_placement(state, window)?;
+                if placed.is_empty() {
+                    msg += &apply_workspace_rules(state, window)?;
+                } else {
+                    msg += &placed;
+                }
+                msg += &swallow_parent_terminal(state, window)?;
+                for watcher in state
+                    .watchers
+                    .iter()
+                    .filter(|w| cmds::window_matches(window, &w.match_opts))
+                {
+                    hooks::run_hook_args(
+                        "watch",
+                        &watcher.command,
+                        hooks::window_env(state, window),
+                    );
+                }
+            }
+            if let Some(output) = state.pinned_win_id_to_output.get(&window.id)
+                && state.get_window_output(window).is_some_and(|o| o != output)
+            {
+                cmds::move_window_to_output(state, window.id, output.clone())?;
+                msg += &format!(" Moved back to pinned output {output}.");
+            }
+            msg += &auto_name_workspaces(state)?;
+            Ok(msg)
+        }
+        niri_ipc::Event::WindowsChanged { windows } => {
+            // Sent when the event stream starts and contains all windows.
+            let stale_ids: Vec<u64> = state
+                .all_windows
+                .iter()
+                .filter(|w| !windows.iter().any(|win| win.id == w.id))
+                .map(|w| w.id)
+                .collect();
+            for id in &stale_ids {
+                state.remove_window(id)?;
+            }
+            for win in windows {
+                if !state.all_windows.iter().any(|w| w.id == win.id) {
+                    apply_follow_mode_rules(state, win);
+                }
+                state.register_window(win.clone())?;
+            }
+            Ok(format!(
+                "Synced {} windows, removed {} stale ones.",
+                windows.len(),
+                stale_ids.len()
+            ))
         }
         niri_ipc::Event::WindowClosed { id } => {
-            let mut state = STATE.write().expect("Could not write() STATE.");
-            state.remove_window(id)
+            if let Some(win) = state.all_windows.iter().find(|w| w.id == *id) {
+                if let Some(hook) = &get_config().hooks.on_window_close {
+                    hooks::run_hook(
+                        "on_window_close",
+                        hook,
+                        hooks::window_env(state, win),
+                    );
+                }
+                for hook in get_config()
+                    .close_hooks
+                    .iter()
+                    .filter(|h| cmds::window_matches(win, &h.match_opts))
+                {
+                    hooks::run_hook(
+                        "close_hook",
+                        &hook.command,
+                        hooks::window_env(state, win),
+                    );
+                }
+            }
+            let mut msg = restore_swallowed_terminal(state, *id)?;
+            msg += &state.remove_window(id)?;
+            msg += &auto_name_workspaces(state)?;
+            Ok(msg)
         }
         niri_ipc::Event::WindowFocusChanged { id } => {
-            let mut state = STATE.write().expect("Could not write() STATE.");
-            state.window_focus_changed(*id)
+            if let Some(delay) = get_config().scratchpad_auto_hide
+                && let Some(prev_id) = state.get_focused_win_id()
+                && state.scratchpad_win_ids.contains(&prev_id)
+                && Some(prev_id) != *id
+            {
+                timers::schedule(
+                    delay,
+                    None,
+                    "scratchpad auto-hide",
+                    Arc::new(move || {
+                        state::with_state(move |state| {
+                            scratchpad_auto_hide(state, prev_id)
+                                .map_err(String::from)
+                        })
+                    }),
+                );
+            }
+            let msg = state.window_focus_changed(*id)?;
+            // Windows focused for the first time get the current layout.
+            if let Some(id) = id
+                && let Some(idx) = state.keyboard_layout_idx
+            {
+                state
+                    .win_id_to_keyboard_layout_idx
+                    .entry(*id)
+                    .or_insert(idx);
+            }
+            if get_config().per_window_keyboard_layout
+                && let Some(id) = id
+                && let Some(idx) =
+                    state.win_id_to_keyboard_layout_idx.get(id).copied()
+                && state.keyboard_layout_idx != Some(idx)
+            {
+                cmds::exec_niri_action(Action::SwitchLayout {
+                    layout: LayoutSwitchTarget::Index(idx),
+                })?;
+                Ok(format!("{msg} Switched to keyboard layout {idx}."))
+            } else {
+                Ok(msg)
+            }
+        }
+        niri_ipc::Event::KeyboardLayoutsChanged { keyboard_layouts } => {
+            state.keyboard_layouts_changed(keyboard_layouts.current_idx)
+        }
+        niri_ipc::Event::KeyboardLayoutSwitched { idx } => {
+            state.keyboard_layout_switched(*idx)
         }
         niri_ipc::Event::WorkspacesChanged { workspaces } => {
-            let mut state = STATE.write().expect("Could not write() STATE.");
-            state.workspaces_changed(workspaces.clone())
+            let outputs: Vec<String> =
+                state.get_outputs().into_iter().map(str::to_owned).collect();
+            let msg = state.workspaces_changed(workspaces.clone())?;
+            if state.get_outputs() == outputs {
+                Ok(msg)
+            } else {
+                run_output_hooks(&outputs, &state.get_outputs());
+                Ok(format!("{msg}{}", apply_output_profile(state)?))
+            }
+        }
+        niri_ipc::Event::WindowUrgencyChanged { id, urgent } => {
+            let Some(win) = state.all_windows.iter_mut().find(|w| w.id == *id)
+            else {
+                return Ok(format!("Unknown window {id}."));
+            };
+            win.is_urgent = *urgent;
+            // niri might repeat the urgency, but it's announced only once.
+            if state.set_urgent(*id, *urgent) {
+                handle_urgent_window(state, *id);
+            }
+            Ok(format!("Updated urgency of window {id}."))
+        }
+        niri_ipc::Event::WindowLayoutsChanged { changes } => {
+            for (id, layout) in changes {
+                let Some(win) =
+                    state.all_windows.iter_mut().find(|w| w.id == *id)
+                else {
+                    continue;
+                };
+                win.layout = layout.clone();
+                let win = win.clone();
+                state.remember_floating_geometry(&win);
+            }
+            Ok(format!("Updated layouts of {} windows.", changes.len()))
         }
         _other => Ok("Nothing to do.".to_owned()),
     }
 }
 
-fn serve_client_requests() {
+/// Hides the terminal which launched the given new window (if any) by moving
+/// it to the bottom workspace of its output.
+fn swallow_parent_terminal(
+    state: &mut State,
+    win: &niri_ipc::Window,
+) -> Result<String, String> {
+    if !get_config().swallow.enabled {
+        return Ok(String::new());
+    }
+    let rx = Regex::new(&get_config().swallow.terminal_app_id)
+        .map_err(|e| format!("Invalid swallow.terminal_app_id: {e}"))?;
+    let is_terminal = |w: &niri_ipc::Window| {
+        w.app_id.as_ref().is_some_and(|a| rx.is_match(a))
+    };
+    if is_terminal(win) {
+        return Ok(String::new());
+    }
+
+    let mut opt_pid = win.pid;
+    let mut parent = None;
+    // Process trees are shallow, so this limit is just a safeguard.
+    for _ in 0..32 {
+        let Some(pid) = opt_pid.and_then(util::get_parent_pid) else {
+            break;
+        };
+        parent = state
+            .all_windows
+            .iter()
+            .find(|w| w.id != win.id && w.pid == Some(pid) && is_terminal(w));
+        if parent.is_some() || pid <= 1 {
+            break;
+        }
+        opt_pid = Some(pid);
+    }
+    let Some(parent) = parent else {
+        return Ok(String::new());
+    };
+    let id = parent.id;
+    if state.swallowed_windows.iter().any(|s| s.id == id) {
+        return Ok(String::new());
+    }
+    let workspace_id = parent.workspace_id.ok_or("Window has no workspace.")?;
+    let output = state
+        .get_workspace_output(workspace_id)
+        .ok_or("Workspace without output.")?;
+    let (bottom_ws_id, _) = state
+        .get_bottom_workspace_id_and_idx_of_output(output)
+        .ok_or("No bottom workspace.")?;
+    cmds::move_window_to_workspace(
+        state,
+        id,
+        WorkspaceReferenceArg::Id(bottom_ws_id),
+        false,
+    )?;
+    state.swallowed_windows.push(state::SwallowedWindow {
+        id,
+        child_id: win.id,
+        workspace_id,
+    });
+    Ok(format!(" Swallowed terminal {id}."))
+}
+
+/// Brings back the terminal swallowed by the given closed window (if any) to
+/// the workspace where the closed window has been.
+fn restore_swallowed_terminal(
+    state: &mut State,
+    closed_win_id: u64,
+) -> Result<String, String> {
+    let Some(idx) = state
+        .swallowed_windows
+        .iter()
+        .position(|s| s.child_id == closed_win_id)
+    else {
+        return Ok(String::new());
+    };
+    let swallowed = state.swallowed_windows.remove(idx);
+    let workspace_id = state
+        .all_windows
+        .iter()
+        .find(|w| w.id == closed_win_id)
+        .and_then(|w| w.workspace_id)
+        .filter(|ws_id| state.all_workspaces.iter().any(|ws| ws.id == *ws_id))
+        .unwrap_or(swallowed.workspace_id);
+    cmds::move_window_to_workspace(
+        state,
+        swallowed.id,
+        WorkspaceReferenceArg::Id(workspace_id),
+        true,
+    )?;
+    Ok(format!("Restored swallowed terminal {}. ", swallowed.id))
+}
+
+/// Sends notifications and runs hooks for the given urgent window according
+/// to the urgency rules.
+fn handle_urgent_window(state: &State, win_id: u64) {
+    let Some(win) = state.all_windows.iter().find(|w| w.id == win_id) else {
+        return;
+    };
+    for rule in get_config()
+        .urgency_rules
+        .iter()
+        .filter(|r| cmds::window_matches(win, &r.match_opts))
+    {
+        if rule.notify {
+            hooks::notify(
+                win.app_id.as_deref().unwrap_or("Window"),
+                &format!(
+                    "{} needs attention.",
+                    win.title.as_deref().unwrap_or("A window")
+                ),
+                move || {
+                    if let Err(err) =
+                        cmds::exec_niri_action(Action::FocusWindow {
+                            id: win_id,
+                        })
+                    {
+                        tracing::error!("Could not focus urgent window: {err}");
+                    }
+                },
+            );
+        }
+        if let Some(command) = &rule.command {
+            hooks::run_hook(
+                "urgency_rule",
+                command,
+                hooks::window_env(state, win),
+            );
+        }
+    }
+}
+
+/// Moves the scratchpad windows back to the scratchpad if the given one is
+/// still shown but unfocused.
+fn scratchpad_auto_hide(
+    state: &State,
+    win_id: u64,
+) -> Result<String, NiriusError> {
+    let Some(win) = state.all_windows.iter().find(|w| w.id == win_id) else {
+        return Ok("Window is gone.".to_owned());
+    };
+    let is_hidden = state.get_window_output(win).is_some_and(|o| {
+        state
+            .get_bottom_workspace_id_and_idx_of_output(o)
+            .is_some_and(|(ws_id, _)| win.workspace_id == Some(ws_id))
+    });
+    if win.is_focused
+        || is_hidden
+        || !state.scratchpad_win_ids.contains(&win_id)
+    {
+        return Ok("Nothing to hide.".to_owned());
+    }
+    cmds::scratchpad_move(state)
+}
+
+/// Puts the given window into follow-mode if it matches one of the configured
+/// follow-mode rules.
+fn apply_follow_mode_rules(state: &mut State, win: &niri_ipc::Window) {
+    if !state.follow_mode_win_ids.contains(&win.id)
+        && get_config().get_follow_mode_rule(win).is_some()
+    {
+        tracing::info!(
+            "Enabling follow-mode for window {} due to rule.",
+            win.id
+        );
+        state.follow_mode_win_ids.push(win.id);
+    }
+}
+
+/// Moves the given new window to the workspace of the first pending placement
+/// it matches.
+fn apply_pending_placement(
+    state: &mut State,
+    win: &niri_ipc::Window,
+) -> Result<String, String> {
+    let Some(idx) = state
+        .pending_placements
+        .iter()
+        .position(|p| cmds::window_matches(win, &p.match_opts))
+    else {
+        return Ok(String::new());
+    };
+    let placement = state.pending_placements.remove(idx);
+    if placement.floating && !win.is_floating {
+        cmds::exec_window_action(
+            state,
+            Action::MoveWindowToFloating { id: Some(win.id) },
+        )?;
+    }
+    cmds::move_window_to_workspace(
+        state,
+        win.id,
+        WorkspaceReferenceArg::Name(placement.workspace.clone()),
+        false,
+    )?;
+    Ok(format!(" Placed on workspace {}.", placement.workspace))
+}
+
+/// Applies the actions of all window rules matching the given new window.
+/// Actions of several matching rules are applied only once.
+fn apply_window_rules(state: &mut State, win: &niri_ipc::Window) -> String {
+    let config = get_config();
+    let mut str = String::new();
+    let mut applied = vec![];
+    for rule in config
+        .rules
+        .iter()
+        .filter(|r| cmds::window_matches(win, &r.match_opts))
+    {
+        // A failing action mustn't keep the other ones from being applied.
+        for action in &rule.actions {
+            if applied.contains(&action) {
+                continue;
+            }
+            applied.push(action);
+            match apply_rule_action(state, win, action) {
+                Ok(()) => str += &format!(" Applied {action:?}."),
+                Err(err) => tracing::error!(
+                    "Could not apply {action:?} to window {}: {err}",
+                    win.id
+                ),
+            }
+        }
+    }
+    str
+}
+
+fn apply_rule_action(
+    state: &mut State,
+    win: &niri_ipc::Window,
+    action: &RuleAction,
+) -> Result<(), NiriusError> {
+    let id = win.id;
+    match action {
+        RuleAction::Float => cmds::exec_window_action(
+            state,
+            Action::MoveWindowToFloating { id: Some(id) },
+        ),
+        RuleAction::Tile => {
+            state.scratchpad_win_ids.retain(|w| *w != id);
+            cmds::exec_window_action(
+                state,
+                Action::MoveWindowToTiling { id: Some(id) },
+            )
+        }
+        RuleAction::Workspace(name) => {
+            cmds::ensure_named_workspace(state, name)?;
+            cmds::move_window_to_workspace(
+                state,
+                id,
+                WorkspaceReferenceArg::Name(name.clone()),
+                false,
+            )
+            .map(|_| ())
+        }
+        RuleAction::Mark(mark) => {
+            let ids = state.mark_to_win_ids.entry(mark.clone()).or_default();
+            if !ids.contains(&id) {
+                ids.push(id);
+            }
+            Ok(())
+        }
+        RuleAction::Scratchpad => {
+            if !state.scratchpad_win_ids.contains(&id) {
+                state.scratchpad_win_ids.push(id);
+            }
+            cmds::exec_window_action(
+                state,
+                Action::MoveWindowToFloating { id: Some(id) },
+            )?;
+            let output = state
+                .get_window_output(win)
+                .ok_or("Window is on no output.")?;
+            let (ws_id, _) = state
+                .get_bottom_workspace_id_and_idx_of_output(output)
+                .ok_or("No bottom workspace.")?;
+            cmds::move_window_to_workspace(
+                state,
+                id,
+                WorkspaceReferenceArg::Id(ws_id),
+                false,
+            )
+            .map(|_| ())
+        }
+        RuleAction::Follow => {
+            if !state.follow_mode_win_ids.contains(&id) {
+                state.follow_mode_win_ids.push(id);
+            }
+            Ok(())
+        }
+        RuleAction::Resize { width, height } => {
+            cmds::exec_niri_action(Action::SetWindowWidth {
+                id: Some(id),
+                change: SizeChange::SetFixed(*width),
+            })?;
+            cmds::exec_niri_action(Action::SetWindowHeight {
+                id: Some(id),
+                change: SizeChange::SetFixed(*height),
+            })
+        }
+        RuleAction::Output(output) => {
+            let ws = state
+                .get_active_workspace_of_output(output)
+                .ok_or_else(|| format!("No active workspace on {output}."))?;
+            if win.workspace_id == Some(ws.id) {
+                return Ok(());
+            }
+            cmds::move_window_to_workspace(
+                state,
+                id,
+                WorkspaceReferenceArg::Id(ws.id),
+                false,
+            )
+            .map(|_| ())
+        }
+        RuleAction::Fullscreen => {
+            // The action toggles fullscreen, and windows may open
+            // fullscreen already.
+            if cmds::is_fullscreen(state, win)? {
+                return Ok(());
+            }
+            cmds::exec_niri_action(Action::FullscreenWindow { id: Some(id) })
+        }
+    }
+}
+
+/// Moves the given new window to the named workspace of the first workspace
+/// rule it matches, creating that workspace if needed.
+fn apply_workspace_rules(
+    state: &State,
+    win: &niri_ipc::Window,
+) -> Result<String, String> {
+    let config = get_config();
+    let Some(rule) = config
+        .workspace_rules
+        .iter()
+        .find(|r| cmds::window_matches(win, &r.match_opts))
+    else {
+        return Ok(String::new());
+    };
+    if state.all_workspaces.iter().any(|ws| {
+        Some(ws.id) == win.workspace_id
+            && ws.name.as_ref() == Some(&rule.workspace)
+    }) {
+        // Already there.
+        return Ok(String::new());
+    }
+    cmds::ensure_named_workspace(state, &rule.workspace)?;
+    cmds::move_window_to_workspace(
+        state,
+        win.id,
+        WorkspaceReferenceArg::Name(rule.workspace.clone()),
+        false,
+    )?;
+    Ok(format!(" Assigned to workspace {}.", rule.workspace))
+}
+
+/// Runs the `on_output_connect` and `on_output_disconnect` hooks for the
+/// outputs which have appeared or disappeared.
+fn run_output_hooks(before: &[String], after: &[&str]) {
+    let config = get_config();
+    if let Some(hook) = &config.hooks.on_output_connect {
+        for output in after.iter().filter(|o| !before.iter().any(|b| b == *o)) {
+            let env = vec![("NIRIUS_OUTPUT", output.to_string())];
+            hooks::run_hook("on_output_connect", hook, env);
+        }
+    }
+    if let Some(hook) = &config.hooks.on_output_disconnect {
+        for output in before.iter().filter(|b| !after.contains(&b.as_str())) {
+            let env = vec![("NIRIUS_OUTPUT", output.clone())];
+            hooks::run_hook("on_output_disconnect", hook, env);
+        }
+    }
+}
+
+/// Moves the workspaces and windows of the output profile matching the
+/// connected outputs to the outputs where they belong.
+fn apply_output_profile(state: &State) -> Result<String, String> {
+    let config = get_config();
+    let outputs = state.get_outputs();
+    let Some(profile) = config.get_output_profile(&outputs) else {
+        return Ok(String::new());
+    };
+    let target_output = |name: Option<&String>| {
+        name.and_then(|n| profile.workspaces.get(n))
+            .map(String::as_str)
+            .filter(|o| outputs.contains(o))
+    };
+
+    let mut moved_workspaces = 0;
+    for ws in &state.all_workspaces {
+        if let Some(output) = target_output(ws.name.as_ref())
+            && ws.output.as_deref() != Some(output)
+        {
+            cmds::exec_niri_action(Action::MoveWorkspaceToMonitor {
+                output: output.to_owned(),
+                reference: Some(WorkspaceReferenceArg::Id(ws.id)),
+            })?;
+            moved_workspaces += 1;
+        }
+    }
+
+    // The state doesn't know about the workspace moves above yet.
+    let ws_output = |ws_id: u64| {
+        let ws = state.all_workspaces.iter().find(|ws| ws.id == ws_id)?;
+        target_output(ws.name.as_ref()).or(ws.output.as_deref())
+    };
+    let mut moved_windows = 0;
+    for win in &state.all_windows {
+        let Some(app) = profile
+            .apps
+            .iter()
+            .find(|a| cmds::window_matches(win, &a.match_opts))
+        else {
+            continue;
+        };
+        if win.workspace_id.and_then(ws_output) == Some(app.output.as_str()) {
+            continue;
+        }
+        let Some(ws) = state.get_active_workspace_of_output(&app.output) else {
+            continue;
+        };
+        cmds::move_window_to_workspace(
+            state,
+            win.id,
+            WorkspaceReferenceArg::Id(ws.id),
+            false,
+        )?;
+        moved_windows += 1;
+    }
+
+    // Moving workspaces also moves the focus.
+    if moved_workspaces > 0
+        && let Some(id) = state.get_focused_workspace_id()
+    {
+        cmds::focus_workspace(WorkspaceReferenceArg::Id(id))?;
+    }
+    Ok(format!(
+        " Applied output profile {}: moved {moved_workspaces} workspaces and \
+         {moved_windows} windows.",
+        profile.name
+    ))
+}
+
+/// Names all workspaces which are unnamed or have been named by nirius after
+/// the app having the most windows on them, if enabled in the config.
+fn auto_name_workspaces(state: &mut State) -> Result<String, String> {
+    let config = &get_config().auto_name_workspaces;
+    if !config.enabled {
+        return Ok(String::new());
+    }
+
+    let mut str = String::new();
+    let workspaces = state.all_workspaces.clone();
+    let mut taken_names: Vec<String> =
+        workspaces.iter().filter_map(|ws| ws.name.clone()).collect();
+    for ws in &workspaces {
+        let ours = state.auto_named_ws_id_to_name.get(&ws.id).cloned();
+        if ws.name.is_some() && ws.name != ours {
+            // The user named it, so leave it alone.
+            state.auto_named_ws_id_to_name.remove(&ws.id);
+            continue;
+        }
+
+        // Count windows per app-id.  On ties, the most recently focused app
+        // wins because all_windows is ordered by focus and max_by_key() picks
+        // the last maximum.
+        let mut counts: Vec<(&str, usize)> = vec![];
+        for app_id in state
+            .all_windows
+            .iter()
+            .filter(|w| w.workspace_id == Some(ws.id))
+            .filter_map(|w| w.app_id.as_deref())
+        {
+            let count = match counts.iter().position(|(a, _)| *a == app_id) {
+                Some(idx) => counts.remove(idx).1 + 1,
+                None => 1,
+            };
+            counts.push((app_id, count));
+        }
+        let dominant =
+            counts
+                .iter()
+                .max_by_key(|(_, count)| *count)
+                .map(|(app_id, _)| {
+                    config
+                        .labels
+                        .get(*app_id)
+                        .cloned()
+                        .unwrap_or(app_id.to_string())
+                });
+
+        if dominant.is_some() && dominant == ours {
+            continue;
+        }
+        taken_names.retain(|n| Some(n) != ours.as_ref());
+
+        match dominant {
+            Some(label) => {
+                // Workspace names have to be unique.
+                let mut name = label.clone();
+                let mut i = 2;
+                while taken_names.contains(&name) {
+                    name = format!("{label} {i}");
+                    i += 1;
+                }
+                cmds::exec_niri_action(Action::SetWorkspaceName {
+                    name: name.clone(),
+                    workspace: Some(WorkspaceReferenceArg::Id(ws.id)),
+                })?;
+                str += &format!(" Named workspace {} {name}.", ws.id);
+                taken_names.push(name.clone());
+                state.auto_named_ws_id_to_name.insert(ws.id, name);
+            }
+            None if ours.is_some() => {
+                cmds::exec_niri_action(Action::UnsetWorkspaceName {
+                    reference: Some(WorkspaceReferenceArg::Id(ws.id)),
+                })?;
+                str += &format!(" Unnamed workspace {}.", ws.id);
+                state.auto_named_ws_id_to_name.remove(&ws.id);
+            }
+            None => (),
+        }
+    }
+    Ok(str)
+}
+
+/// Returns the listening socket passed by systemd if niriusd has been
+/// socket-activated.
+fn take_activated_socket() -> Option<StdUnixListener> {
+    let fd = match sd_notify::listen_fds() {
+        Ok(mut fds) => fds.next()?,
+        Err(err) => {
+            tracing::error!("Could not get sockets passed by systemd: {err}");
+            return None;
+        }
+    };
+    tracing::debug!("niriusd has been socket-activated.");
+    // SAFETY: systemd hands over ownership of the listening socket to us and
+    // listen_fds() ensures that it's meant for this process.
+    Some(unsafe { StdUnixListener::from_raw_fd(fd) })
+}
+
+/// Binds the nirius socket, deleting a stale one from a previous run.  If
+/// another daemon still listens on it, it's terminated if `replace` is true.
+async fn bind_socket(replace: bool) -> Result<UnixListener, String> {
     let socket_path = util::get_nirius_socket_path();
+    // Daemons starting at the same time must not delete each other's socket
+    // between probing and binding it.
+    let _lock = lock_file(&lock_file_path(&socket_path))?;
 
-    match std::fs::exists(&socket_path) {
-        Ok(true) => match std::fs::remove_file(&socket_path) {
-            Ok(()) => log::debug!(
+    match UnixStream::connect(&socket_path).await {
+        Ok(stream) => {
+            if !replace {
+                return Err(format!(
+                    "Another niriusd is already listening on {socket_path}.  \
+                     Use --replace to replace it."
+                ));
+            }
+            terminate_daemon(&stream).await?;
+            remove_socket(&socket_path)?;
+        }
+        // Nobody listens on the socket anymore.
+        Err(err) if err.kind() == std::io::ErrorKind::ConnectionRefused => {
+            remove_socket(&socket_path)?;
+            tracing::debug!(
                 "Deleted stale socket {socket_path} from previous run."
+            );
+        }
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => (),
+        Err(err) => {
+            return Err(format!("Could not probe socket {socket_path}: {err}"));
+        }
+    }
+
+    tracing::debug!("niriusd starts listening on {socket_path}.");
+    // Only the user may connect to the socket, so create it with mode 0600
+    // right away.  The umask is process-wide but no other threads create
+    // files yet.
+    // SAFETY: umask() has no memory safety preconditions.
+    let old_umask = unsafe { libc::umask(0o177) };
+    let listener = UnixListener::bind(socket_path);
+    // SAFETY: See above.
+    unsafe { libc::umask(old_umask) };
+    listener.map_err(|e| format!("Could not bind socket: {e}"))
+}
+
+fn remove_socket(socket_path: &str) -> Result<(), String> {
+    match std::fs::remove_file(socket_path) {
+        Ok(()) => Ok(()),
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(()),
+        Err(err) => Err(format!(
+            "Could not delete stale socket {socket_path}: {err}"
+        )),
+    }
+}
+
+fn lock_file_path(socket_path: &str) -> String {
+    format!("{socket_path}.lock")
+}
+
+/// Creates the file at `path` if needed and locks it exclusively.  If
+/// another niriusd holds the lock because it's starting right now, an error
+/// is returned right away.  The lock is released when the returned file is
+/// dropped.
+fn lock_file(path: &str) -> Result<std::fs::File, String> {
+    let file = std::fs::OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(path)
+        .map_err(|e| format!("Could not open {path}: {e}"))?;
+    // SAFETY: flock() has no memory safety preconditions and the file
+    // descriptor is valid as long as `file` lives.
+    if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) }
+        != 0
+    {
+        let err = std::io::Error::last_os_error();
+        if err.kind() == std::io::ErrorKind::WouldBlock {
+            return Err(format!(
+                "Another niriusd is already starting ({path} is locked)."
+            ));
+        }
+        return Err(format!("Could not lock {path}: {err}"));
+    }
+    Ok(file)
+}
+
+/// Terminates the daemon at the other end of `stream` and waits until it
+/// has exited.
+async fn terminate_daemon(stream: &UnixStream) -> Result<(), String> {
+    let pid = stream
+        .peer_cred()
+        .ok()
+        .and_then(|cred| cred.pid())
+        .ok_or("Could not determine the pid of the running niriusd.")?;
+    tracing::info!("Replacing the running niriusd with pid {pid}.");
+    // SAFETY: kill() has no memory safety preconditions.
+    if unsafe { libc::kill(pid, libc::SIGTERM) } != 0 {
+        return Err(format!(
+            "Could not terminate niriusd with pid {pid}: {}",
+            std::io::Error::last_os_error()
+        ));
+    }
+    for _ in 0..50 {
+        // The process is gone or a zombie waiting to be reaped by its parent.
+        let exited = std::fs::read_to_string(format!("/proc/{pid}/stat"))
+            .map_or(true, |stat| {
+                stat.rsplit_once(") ")
+                    .is_some_and(|(_, rest)| rest.starts_with('Z'))
+            });
+        if exited {
+            return Ok(());
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+    Err(format!("niriusd with pid {pid} didn't terminate."))
+}
+
+/// Serves clients until [`stop_daemon`] is called.  Then the socket at
+/// `socket_path` and its lock file are deleted.
+async fn serve_client_requests(
+    listener: UnixListener,
+    socket_path: Option<String>,
+) {
+    // The state has been initialized from niri and clients can connect now.
+    notify_ready();
+
+    let mut clients = JoinSet::new();
+    loop {
+        tokio::select! {
+            accepted = listener.accept() => match accepted {
+                Ok((stream, _addr)) => {
+                    // Every client gets its own task so that slow or stuck
+                    // clients and long-running commands such as wait-for
+                    // don't block others.
+                    clients.spawn(handle_client_request(stream));
+                }
+                Err(err) => {
+                    tracing::error!("Error handling client request: {err}");
+                }
+            },
+            Some(_) = clients.join_next() => (),
+            () = SHUTDOWN.notified() => break,
+        }
+    }
+
+    drop(listener);
+    STOP_SUBSCRIPTIONS.notify_waiters();
+    if let Some(socket_path) = socket_path {
+        if let Err(err) = std::fs::remove_file(&socket_path) {
+            tracing::error!("Could not delete socket {socket_path}: {err}");
+        }
+        // Only delete the lock file if no starting niriusd holds it.
+        let lock_path = lock_file_path(&socket_path);
+        if let Ok(_lock) = lock_file(&lock_path)
+            && let Err(err) = std::fs::remove_file(&lock_path)
+        {
+            tracing::error!("Could not delete {lock_path}: {err}");
+        }
+    }
+    // Let the clients receive their results, e.g., the one which stopped the
+    // daemon.  Clients waiting for windows are cut off.
+    let _ = timeout(CLIENT_IO_TIMEOUT, async {
+        while clients.join_next().await.is_some() {}
+    })
+    .await;
+}
+
+/// Reads the command from the request envelope.  Requests of clients speaking
+/// another protocol version are refused with an error telling the user to
+/// restart niriusd.  Also returns the format the response should be sent in
+/// which is the one of the request, or JSON if it can't be read.
+fn read_request(
+    buf: &[u8],
+) -> (WireFormat, Result<cmds::NiriusCmd, NiriusError>) {
+    match protocol::read_message(&mut &buf[..]) {
+        Ok(Some((format, msg))) => (format, decode_request(format, &msg)),
+        Ok(None) => {
+            (WireFormat::Json, Err(invalid_request("Empty.".to_owned())))
+        }
+        Err(err) => (WireFormat::Json, Err(invalid_request(err.to_string()))),
+    }
+}
+
+fn invalid_request(err: String) -> NiriusError {
+    tracing::error!("Could not read command from client: {err}");
+    NiriusError::new(
+        ErrorCode::InvalidRequest,
+        format!("Invalid request: {err}"),
+    )
+}
+
+fn decode_request(
+    format: WireFormat,
+    msg: &[u8],
+) -> Result<cmds::NiriusCmd, NiriusError> {
+    let req: protocol::Request<IgnoredAny> =
+        format.decode(msg).map_err(invalid_request)?;
+    if req.version != protocol::PROTOCOL_VERSION {
+        tracing::warn!(
+            "Refusing request of client with protocol version {}.",
+            req.version
+        );
+        return Err(NiriusError::new(
+            ErrorCode::VersionMismatch,
+            protocol::version_mismatch_message(
+                req.version,
+                protocol::PROTOCOL_VERSION,
             ),
-            Err(e) => {
-                panic!("Could not delete stale socket {socket_path}.\n{e:?}");
+        ));
+    }
+    let req: protocol::Request<cmds::NiriusCmd> =
+        format.decode(msg).map_err(invalid_request)?;
+    Ok(req.cmd)
+}
+
+/// Executes the command of a client.  If the client connected via `client`
+/// goes away before the command has finished, waiting commands are
+/// cancelled and `None` is returned.  List commands send parts of their
+/// response to `parts` if given, see [`cmds::exec_nirius_cmd_cancellable`].
+pub(crate) async fn exec_client_cmd(
+    cmd: cmds::NiriusCmd,
+    client: Option<&UnixStream>,
+    parts: Option<cmds::Parts>,
+) -> Option<NiriusResponse> {
+    let span = tracing::Span::current();
+    span.record("cmd", field::debug(&cmd));
+    tracing::debug!("Received command.");
+    let start = Instant::now();
+    let record = (!cmd.is_query()).then(|| cmd.clone());
+    let cancelled = Arc::new(AtomicBool::new(false));
+    // Commands query niri and wait for the state actor synchronously.
+    let mut job = tokio::task::spawn_blocking({
+        let cancelled = cancelled.clone();
+        move || {
+            span.in_scope(|| {
+                cmds::exec_nirius_cmd_cancellable(cmd, &cancelled, parts)
+            })
+        }
+    });
+    let joined = match client {
+        Some(client) => tokio::select! {
+            joined = &mut job => joined,
+            () = client_hung_up(client) => {
+                tracing::debug!("Client has gone away, cancelling command.");
+                cancelled.store(true, Ordering::Relaxed);
+                state::notify_windows_changed();
+                let _ = job.await;
+                return None;
             }
         },
+        None => job.await,
+    };
+    let result = match joined {
+        Ok(result) => result,
+        Err(err) => NiriusResponse::failed(format!("Command failed: {err}")),
+    };
+    tracing::debug!(
+        elapsed = ?start.elapsed(),
+        "Executed command, returning result {result:?}"
+    );
+    if let Some(cmd) = record {
+        history::record(
+            history::Source::Command,
+            &cmd,
+            &result.clone().into_result(),
+        );
+    }
+    Some(result)
+}
+
+/// Returns when the client has closed its connection.  Clients shut down
+/// their writing half after sending the command, so that's only noticeable
+/// as a hang-up of the whole connection, i.e., the writing half being
+/// closed, too.
+async fn client_hung_up(client: &UnixStream) {
+    // Waiting on a duplicate of the connection's file descriptor doesn't
+    // touch the readiness of `client`, so sending the response isn't held
+    // up by the readiness forgotten below.
+    let fd = match client.as_fd().try_clone_to_owned() {
+        Ok(fd) => AsyncFd::with_interest(fd, Interest::WRITABLE),
+        Err(err) => Err(err),
+    };
+    let fd = match fd {
+        Ok(fd) => fd,
         Err(err) => {
-            panic!("Error when trying to access {socket_path}.\n{err:?}")
+            tracing::error!("Cannot watch client for hang-ups: {err}");
+            return std::future::pending().await;
         }
-        _ => (),
     };
+    loop {
+        match fd.writable().await {
+            Ok(mut guard) if !guard.ready().is_write_closed() => {
+                // Forget the readiness so that the next writable() waits
+                // for the connection's state to change.
+                guard.clear_ready();
+            }
+            _ => return,
+        }
+    }
+}
 
-    log::debug!("niriusd starts listening on {socket_path}.");
+/// How long the daemon waits for a client to send its command or to receive
+/// the result.
+const CLIENT_IO_TIMEOUT: Duration = Duration::from_secs(5);
 
-    match UnixListener::bind(socket_path) {
-        Ok(listener) => {
-            for stream in listener.incoming() {
-                match stream {
-                    Ok(stream) => {
-                        handle_client_request(stream);
-                    }
-                    Err(err) => {
-                        log::error!("Error handling client request: {err}");
-                    }
-                }
-            }
+/// The id of the next client request, used for correlating log messages.
+static NEXT_REQUEST_ID: AtomicU64 = AtomicU64::new(1);
+
+async fn handle_client_request(stream: UnixStream) {
+    let id = NEXT_REQUEST_ID.fetch_add(1, Ordering::Relaxed);
+    let span = tracing::info_span!("request", id, cmd = field::Empty);
+    async {
+        if is_peer_allowed(&stream) {
+            serve_client(stream).await;
+        }
+    }
+    .instrument(span)
+    .await
+}
+
+/// Returns true if the client connected to the socket runs as the same user
+/// as the daemon.  Other users must not control our windows even if the
+/// socket's permissions let them connect, e.g., an activated socket.
+fn is_peer_allowed(stream: &UnixStream) -> bool {
+    // SAFETY: getuid() has no memory safety preconditions and always
+    // succeeds.
+    let uid = unsafe { libc::getuid() };
+    match stream.peer_cred() {
+        Ok(cred) if cred.uid() == uid => true,
+        Ok(cred) => {
+            tracing::warn!(
+                "Refusing client of user {} (pid {:?}).",
+                cred.uid(),
+                cred.pid()
+            );
+            false
         }
         Err(err) => {
-            log::error!("Could not bind socket: {err}")
+            tracing::error!("Could not get credentials of client: {err}");
+            false
         }
     }
 }
 
-fn handle_client_request(stream: UnixStream) {
-    match serde_json::from_reader::<_, cmds::NiriusCmd>(&stream) {
-        Ok(cmd) => {
-            log::debug!("Received command: {cmd:?}");
-            if let Err(err) = stream.shutdown(std::net::Shutdown::Read) {
-                log::error!("Could not shutdown stream for read: {err}")
+/// Sends nirius events to the client as JSON lines until it disconnects or
+/// the daemon shuts down.  A disconnected client is only noticed when the
+/// next event is sent.
+async fn stream_events(mut stream: UnixStream, format: WireFormat) {
+    tracing::debug!("Client subscribed to events.");
+    let mut events = events::subscribe();
+    loop {
+        let event = tokio::select! {
+            event = events.recv() => match event {
+                Ok(event) => event,
+                Err(broadcast::error::RecvError::Lagged(n)) => {
+                    tracing::warn!("Subscriber missed {n} events.");
+                    continue;
+                }
+                Err(broadcast::error::RecvError::Closed) => break,
+            },
+            () = STOP_SUBSCRIPTIONS.notified() => break,
+        };
+        let msg = match format.encode(&protocol::Response {
+            version: protocol::PROTOCOL_VERSION,
+            response: NiriusResponse::Event(event),
+        }) {
+            Ok(msg) => msg,
+            Err(err) => {
+                tracing::error!("Couldn't serialize event: {err}");
+                continue;
+            }
+        };
+        match timeout(CLIENT_IO_TIMEOUT, stream.write_all(&msg)).await {
+            Ok(Ok(())) => (),
+            Ok(Err(err)) => {
+                tracing::debug!("Subscriber disconnected: {err}");
+                break;
+            }
+            Err(_) => {
+                tracing::warn!("Timed out sending event to subscriber.");
+                break;
             }
-            let result = cmds::exec_nirius_cmd(cmd);
-            log::debug!("Executed command, returning result {result:?}");
-            if let Err(err) = serde_json::to_writer(&stream, &result) {
-                log::error!("Couldn't send result back to client: {err}");
+        }
+    }
+}
+
+async fn serve_client(mut stream: UnixStream) {
+    let mut buf = vec![];
+    match timeout(CLIENT_IO_TIMEOUT, stream.read_to_end(&mut buf)).await {
+        Ok(Ok(_)) => (),
+        Ok(Err(err)) => {
+            tracing::error!("Could not read command from client: {err}");
+            return;
+        }
+        Err(_) => {
+            tracing::error!("Timed out reading command from client.");
+            return;
+        }
+    }
+    if buf.is_empty() {
+        // Starting daemons probe whether the socket is alive this way.
+        tracing::debug!("Client disconnected without sending a command.");
+        return;
+    }
+    let (format, request) = read_request(&buf);
+    let cmd = match request {
+        Ok(cmds::NiriusCmd::Events) => {
+            tracing::Span::current().record("cmd", "Events");
+            stream_events(stream, format).await;
+            return;
+        }
+        Ok(cmd) => cmd,
+        Err(NiriusError { code, message }) => {
+            let resp = NiriusResponse::Error { code, message };
+            if send_response(&mut stream, format, resp).await {
+                close_client(&mut stream).await;
             }
-            if let Err(err) = stream.shutdown(std::net::Shutdown::Write) {
-                log::error!("Could not shutdown stream for read: {err}");
+            return;
+        }
+    };
+
+    // Parts of long lists are sent as soon as the state actor has collected
+    // them so that the whole list is never serialized at once.
+    let (parts_tx, mut parts) = mpsc::unbounded_channel();
+    let (reader, mut writer) = stream.split();
+    let exec = exec_client_cmd(cmd, Some(reader.as_ref()), Some(parts_tx));
+    tokio::pin!(exec);
+    let result = loop {
+        tokio::select! {
+            result = &mut exec => break result,
+            Some(part) = parts.recv() => {
+                let part = NiriusResponse::Part(Box::new(part));
+                if !send_response(&mut writer, format, part).await {
+                    return;
+                }
             }
         }
+    };
+    let Some(result) = result else { return };
+    // The command has finished, so all its parts are there already.
+    while let Ok(part) = parts.try_recv() {
+        let part = NiriusResponse::Part(Box::new(part));
+        if !send_response(&mut writer, format, part).await {
+            return;
+        }
+    }
+    if send_response(&mut writer, format, result).await {
+        close_client(&mut writer).await;
+    }
+}
+
+/// Sends the response to the client.  Returns false if that failed.
+async fn send_response(
+    writer: &mut (impl AsyncWrite + Unpin),
+    format: WireFormat,
+    response: NiriusResponse,
+) -> bool {
+    let msg = match format.encode(&protocol::Response {
+        version: protocol::PROTOCOL_VERSION,
+        response,
+    }) {
+        Ok(msg) => msg,
         Err(err) => {
-            log::error!("Could not read command from client: {err}");
+            tracing::error!("Couldn't serialize result: {err}");
+            return false;
+        }
+    };
+    match timeout(CLIENT_IO_TIMEOUT, writer.write_all(&msg)).await {
+        Ok(Ok(())) => true,
+        Ok(Err(err)) => {
+            tracing::error!("Couldn't send result back to client: {err}");
+            false
         }
+        Err(_) => {
+            tracing::error!("Timed out sending result back to client.");
+            false
+        }
+    }
+}
+
+async fn close_client(writer: &mut (impl AsyncWrite + Unpin)) {
+    if let Err(err) = writer.shutdown().await {
+        tracing::error!("Couldn't close connection to client: {err}");
     }
 }
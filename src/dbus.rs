@@ -0,0 +1,150 @@
+// Copyright (C) 2025  Tassilo Horn <tsdh@gnu.org>
+//
+// This program is free software: you can redistribute it and/or modify it
+// under the terms of the GNU General Public License as published by the Free
+// Software Foundation, either version 3 of the License, or (at your option)
+// any later version.
+//
+// This program is distributed in the hope that it will be useful, but WITHOUT
+// ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or
+// FITNESS FOR A PARTICULAR PURPOSE.  See the GNU General Public License for
+// more details.
+//
+// You should have received a copy of the GNU General Public License along with
+// this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! The D-Bus interface of niriusd.  It's served on the session bus as
+//! `org.nirius.Daemon` in addition to the Unix socket and offers the same
+//! commands and events.
+
+use tokio::sync::broadcast;
+use tracing::{Instrument, field};
+use zbus::object_server::SignalEmitter;
+
+use crate::cmds::{self, NiriusCmd};
+use crate::daemon;
+use crate::events;
+use crate::protocol::{ErrorCode, NiriusResponse};
+
+const BUS_NAME: &str = "org.nirius.Daemon";
+const OBJECT_PATH: &str = "/org/nirius/Daemon";
+
+/// The errors of D-Bus calls named like `org.nirius.Error.NoMatchingWindow`.
+#[derive(Debug, zbus::DBusError)]
+#[zbus(prefix = "org.nirius.Error")]
+enum Error {
+    #[zbus(error)]
+    ZBus(zbus::Error),
+    Failed(String),
+    NoMatchingWindow(String),
+    NoFocusedWindow(String),
+    NiriIpc(String),
+    BadArguments(String),
+}
+
+impl Error {
+    fn new(code: ErrorCode, message: String) -> Self {
+        match code {
+            ErrorCode::NoMatchingWindow => Error::NoMatchingWindow(message),
+            ErrorCode::NoFocusedWindow => Error::NoFocusedWindow(message),
+            ErrorCode::NiriIpc => Error::NiriIpc(message),
+            ErrorCode::BadArguments => Error::BadArguments(message),
+            ErrorCode::Failed
+            | ErrorCode::DaemonUnreachable
+            | ErrorCode::InvalidRequest
+            | ErrorCode::VersionMismatch => Error::Failed(message),
+        }
+    }
+}
+
+struct Daemon;
+
+impl Daemon {
+    async fn exec(args: Vec<String>) -> Result<NiriusResponse, Error> {
+        let cmd = cmds::parse_nirius_cmd(&args)
+            .map_err(|e| Error::new(e.code, e.message))?;
+        if cmd == NiriusCmd::Events {
+            return Err(Error::BadArguments(
+                "Connect to the Event signal instead.".to_owned(),
+            ));
+        }
+        let span = tracing::info_span!("dbus", cmd = field::Empty);
+        let resp = daemon::exec_client_cmd(cmd, None, None)
+            .instrument(span)
+            .await
+            .ok_or_else(|| Error::Failed("Cancelled.".to_owned()))?;
+        match resp.error_code() {
+            None => Ok(resp),
+            Some(code) => Err(Error::new(code, resp.format_text())),
+        }
+    }
+}
+
+#[zbus::interface(name = "org.nirius.Daemon")]
+impl Daemon {
+    /// Executes the nirius command given as arguments like on the command
+    /// line, e.g., `["focus", "-a", "firefox"]`, and returns its output.
+    async fn execute(&self, args: Vec<String>) -> Result<String, Error> {
+        Ok(Daemon::exec(args).await?.format_text())
+    }
+
+    /// Like `Execute` but returns the output as JSON.
+    async fn execute_json(&self, args: Vec<String>) -> Result<String, Error> {
+        Daemon::exec(args)
+            .await?
+            .format_json()
+            .map_err(|e| Error::new(ErrorCode::Failed, e))
+    }
+
+    /// Emitted for every event `nirius events` prints with the same JSON.
+    #[zbus(signal)]
+    async fn event(emitter: &SignalEmitter<'_>, json: &str)
+    -> zbus::Result<()>;
+}
+
+/// Serves the D-Bus interface and emits events until the daemon stops.  Not
+/// having a session bus isn't an error since the socket still works.
+pub async fn serve() {
+    let conn = match zbus::connection::Builder::session()
+        .and_then(|b| b.name(BUS_NAME))
+        .and_then(|b| b.serve_at(OBJECT_PATH, Daemon))
+    {
+        Ok(builder) => builder.build().await,
+        Err(err) => Err(err),
+    };
+    let conn = match conn {
+        Ok(conn) => conn,
+        Err(err) => {
+            tracing::warn!("Not serving {BUS_NAME} on D-Bus: {err}");
+            return;
+        }
+    };
+    tracing::info!("Serving {BUS_NAME} on D-Bus.");
+
+    let emitter = match SignalEmitter::new(&conn, OBJECT_PATH) {
+        Ok(emitter) => emitter,
+        Err(err) => {
+            tracing::error!("Cannot emit D-Bus signals: {err}");
+            return;
+        }
+    };
+    let mut events = events::subscribe();
+    loop {
+        let event = match events.recv().await {
+            Ok(event) => event,
+            Err(broadcast::error::RecvError::Lagged(n)) => {
+                tracing::warn!("D-Bus missed {n} events.");
+                continue;
+            }
+            Err(broadcast::error::RecvError::Closed) => break,
+        };
+        match serde_json::to_string(&event) {
+            Ok(json) => {
+                if let Err(err) = Daemon::event(&emitter, &json).await {
+                    tracing::error!("Could not emit D-Bus signal: {err}");
+                }
+            }
+            Err(err) => tracing::error!("Couldn't serialize event: {err}"),
+        }
+    }
+}
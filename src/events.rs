@@ -0,0 +1,176 @@
+// Copyright (C) 2025  Tassilo Horn <tsdh@gnu.org>
+//
+// This program is free software: you can redistribute it and/or modify it
+// under the terms of the GNU General Public License as published by the Free
+// Software Foundation, either version 3 of the License, or (at your option)
+// any later version.
+//
+// This program is distributed in the hope that it will be useful, but WITHOUT
+// ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or
+// FITNESS FOR A PARTICULAR PURPOSE.  See the GNU General Public License for
+// more details.
+//
+// You should have received a copy of the GNU General Public License along with
+// this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! The events clients can subscribe to with `nirius events`.  They are
+//! derived by comparing the state after each state job with what has been
+//! published before, so they are sent no matter if a niri event or a nirius
+//! command changed the state.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::sync::LazyLock;
+
+use tokio::sync::broadcast;
+
+use crate::protocol::NiriusEvent;
+use crate::state::State;
+
+/// How many events a slow subscriber may lag behind before it misses events.
+const CAPACITY: usize = 256;
+
+static EVENTS: LazyLock<broadcast::Sender<NiriusEvent>> =
+    LazyLock::new(|| broadcast::channel(CAPACITY).0);
+
+/// Returns a receiver of all events published from now on.
+pub fn subscribe() -> broadcast::Receiver<NiriusEvent> {
+    EVENTS.subscribe()
+}
+
+/// The parts of the state subscribers have been told about.
+#[derive(Default, PartialEq)]
+pub struct Published {
+    win_ids: BTreeSet<u64>,
+    focused_win_id: Option<u64>,
+    focused_ws_id: Option<u64>,
+    marks: BTreeMap<String, Vec<u64>>,
+    scratchpad_win_ids: Vec<u64>,
+    urgent_win_ids: Vec<u64>,
+}
+
+impl Published {
+    fn of(state: &State) -> Self {
+        Published {
+            win_ids: state.all_windows.iter().map(|w| w.id).collect(),
+            focused_win_id: state.get_focused_win_id(),
+            focused_ws_id: state
+                .all_workspaces
+                .iter()
+                .find(|ws| ws.is_focused)
+                .map(|ws| ws.id),
+            marks: state
+                .mark_to_win_ids
+                .iter()
+                .filter(|(_, ids)| !ids.is_empty())
+                .map(|(mark, ids)| (mark.clone(), ids.clone()))
+                .collect(),
+            scratchpad_win_ids: state.scratchpad_win_ids.clone(),
+            urgent_win_ids: state
+                .all_windows
+                .iter()
+                .filter(|w| w.is_urgent)
+                .map(|w| w.id)
+                .collect(),
+        }
+    }
+
+    /// Publishes the events for everything which changed in `state` since
+    /// the last call.
+    pub fn publish_changes(&mut self, state: &State) {
+        let new = Published::of(state);
+        if new == *self {
+            return;
+        }
+        let old = std::mem::replace(self, new);
+        if EVENTS.receiver_count() == 0 {
+            return;
+        }
+        let new = &*self;
+
+        for win in &state.all_windows {
+            if !old.win_ids.contains(&win.id) {
+                publish(NiriusEvent::WindowOpened(state.window_info(win)));
+            }
+        }
+        for id in old.win_ids.difference(&new.win_ids) {
+            publish(NiriusEvent::WindowClosed { id: *id });
+        }
+        if old.focused_win_id != new.focused_win_id {
+            publish(NiriusEvent::WindowFocused {
+                id: new.focused_win_id,
+            });
+        }
+        if old.focused_ws_id != new.focused_ws_id
+            && let Some(id) = new.focused_ws_id
+        {
+            publish(NiriusEvent::WorkspaceFocused { id });
+        }
+        if old.marks != new.marks {
+            publish(NiriusEvent::MarksChanged(new.marks.clone()));
+        }
+        if old.scratchpad_win_ids != new.scratchpad_win_ids {
+            publish(NiriusEvent::ScratchpadChanged(
+                new.scratchpad_win_ids.clone(),
+            ));
+        }
+        if old.urgent_win_ids != new.urgent_win_ids {
+            publish(NiriusEvent::UrgencyChanged(new.urgent_win_ids.clone()));
+        }
+    }
+}
+
+fn publish(event: NiriusEvent) {
+    tracing::debug!("Publishing {event:?}.");
+    // Sending only fails if there are no subscribers.
+    let _ = EVENTS.send(event);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::state::tests::{state, window};
+
+    fn received(events: &mut broadcast::Receiver<NiriusEvent>) -> Vec<String> {
+        std::iter::from_fn(|| events.try_recv().ok())
+            .map(|event| serde_json::to_string(&event).unwrap())
+            .collect()
+    }
+
+    // All changes are checked in one test because the events go to all
+    // subscribers.
+    #[test]
+    fn publish_changes_diffs_the_state() {
+        let mut events = subscribe();
+        let mut published = Published::default();
+        let mut state = state(vec![window(1, 1), window(2, 1)]);
+        state.window_focus_changed(Some(2)).unwrap();
+
+        published.publish_changes(&state);
+        let opened = received(&mut events);
+        assert_eq!(opened.len(), 4, "{opened:?}");
+        assert!(opened[0].starts_with(r#"{"WindowOpened":{"id":1,"#));
+        assert!(opened[1].starts_with(r#"{"WindowOpened":{"id":2,"#));
+        assert_eq!(opened[2], r#"{"WindowFocused":{"id":2}}"#);
+        assert_eq!(opened[3], r#"{"WorkspaceFocused":{"id":1}}"#);
+
+        published.publish_changes(&state);
+        assert!(received(&mut events).is_empty());
+
+        state.remove_window(&2).unwrap();
+        state.window_focus_changed(Some(1)).unwrap();
+        state.mark_to_win_ids.insert("a".to_owned(), vec![1]);
+        published.publish_changes(&state);
+        assert_eq!(
+            received(&mut events),
+            [
+                r#"{"WindowClosed":{"id":2}}"#,
+                r#"{"WindowFocused":{"id":1}}"#,
+                r#"{"MarksChanged":{"a":[1]}}"#,
+            ]
+        );
+
+        state.window_focus_changed(None).unwrap();
+        published.publish_changes(&state);
+        assert_eq!(received(&mut events), [r#"{"WindowFocused":{"id":null}}"#]);
+    }
+}
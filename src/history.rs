@@ -0,0 +1,111 @@
+// Copyright (C) 2025  Tassilo Horn <tsdh@gnu.org>
+//
+// This program is free software: you can redistribute it and/or modify it
+// under the terms of the GNU General Public License as published by the Free
+// Software Foundation, either version 3 of the License, or (at your option)
+// any later version.
+//
+// This program is distributed in the hope that it will be useful, but WITHOUT
+// ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or
+// FITNESS FOR A PARTICULAR PURPOSE.  See the GNU General Public License for
+// more details.
+//
+// You should have received a copy of the GNU General Public License along with
+// this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! The recent niri events, client commands and timers the daemon handled,
+//! and what came out of them, shown by `nirius history`.
+
+use std::collections::VecDeque;
+use std::fmt::Debug;
+use std::sync::{Mutex, PoisonError};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+
+/// How many entries are kept.  Older ones are dropped.
+const CAPACITY: usize = 1000;
+
+/// How many characters of an event or command are kept.
+const MAX_WHAT_LEN: usize = 300;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Source {
+    Event,
+    Command,
+    Timer,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct HistoryEntry {
+    /// When it has been handled as UNIX timestamp in milliseconds.
+    pub time_ms: u64,
+    pub source: Source,
+    /// The event, command or timer name.
+    pub what: String,
+    pub ok: bool,
+    /// What the daemon did or the error.
+    pub message: String,
+}
+
+impl HistoryEntry {
+    pub fn format(&self) -> String {
+        let source = match self.source {
+            Source::Event => "event",
+            Source::Command => "command",
+            Source::Timer => "timer",
+        };
+        format!(
+            "{} {source} {} => {}{}",
+            crate::util::format_local_time(self.time_ms / 1000),
+            self.what,
+            if self.ok { "" } else { "Error: " },
+            self.message.trim()
+        )
+    }
+}
+
+static HISTORY: Mutex<VecDeque<HistoryEntry>> = Mutex::new(VecDeque::new());
+
+/// Records an event, command or timer with its result.
+pub fn record<T: Debug>(
+    source: Source,
+    what: &T,
+    result: &Result<String, String>,
+) {
+    let mut what = format!("{what:?}");
+    if let Some((idx, _)) = what.char_indices().nth(MAX_WHAT_LEN) {
+        what.truncate(idx);
+        what.push('…');
+    }
+    let (ok, message) = match result {
+        Ok(msg) => (true, msg.clone()),
+        Err(err) => (false, err.clone()),
+    };
+    let time_ms = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| u64::try_from(d.as_millis()).unwrap_or(u64::MAX))
+        .unwrap_or_default();
+    let mut history = HISTORY.lock().unwrap_or_else(PoisonError::into_inner);
+    if history.len() == CAPACITY {
+        history.pop_front();
+    }
+    history.push_back(HistoryEntry {
+        time_ms,
+        source,
+        what,
+        ok,
+        message,
+    });
+}
+
+/// Returns the given number of most recent entries, the oldest first.
+pub fn recent(limit: usize) -> Vec<HistoryEntry> {
+    let history = HISTORY.lock().unwrap_or_else(PoisonError::into_inner);
+    history
+        .iter()
+        .skip(history.len().saturating_sub(limit))
+        .cloned()
+        .collect()
+}
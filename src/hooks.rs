@@ -0,0 +1,145 @@
+// Copyright (C) 2025  Tassilo Horn <tsdh@gnu.org>
+//
+// This program is free software: you can redistribute it and/or modify it
+// under the terms of the GNU General Public License as published by the Free
+// Software Foundation, either version 3 of the License, or (at your option)
+// any later version.
+//
+// This program is distributed in the hope that it will be useful, but WITHOUT
+// ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or
+// FITNESS FOR A PARTICULAR PURPOSE.  See the GNU General Public License for
+// more details.
+//
+// You should have received a copy of the GNU General Public License along with
+// this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! Running user-defined shell commands (hooks) with details about windows and
+//! workspaces passed in environment variables.
+
+use std::process::Stdio;
+
+use tokio::process::Command;
+use tracing::Instrument;
+
+use niri_ipc::{Window, Workspace};
+
+use crate::state::State;
+
+pub type HookEnv = Vec<(&'static str, String)>;
+
+/// Runs the given command using `sh -c` in the background with the given
+/// environment variables added.
+pub fn run_hook(name: &str, command: &str, env: HookEnv) {
+    tracing::debug!("Running {name} hook: {command}");
+    let mut cmd = Command::new("sh");
+    cmd.arg("-c").arg(command);
+    spawn_hook(name, cmd, env);
+}
+
+/// Like [`run_hook`] but runs the given program with arguments directly
+/// instead of using a shell.
+pub fn run_hook_args(name: &str, args: &[String], env: HookEnv) {
+    tracing::debug!("Running {name} hook: {args:?}");
+    let Some((program, args)) = args.split_first() else {
+        tracing::error!("Empty command for {name} hook.");
+        return;
+    };
+    let mut cmd = Command::new(program);
+    cmd.args(args);
+    spawn_hook(name, cmd, env);
+}
+
+fn spawn_hook(name: &str, mut cmd: Command, env: HookEnv) {
+    match cmd.env("NIRIUS_HOOK", name).envs(env).spawn() {
+        Ok(mut child) => {
+            // Reap the child so that it doesn't stay around as a zombie.
+            tokio::spawn(
+                async move {
+                    match child.wait().await {
+                        Ok(status) if !status.success() => {
+                            tracing::warn!("Hook exited with {status}.")
+                        }
+                        Err(err) => {
+                            tracing::error!("Could not wait for hook: {err}")
+                        }
+                        _ => (),
+                    }
+                }
+                .in_current_span(),
+            );
+        }
+        Err(err) => tracing::error!("Could not run {name} hook: {err}"),
+    }
+}
+
+/// Returns the environment variables describing the given window.
+pub fn window_env(state: &State, win: &Window) -> HookEnv {
+    let mut env = vec![
+        ("NIRIUS_WINDOW_ID", win.id.to_string()),
+        (
+            "NIRIUS_WINDOW_APP_ID",
+            win.app_id.clone().unwrap_or_default(),
+        ),
+        ("NIRIUS_WINDOW_TITLE", win.title.clone().unwrap_or_default()),
+        (
+            "NIRIUS_WINDOW_PID",
+            win.pid.map(|p| p.to_string()).unwrap_or_default(),
+        ),
+    ];
+    env.push((
+        "NIRIUS_WINDOW_MARKS",
+        state.get_window_marks(win.id).join(","),
+    ));
+    if let Some(ws) = win
+        .workspace_id
+        .and_then(|id| state.all_workspaces.iter().find(|ws| ws.id == id))
+    {
+        env.extend(workspace_env(ws));
+    }
+    env
+}
+
+/// Returns the environment variables describing the given workspace.
+pub fn workspace_env(ws: &Workspace) -> HookEnv {
+    vec![
+        ("NIRIUS_WORKSPACE_ID", ws.id.to_string()),
+        ("NIRIUS_WORKSPACE_IDX", ws.idx.to_string()),
+        ("NIRIUS_WORKSPACE_NAME", ws.name.clone().unwrap_or_default()),
+        ("NIRIUS_OUTPUT", ws.output.clone().unwrap_or_default()),
+    ]
+}
+
+/// Sends a desktop notification using `notify-send` in the background.  If
+/// the user clicks the notification, `on_click` is called.
+pub fn notify<F>(summary: &str, body: &str, on_click: F)
+where
+    F: FnOnce() + Send + 'static,
+{
+    match Command::new("notify-send")
+        .args(["--app-name=nirius", "--action=default=Focus", "--wait"])
+        .arg(summary)
+        .arg(body)
+        .stdout(Stdio::piped())
+        .spawn()
+    {
+        Ok(child) => {
+            // notify-send prints the name of the invoked action.
+            tokio::spawn(async move {
+                match child.wait_with_output().await {
+                    Ok(output)
+                        if String::from_utf8_lossy(&output.stdout).trim()
+                            == "default" =>
+                    {
+                        // The click handler usually talks to niri.
+                        tokio::task::spawn_blocking(on_click);
+                    }
+                    Err(err) => {
+                        tracing::error!("Could not wait for notify-send: {err}")
+                    }
+                    _ => (),
+                }
+            });
+        }
+        Err(err) => tracing::error!("Could not run notify-send: {err}"),
+    }
+}
@@ -0,0 +1,144 @@
+// Copyright (C) 2025  Tassilo Horn <tsdh@gnu.org>
+//
+// This program is free software: you can redistribute it and/or modify it
+// under the terms of the GNU General Public License as published by the Free
+// Software Foundation, either version 3 of the License, or (at your option)
+// any later version.
+//
+// This program is distributed in the hope that it will be useful, but WITHOUT
+// ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or
+// FITNESS FOR A PARTICULAR PURPOSE.  See the GNU General Public License for
+// more details.
+//
+// You should have received a copy of the GNU General Public License along with
+// this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! Window icons looked up from the `Icon` key of the desktop entries in the
+//! `applications` directories below `$XDG_DATA_HOME` and `$XDG_DATA_DIRS`.
+
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+/// The icons of the installed applications by lowercase app-id.
+pub struct Icons {
+    by_app_id: HashMap<String, String>,
+}
+
+/// The keys of a desktop entry relevant for finding icons.
+struct DesktopEntry {
+    icon: Option<String>,
+    startup_wm_class: Option<String>,
+}
+
+impl Icons {
+    /// Reads the desktop entries of all installed applications.  Entries in
+    /// directories with higher precedence shadow the ones in others.
+    pub fn load() -> Self {
+        let mut files = vec![];
+        for dir in application_dirs() {
+            collect_desktop_files(&dir, &mut files);
+        }
+
+        let mut exact = HashMap::new();
+        let mut suffixes = HashMap::new();
+        for file in files {
+            let Some(stem) = file.file_stem().and_then(|s| s.to_str()) else {
+                continue;
+            };
+            let Some(DesktopEntry {
+                icon: Some(icon),
+                startup_wm_class,
+            }) = read_desktop_entry(&file)
+            else {
+                continue;
+            };
+            let stem = stem.to_lowercase();
+            if let Some(class) = startup_wm_class {
+                exact.entry(class.to_lowercase()).or_insert(icon.clone());
+            }
+            // Reverse-DNS ids like org.codeberg.dnkl.foot also match the
+            // app-id foot.
+            if let Some((_, suffix)) = stem.rsplit_once('.') {
+                suffixes.entry(suffix.to_owned()).or_insert(icon.clone());
+            }
+            exact.entry(stem).or_insert(icon);
+        }
+        for (suffix, icon) in suffixes {
+            exact.entry(suffix).or_insert(icon);
+        }
+        Icons { by_app_id: exact }
+    }
+
+    /// Returns the icon name or path for the app-id.  Without a desktop
+    /// entry, that's the app-id itself which often names an icon, too.
+    pub fn get<'a>(&'a self, app_id: &'a str) -> &'a str {
+        let app_id_lc = app_id.to_lowercase();
+        self.by_app_id
+            .get(&app_id_lc)
+            .or_else(|| {
+                app_id_lc
+                    .rsplit_once('.')
+                    .and_then(|(_, suffix)| self.by_app_id.get(suffix))
+            })
+            .map(String::as_str)
+            .unwrap_or(app_id)
+    }
+}
+
+/// Returns the `applications` directories in the order of precedence.
+fn application_dirs() -> Vec<PathBuf> {
+    let mut dirs = vec![];
+    if let Some(base) = directories::BaseDirs::new() {
+        dirs.push(base.data_dir().to_owned());
+    }
+    let data_dirs = std::env::var("XDG_DATA_DIRS")
+        .ok()
+        .filter(|d| !d.is_empty())
+        .unwrap_or_else(|| "/usr/local/share:/usr/share".to_owned());
+    dirs.extend(data_dirs.split(':').map(PathBuf::from));
+    dirs.into_iter().map(|d| d.join("applications")).collect()
+}
+
+fn collect_desktop_files(dir: &Path, files: &mut Vec<PathBuf>) {
+    let Ok(entries) = std::fs::read_dir(dir) else {
+        return;
+    };
+    for entry in entries.flatten() {
+        let path = entry.path();
+        if path.is_dir() {
+            collect_desktop_files(&path, files);
+        } else if path.extension().is_some_and(|ext| ext == "desktop") {
+            files.push(path);
+        }
+    }
+}
+
+fn read_desktop_entry(file: &Path) -> Option<DesktopEntry> {
+    let content = std::fs::read_to_string(file).ok()?;
+    let mut entry = DesktopEntry {
+        icon: None,
+        startup_wm_class: None,
+    };
+    let mut in_desktop_entry = false;
+    for line in content.lines().map(str::trim) {
+        if line.starts_with('[') {
+            in_desktop_entry = line == "[Desktop Entry]";
+            continue;
+        }
+        if !in_desktop_entry {
+            continue;
+        }
+        let Some((key, value)) = line.split_once('=') else {
+            continue;
+        };
+        let value = value.trim();
+        match key.trim() {
+            "Icon" if !value.is_empty() => entry.icon = Some(value.to_owned()),
+            "StartupWMClass" if !value.is_empty() => {
+                entry.startup_wm_class = Some(value.to_owned())
+            }
+            _ => (),
+        }
+    }
+    Some(entry)
+}
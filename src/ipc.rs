@@ -13,19 +13,53 @@
 // You should have received a copy of the GNU General Public License along with
 // this program.  If not, see <https://www.gnu.org/licenses/>.
 
+use std::time::Instant;
+
 use niri_ipc::socket::Socket;
 use niri_ipc::{Request, Response};
 
-pub fn query_niri(req: Request) -> Result<Response, String> {
-    match Socket::connect() {
-        Ok(mut socket) => match socket.send(req) {
-            Ok(Ok(response)) => Ok(response),
-            Ok(Err(e)) => Err(e),
-            Err(err) => Err(err.to_string()),
-        },
-        Err(err) => {
-            log::error!("Cannot connect to niri: {err:?}");
-            Err(err.to_string())
+use crate::protocol::{ErrorCode, NiriusError};
+use crate::{stats, util};
+
+/// The prefix of all errors talking to niri.
+const NIRI_IPC_ERROR: &str = "Cannot talk to niri";
+
+fn ipc_error(err: impl std::fmt::Display) -> NiriusError {
+    NiriusError::new(ErrorCode::NiriIpc, format!("{NIRI_IPC_ERROR}: {err}"))
+}
+
+/// The error for a reply which doesn't fit the request.
+pub fn unexpected_reply(reply: Response) -> NiriusError {
+    NiriusError::new(
+        ErrorCode::NiriIpc,
+        format!("Received unexpected reply {reply:?}"),
+    )
+}
+
+fn connect() -> Result<Socket, NiriusError> {
+    let path = util::get_niri_socket_path().map_err(ipc_error)?;
+    Socket::connect_to(path).map_err(|err| {
+        tracing::error!("Cannot connect to niri: {err:?}");
+        ipc_error(err)
+    })
+}
+
+/// Sends the request to niri.  Errors niri replies with are classified as
+/// [`ErrorCode::NiriIpc`] just like connection failures.
+pub fn query_niri(req: Request) -> Result<Response, NiriusError> {
+    let start = Instant::now();
+    let result = send_to_niri(req);
+    stats::record_niri_query(start.elapsed());
+    result
+}
+
+fn send_to_niri(req: Request) -> Result<Response, NiriusError> {
+    // niri answers one request per connection, so each query needs a fresh
+    // one.
+    match connect()?.send(req) {
+        Ok(reply) => {
+            reply.map_err(|msg| NiriusError::new(ErrorCode::NiriIpc, msg))
         }
+        Err(err) => Err(ipc_error(err)),
     }
 }
@@ -0,0 +1,101 @@
+// Copyright (C) 2025  Tassilo Horn <tsdh@gnu.org>
+//
+// This program is free software: you can redistribute it and/or modify it
+// under the terms of the GNU General Public License as published by the Free
+// Software Foundation, either version 3 of the License, or (at your option)
+// any later version.
+//
+// This program is distributed in the hope that it will be useful, but WITHOUT
+// ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or
+// FITNESS FOR A PARTICULAR PURPOSE.  See the GNU General Public License for
+// more details.
+//
+// You should have received a copy of the GNU General Public License along with
+// this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! Layout snapshots recording which windows are on which workspaces and
+//! outputs.  They are persisted as JSON files in the nirius state directory.
+
+use std::path::PathBuf;
+
+use niri_ipc::Window;
+use serde::{Deserialize, Serialize};
+
+use crate::state::State;
+use crate::util;
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct LayoutSnapshot {
+    pub windows: Vec<WindowPlacement>,
+}
+
+/// Where a window identified by its app-id and title has been placed.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct WindowPlacement {
+    pub app_id: Option<String>,
+    pub title: Option<String>,
+    pub output: String,
+    pub workspace_idx: u8,
+    pub workspace_name: Option<String>,
+    pub is_floating: bool,
+}
+
+impl LayoutSnapshot {
+    pub fn of_state(state: &State) -> Self {
+        let windows = state
+            .all_windows
+            .iter()
+            .filter_map(|w| {
+                let ws = state
+                    .all_workspaces
+                    .iter()
+                    .find(|ws| Some(ws.id) == w.workspace_id)?;
+                Some(WindowPlacement {
+                    app_id: w.app_id.clone(),
+                    title: w.title.clone(),
+                    output: ws.output.clone()?,
+                    workspace_idx: ws.idx,
+                    workspace_name: ws.name.clone(),
+                    is_floating: w.is_floating,
+                })
+            })
+            .collect();
+        LayoutSnapshot { windows }
+    }
+}
+
+impl WindowPlacement {
+    /// Returns true if the given window has the same app-id and, if
+    /// `check_title` is true, the same title as the placed window.
+    pub fn is_for(&self, win: &Window, check_title: bool) -> bool {
+        self.app_id == win.app_id && (!check_title || self.title == win.title)
+    }
+}
+
+fn get_layout_file_path(name: &str) -> Result<PathBuf, String> {
+    if name.is_empty() || name.contains('/') {
+        return Err(format!("Invalid layout name {name:?}."));
+    }
+    Ok(util::get_nirius_state_dir()?
+        .join("layouts")
+        .join(format!("{name}.json")))
+}
+
+pub fn save_layout(
+    name: &str,
+    snapshot: &LayoutSnapshot,
+) -> Result<(), String> {
+    let path = get_layout_file_path(name)?;
+    if let Some(dir) = path.parent() {
+        std::fs::create_dir_all(dir).map_err(|e| e.to_string())?;
+    }
+    let file = std::fs::File::create(&path).map_err(|e| e.to_string())?;
+    serde_json::to_writer_pretty(file, snapshot).map_err(|e| e.to_string())
+}
+
+pub fn load_layout(name: &str) -> Result<LayoutSnapshot, String> {
+    let path = get_layout_file_path(name)?;
+    let file = std::fs::File::open(&path)
+        .map_err(|e| format!("Could not open {}: {e}", path.display()))?;
+    serde_json::from_reader(file).map_err(|e| e.to_string())
+}
@@ -18,9 +18,29 @@
 //! subcommands, see `nirius --help` and sends them to the daemon `niriusd`
 //! which executes them.
 
+#[cfg(feature = "async-client")]
+pub mod async_client;
 pub mod client;
 pub mod cmds;
+pub mod completions;
+pub mod config;
 pub mod daemon;
+#[cfg(feature = "dbus")]
+pub mod dbus;
+pub mod events;
+pub mod history;
+pub mod hooks;
+pub mod icons;
 pub mod ipc;
+pub mod layout;
+pub mod menu;
+pub mod protocol;
+#[cfg(feature = "scripting")]
+pub mod scripting;
 pub mod state;
+pub mod stats;
+pub mod time_tracking;
+pub mod timers;
+pub mod undo;
 pub mod util;
+pub mod validate;
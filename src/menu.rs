@@ -0,0 +1,330 @@
+// Copyright (C) 2025  Tassilo Horn <tsdh@gnu.org>
+//
+// This program is free software: you can redistribute it and/or modify it
+// under the terms of the GNU General Public License as published by the Free
+// Software Foundation, either version 3 of the License, or (at your option)
+// any later version.
+//
+// This program is distributed in the hope that it will be useful, but WITHOUT
+// ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or
+// FITNESS FOR A PARTICULAR PURPOSE.  See the GNU General Public License for
+// more details.
+//
+// You should have received a copy of the GNU General Public License along with
+// this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! Window pickers for `nirius menu` and `nirius pick`.  The windows are listed
+//! one per line in a menu program or on stdout, and the selected one is acted
+//! upon.  This runs in the client since the picker needs the user's
+//! attention.
+
+use std::io::{BufRead, Write};
+use std::process::{Command, Stdio};
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+
+use crate::client;
+use crate::cmds::{MatchOptions, NiriusCmd, WindowOrder};
+use crate::icons::Icons;
+use crate::protocol::{ErrorCode, NiriusError, NiriusResponse, WindowInfo};
+
+/// The menu programs `nirius menu` can show the windows in.
+#[derive(
+    clap::ValueEnum, Clone, Copy, PartialEq, Eq, Debug, Serialize, Deserialize,
+)]
+pub enum Picker {
+    Fuzzel,
+    Rofi,
+    Wofi,
+    Dmenu,
+}
+
+impl Picker {
+    fn command(self) -> Command {
+        let (program, args): (&str, &[&str]) = match self {
+            Picker::Fuzzel => ("fuzzel", &["--dmenu"]),
+            Picker::Rofi => {
+                ("rofi", &["-dmenu", "-i", "-p", "window", "-show-icons"])
+            }
+            Picker::Wofi => ("wofi", &["--dmenu"]),
+            Picker::Dmenu => ("dmenu", &["-i"]),
+        };
+        let mut cmd = Command::new(program);
+        cmd.args(args);
+        cmd
+    }
+
+    /// Returns true if the picker shows icons given in rofi's extended dmenu
+    /// protocol.
+    fn supports_icons(self) -> bool {
+        matches!(self, Picker::Fuzzel | Picker::Rofi)
+    }
+}
+
+/// What to do with the window selected in a menu.
+#[derive(
+    clap::ValueEnum, Clone, Copy, PartialEq, Eq, Debug, Serialize, Deserialize,
+)]
+pub enum MenuAction {
+    /// Focus the window.
+    Focus,
+    /// Move the window to the current workspace and focus it.
+    Move,
+    /// Toggle the default mark of the window.
+    Mark,
+}
+
+impl MenuAction {
+    /// Returns the command performing the action on the given window.
+    pub fn cmd(self, id: u64) -> NiriusCmd {
+        match self {
+            MenuAction::Focus => NiriusCmd::Focus {
+                match_opts: MatchOptions::id(id),
+            },
+            MenuAction::Move => NiriusCmd::MoveToCurrentWorkspace {
+                match_opts: MatchOptions::id(id),
+                focus: true,
+            },
+            MenuAction::Mark => NiriusCmd::ToggleMark {
+                mark: None,
+                id: Some(id),
+            },
+        }
+    }
+}
+
+/// Formats the window as menu entry `<id> <app-id>: <title>`, followed by its
+/// marks in brackets if it has any.
+pub fn format_entry(win: &WindowInfo) -> String {
+    let mut entry = format!(
+        "{} {}: {}",
+        win.id,
+        win.app_id.as_deref().unwrap_or(""),
+        win.title.as_deref().unwrap_or("")
+    );
+    if !win.marks.is_empty() {
+        entry.push_str(&format!(" [{}]", win.marks.join(" ")));
+    }
+    // Menu entries are separated by newlines.
+    entry.replace(['\n', '\r'], " ")
+}
+
+/// Returns the id of the window of a menu entry.
+pub fn parse_entry(entry: &str) -> Option<u64> {
+    entry.split_whitespace().next()?.parse().ok()
+}
+
+/// Returns the windows matching the given options, the most recently
+/// focused one first.
+pub fn get_windows(
+    match_opts: MatchOptions,
+    timeout: Option<Duration>,
+) -> Result<Vec<WindowInfo>, NiriusError> {
+    match client::send_nirius_cmd(
+        NiriusCmd::ListWindows {
+            match_opts,
+            json: false,
+            sort: Some(WindowOrder::Mru),
+        },
+        timeout,
+    )? {
+        NiriusResponse::WindowList(wins) => Ok(wins),
+        NiriusResponse::Error { code, message } => {
+            Err(NiriusError { code, message })
+        }
+        resp => Err(NiriusError::new(
+            ErrorCode::Failed,
+            format!("Unexpected response {resp:?}"),
+        )),
+    }
+}
+
+/// Shows the entries in the picker and returns the selected one, or `None`
+/// if the user cancelled.
+fn run_picker(
+    picker: Picker,
+    entries: &str,
+) -> Result<Option<String>, NiriusError> {
+    let failed = |e: std::io::Error| {
+        NiriusError::new(ErrorCode::Failed, format!("{picker:?} failed: {e}"))
+    };
+    let mut child = picker
+        .command()
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .map_err(failed)?;
+    if let Some(mut stdin) = child.stdin.take() {
+        stdin.write_all(entries.as_bytes()).map_err(failed)?;
+    }
+    let output = child.wait_with_output().map_err(failed)?;
+    let selection = String::from_utf8_lossy(&output.stdout).trim().to_owned();
+    Ok((output.status.success() && !selection.is_empty()).then_some(selection))
+}
+
+/// Shows the windows matching the given options in the picker and performs
+/// the action on the selected one.
+pub fn menu(
+    picker: Picker,
+    action: MenuAction,
+    match_opts: MatchOptions,
+    timeout: Option<Duration>,
+) -> Result<NiriusResponse, NiriusError> {
+    let icons = picker.supports_icons().then(Icons::load);
+    let Some(entries) = get_entries(match_opts, timeout, icons.as_ref())?
+    else {
+        return Ok(no_matching_window());
+    };
+    let selection = run_picker(picker, &entries)?;
+    act_on_selection(selection, action, timeout)
+}
+
+/// Prints the windows matching the given options on stdout.  With `stdin`,
+/// a selected entry is read from stdin then and the action is performed on
+/// its window.
+pub fn pick(
+    stdin: bool,
+    action: MenuAction,
+    match_opts: MatchOptions,
+    timeout: Option<Duration>,
+) -> Result<NiriusResponse, NiriusError> {
+    let Some(entries) = get_entries(match_opts, timeout, None)? else {
+        return Ok(no_matching_window());
+    };
+    if !stdin {
+        return Ok(NiriusResponse::Ok(entries));
+    }
+    let failed =
+        |e: std::io::Error| NiriusError::new(ErrorCode::Failed, e.to_string());
+    let mut stdout = std::io::stdout().lock();
+    stdout.write_all(entries.as_bytes()).map_err(failed)?;
+    stdout.flush().map_err(failed)?;
+    let mut selection = String::new();
+    std::io::stdin()
+        .lock()
+        .read_line(&mut selection)
+        .map_err(failed)?;
+    let selection = selection.trim();
+    act_on_selection(
+        (!selection.is_empty()).then(|| selection.to_owned()),
+        action,
+        timeout,
+    )
+}
+
+/// Implements rofi's script mode.  rofi runs `nirius rofi` for the listing
+/// and again with the selected entry and `ROFI_RETV=1` once the user has
+/// selected one.  The window id is passed back in the row's info.
+pub fn rofi(
+    selection: Option<String>,
+    action: MenuAction,
+    match_opts: MatchOptions,
+    timeout: Option<Duration>,
+) -> Result<NiriusResponse, NiriusError> {
+    let retv = std::env::var("ROFI_RETV").ok();
+    match retv.as_deref().unwrap_or("0") {
+        "0" => Ok(NiriusResponse::Ok(rofi_rows(
+            &get_windows(match_opts, timeout)?,
+            &Icons::load(),
+        ))),
+        "1" => {
+            let id = std::env::var("ROFI_INFO")
+                .ok()
+                .and_then(|info| info.parse().ok())
+                .or_else(|| selection.as_deref().and_then(parse_entry));
+            let Some(id) = id else {
+                return Ok(NiriusResponse::Error {
+                    code: ErrorCode::BadArguments,
+                    message: format!("Not a window: {selection:?}"),
+                });
+            };
+            // Anything printed would be shown by rofi as a new listing.
+            match client::send_nirius_cmd(action.cmd(id), timeout)? {
+                NiriusResponse::Ok(_) => Ok(NiriusResponse::Ok(String::new())),
+                resp => Ok(resp),
+            }
+        }
+        // Custom input and keybindings aren't supported.
+        _ => Ok(NiriusResponse::Ok(String::new())),
+    }
+}
+
+/// Returns rofi's script mode rows for the windows.  Each row shows
+/// `<app-id>: <title>`, uses the icon of the app-id, carries the window id as
+/// info, and the marks as search keywords.  The focused window is active.
+fn rofi_rows(wins: &[WindowInfo], icons: &Icons) -> String {
+    let mut rows = String::from("\0prompt\x1fwindow\n\0no-custom\x1ftrue\n");
+    if let Some(pos) = wins.iter().position(|w| w.is_focused) {
+        rows.push_str(&format!("\0active\x1f{pos}\n"));
+    }
+    if wins.is_empty() {
+        rows.push_str("\0message\x1fNo matching window.\n");
+    }
+    for win in wins {
+        let app_id = win.app_id.as_deref().unwrap_or("");
+        let mut text =
+            format!("{app_id}: {}", win.title.as_deref().unwrap_or(""));
+        if !win.marks.is_empty() {
+            text.push_str(&format!(" [{}]", win.marks.join(" ")));
+        }
+        // Rows are separated by newlines and end at the first NUL.
+        text = text.replace(['\n', '\0'], " ");
+        rows.push_str(&format!(
+            "{text}\0icon\x1f{}\x1finfo\x1f{}\x1fmeta\x1f{}\n",
+            icons.get(app_id),
+            win.id,
+            win.marks.join(" ")
+        ));
+    }
+    rows
+}
+
+fn no_matching_window() -> NiriusResponse {
+    NiriusResponse::Error {
+        code: ErrorCode::NoMatchingWindow,
+        message: "No matching window.".to_owned(),
+    }
+}
+
+/// Returns the menu entries of the windows matching the given options, one
+/// per line, or `None` if there are no such windows.  With `icons`, each
+/// entry names the window's icon in rofi's extended dmenu protocol.
+fn get_entries(
+    match_opts: MatchOptions,
+    timeout: Option<Duration>,
+    icons: Option<&Icons>,
+) -> Result<Option<String>, NiriusError> {
+    let wins = get_windows(match_opts, timeout)?;
+    Ok((!wins.is_empty()).then(|| {
+        wins.iter()
+            .map(|w| match (icons, &w.app_id) {
+                (Some(icons), Some(app_id)) => format!(
+                    "{}\0icon\x1f{}\n",
+                    format_entry(w),
+                    icons.get(app_id)
+                ),
+                _ => format_entry(w) + "\n",
+            })
+            .collect()
+    }))
+}
+
+/// Performs the action on the window of the selected entry.  Nothing is done
+/// if nothing has been selected.
+fn act_on_selection(
+    selection: Option<String>,
+    action: MenuAction,
+    timeout: Option<Duration>,
+) -> Result<NiriusResponse, NiriusError> {
+    let Some(selection) = selection else {
+        return Ok(NiriusResponse::Ok(String::new()));
+    };
+    match parse_entry(&selection) {
+        Some(id) => client::send_nirius_cmd(action.cmd(id), timeout),
+        None => Ok(NiriusResponse::Error {
+            code: ErrorCode::BadArguments,
+            message: format!("Not a window: {selection}"),
+        }),
+    }
+}
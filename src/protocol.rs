@@ -0,0 +1,671 @@
+// Copyright (C) 2025  Tassilo Horn <tsdh@gnu.org>
+//
+// This program is free software: you can redistribute it and/or modify it
+// under the terms of the GNU General Public License as published by the Free
+// Software Foundation, either version 3 of the License, or (at your option)
+// any later version.
+//
+// This program is distributed in the hope that it will be useful, but WITHOUT
+// ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or
+// FITNESS FOR A PARTICULAR PURPOSE.  See the GNU General Public License for
+// more details.
+//
+// You should have received a copy of the GNU General Public License along with
+// this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! The protocol between nirius and niriusd.  A client sends a JSON-serialized
+//! [`Request`] wrapping a [`NiriusCmd`](crate::cmds::NiriusCmd), shuts down
+//! its writing half of the socket, and receives a JSON-serialized
+//! [`Response`] wrapping a [`NiriusResponse`].  Long lists are preceded by
+//! [`NiriusResponse::Part`]s of them.
+
+use std::collections::BTreeMap;
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+
+use crate::history::HistoryEntry;
+use crate::stats::Stats;
+use crate::time_tracking::TimeReport;
+use crate::util;
+
+/// The version of the protocol.  It has to be incremented whenever
+/// [`NiriusCmd`](crate::cmds::NiriusCmd) or [`NiriusResponse`] change
+/// incompatibly.
+pub const PROTOCOL_VERSION: u32 = 4;
+
+/// The envelope of a command sent to niriusd.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Request<C> {
+    pub version: u32,
+    pub cmd: C,
+}
+
+/// The envelope of the response niriusd sends back.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Response<R> {
+    pub version: u32,
+    pub response: R,
+}
+
+/// The encoding of requests and responses on the socket.  niriusd answers in
+/// the encoding of the request.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum WireFormat {
+    /// One JSON document per line.
+    #[default]
+    Json,
+    /// MessagePack documents, each preceded by its length as 32 bit
+    /// big-endian integer.
+    #[cfg(feature = "msgpack")]
+    MessagePack,
+}
+
+impl WireFormat {
+    /// Returns the format of a message starting with the given byte.  The
+    /// length of MessagePack messages starts with a zero byte which JSON
+    /// documents never do.
+    pub(crate) fn detect(first_byte: u8) -> Result<WireFormat, String> {
+        match first_byte {
+            #[cfg(feature = "msgpack")]
+            0 => Ok(WireFormat::MessagePack),
+            #[cfg(not(feature = "msgpack"))]
+            0 => Err("niriusd has been built without the msgpack feature."
+                .to_owned()),
+            _ => Ok(WireFormat::Json),
+        }
+    }
+
+    /// Encodes the value as one message.
+    pub(crate) fn encode<T: Serialize>(
+        self,
+        value: &T,
+    ) -> Result<Vec<u8>, String> {
+        match self {
+            WireFormat::Json => {
+                let mut msg =
+                    serde_json::to_vec(value).map_err(|e| e.to_string())?;
+                msg.push(b'\n');
+                Ok(msg)
+            }
+            #[cfg(feature = "msgpack")]
+            WireFormat::MessagePack => {
+                let doc = rmp_serde::to_vec_named(value)
+                    .map_err(|e| e.to_string())?;
+                let len =
+                    u32::try_from(doc.len()).map_err(|e| e.to_string())?;
+                let mut msg = len.to_be_bytes().to_vec();
+                msg.extend(doc);
+                Ok(msg)
+            }
+        }
+    }
+
+    /// Decodes a message without its length.
+    pub(crate) fn decode<T: serde::de::DeserializeOwned>(
+        self,
+        msg: &[u8],
+    ) -> Result<T, String> {
+        match self {
+            WireFormat::Json => {
+                serde_json::from_slice(msg).map_err(|e| e.to_string())
+            }
+            #[cfg(feature = "msgpack")]
+            WireFormat::MessagePack => {
+                rmp_serde::from_slice(msg).map_err(|e| e.to_string())
+            }
+        }
+    }
+}
+
+/// Reads the next message sent in any [`WireFormat`].  Returns `None` at the
+/// end of the stream.
+pub(crate) fn read_message(
+    reader: &mut impl std::io::BufRead,
+) -> std::io::Result<Option<(WireFormat, Vec<u8>)>> {
+    let Some(&first_byte) = reader.fill_buf()?.first() else {
+        return Ok(None);
+    };
+    let format = WireFormat::detect(first_byte)
+        .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?;
+    let mut msg = vec![];
+    match format {
+        WireFormat::Json => {
+            reader.read_until(b'\n', &mut msg)?;
+        }
+        #[cfg(feature = "msgpack")]
+        WireFormat::MessagePack => {
+            let mut len = [0; 4];
+            reader.read_exact(&mut len)?;
+            msg.resize(u32::from_be_bytes(len) as usize, 0);
+            reader.read_exact(&mut msg)?;
+        }
+    }
+    Ok(Some((format, msg)))
+}
+
+/// Returns the error message shown if client and daemon speak different
+/// protocol versions.
+pub fn version_mismatch_message(client: u32, daemon: u32) -> String {
+    format!(
+        "Protocol version mismatch: client v{client} vs daemon v{daemon}, \
+         please restart niriusd."
+    )
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum NiriusResponse {
+    /// The command succeeded with the given human-readable message.
+    Ok(String),
+    /// The command failed.
+    Error {
+        code: ErrorCode,
+        message: String,
+    },
+    /// A single window, e.g., the one `wait-for` waited for.
+    Window(WindowInfo),
+    /// A list of windows, e.g., the ones having some mark.
+    Windows(Vec<WindowInfo>),
+    /// The windows listed by `list-windows` which are printed with their
+    /// annotations.
+    WindowList(Vec<WindowInfo>),
+    /// The daemon's whole state as dumped by `dump-state`.
+    StateDump(serde_json::Value),
+    /// The output of `waybar`.
+    Waybar(WaybarModule),
+    /// A number of windows, e.g., the ones counted by `count`.
+    Count(usize),
+    /// Everything known about a single window as printed by
+    /// `describe-window`.
+    WindowDetails(Box<WindowDetails>),
+    /// Marks with the windows having them.
+    Marks(BTreeMap<String, Vec<WindowInfo>>),
+    /// Workspaces with their windows.
+    Workspaces(Vec<WorkspaceInfo>),
+    Status(Status),
+    Stats(Stats),
+    TimeReport(TimeReport),
+    /// The recent events, commands and timers shown by `history`.
+    History(Vec<HistoryEntry>),
+    /// The responses of the commands of a batch up to the first failing one.
+    Batch(Vec<NiriusResponse>),
+    /// An event sent to clients subscribed with `nirius events`.
+    Event(NiriusEvent),
+    /// A part of a list of windows, marks or workspaces which is sent while
+    /// the list is still being collected.  The response is complete with
+    /// the next response which isn't a part.
+    Part(Box<NiriusResponse>),
+}
+
+/// The events clients can subscribe to with `nirius events`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum NiriusEvent {
+    WindowOpened(WindowInfo),
+    WindowClosed {
+        id: u64,
+    },
+    /// The focused window changed, `None` if no window has focus anymore.
+    WindowFocused {
+        id: Option<u64>,
+    },
+    WorkspaceFocused {
+        id: u64,
+    },
+    /// The marks changed.  Contains all marks with their window ids.
+    MarksChanged(BTreeMap<String, Vec<u64>>),
+    /// The scratchpad changed.  Contains the ids of all scratchpad windows.
+    ScratchpadChanged(Vec<u64>),
+    /// The urgency of windows changed.  Contains the ids of all urgent
+    /// windows.
+    UrgencyChanged(Vec<u64>),
+}
+
+/// The kind of an error so that scripts can react on specific failures.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ErrorCode {
+    /// The command has been executed but failed for some other reason.
+    Failed,
+    /// No window matched the given options.
+    NoMatchingWindow,
+    /// The command acts on the focused window but none has focus.
+    NoFocusedWindow,
+    /// niri couldn't be reached or didn't understand the request.
+    NiriIpc,
+    /// The command had invalid arguments.
+    BadArguments,
+    /// The client couldn't connect to the daemon.  Only reported by the
+    /// client, never sent by the daemon.
+    DaemonUnreachable,
+    /// The daemon couldn't read the command.
+    InvalidRequest,
+    /// The client speaks another protocol version than the daemon.
+    VersionMismatch,
+}
+
+/// An error with its classification.
+#[derive(Debug, Clone)]
+pub struct NiriusError {
+    pub code: ErrorCode,
+    pub message: String,
+}
+
+impl NiriusError {
+    pub fn new(code: ErrorCode, message: impl Into<String>) -> Self {
+        NiriusError {
+            code,
+            message: message.into(),
+        }
+    }
+}
+
+/// Plain error messages are failures without a more specific kind.
+impl From<String> for NiriusError {
+    fn from(message: String) -> Self {
+        NiriusError::new(ErrorCode::Failed, message)
+    }
+}
+
+impl From<&str> for NiriusError {
+    fn from(message: &str) -> Self {
+        NiriusError::new(ErrorCode::Failed, message)
+    }
+}
+
+impl From<NiriusError> for String {
+    fn from(err: NiriusError) -> Self {
+        err.message
+    }
+}
+
+impl std::error::Error for NiriusError {}
+
+impl std::fmt::Display for NiriusError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+/// A window with everything nirius knows about it.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct WindowInfo {
+    pub id: u64,
+    pub app_id: Option<String>,
+    pub title: Option<String>,
+    pub workspace_id: Option<u64>,
+    #[serde(default)]
+    pub workspace_name: Option<String>,
+    #[serde(default)]
+    pub output: Option<String>,
+    pub is_focused: bool,
+    pub is_floating: bool,
+    pub marks: Vec<String>,
+    pub scratchpad: bool,
+    pub follow_mode: bool,
+}
+
+/// Everything nirius knows about a window: what niri tells about it and the
+/// nirius state concerning it.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct WindowDetails {
+    pub window: WindowInfo,
+    pub pid: Option<i32>,
+    pub is_urgent: bool,
+    pub layout: niri_ipc::WindowLayout,
+    /// The position in the focus history, 0 for the most recently focused
+    /// window.
+    pub focus_history_pos: usize,
+    /// The output the window is pinned to with `toggle-pin-output`.
+    pub pinned_output: Option<String>,
+    pub pip: bool,
+    pub minimized: bool,
+}
+
+/// A workspace with its windows.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct WorkspaceInfo {
+    pub id: u64,
+    pub idx: u8,
+    pub name: Option<String>,
+    pub output: Option<String>,
+    pub is_focused: bool,
+    pub is_active: bool,
+    pub windows: Vec<WindowInfo>,
+}
+
+/// The JSON of a waybar custom module with `"return-type": "json"`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct WaybarModule {
+    pub text: String,
+    pub tooltip: String,
+    pub class: Vec<String>,
+}
+
+/// The health of the daemon as reported by `nirius status`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Status {
+    pub uptime_secs: u64,
+    pub niri_version: Option<String>,
+    pub niri_error: Option<String>,
+    pub windows: usize,
+    pub workspaces: usize,
+    pub marks: usize,
+    pub marked_windows: usize,
+    pub scratchpad_windows: usize,
+    pub follow_mode_windows: usize,
+}
+
+impl NiriusResponse {
+    /// Returns a failure response with code [`ErrorCode::Failed`].
+    pub fn failed(message: impl Into<String>) -> Self {
+        NiriusResponse::Error {
+            code: ErrorCode::Failed,
+            message: message.into(),
+        }
+    }
+
+    /// Returns the error code if the command failed or, in the case of
+    /// `status`, if niri isn't reachable so that health checks can rely on
+    /// the exit code.
+    pub fn error_code(&self) -> Option<ErrorCode> {
+        match self {
+            NiriusResponse::Error { code, .. } => Some(*code),
+            NiriusResponse::Status(Status {
+                niri_error: Some(_),
+                ..
+            }) => Some(ErrorCode::NiriIpc),
+            NiriusResponse::Batch(resps) => {
+                resps.iter().find_map(NiriusResponse::error_code)
+            }
+            NiriusResponse::Part(part) => part.error_code(),
+            _ => None,
+        }
+    }
+
+    pub fn is_success(&self) -> bool {
+        self.error_code().is_none()
+    }
+
+    /// Appends the windows, marks or workspaces of `part` to the ones of
+    /// this response.
+    pub(crate) fn append(&mut self, part: NiriusResponse) {
+        match (self, part) {
+            (NiriusResponse::Windows(wins), NiriusResponse::Windows(more))
+            | (
+                NiriusResponse::WindowList(wins),
+                NiriusResponse::WindowList(more),
+            ) => wins.extend(more),
+            (NiriusResponse::Marks(marks), NiriusResponse::Marks(more)) => {
+                for (mark, wins) in more {
+                    marks.entry(mark).or_default().extend(wins);
+                }
+            }
+            (
+                NiriusResponse::Workspaces(workspaces),
+                NiriusResponse::Workspaces(more),
+            ) => workspaces.extend(more),
+            (_, part) => {
+                tracing::warn!("Ignoring unexpected response part {part:?}.")
+            }
+        }
+    }
+
+    /// Converts the response into its text representation, or the error
+    /// message if it isn't successful.
+    pub fn into_result(self) -> Result<String, String> {
+        match self {
+            NiriusResponse::Error { message, .. } => Err(message),
+            resp if resp.is_success() => Ok(resp.format_text()),
+            resp => Err(resp.format_text()),
+        }
+    }
+
+    /// Returns the human-readable representation of the response.
+    pub fn format_text(&self) -> String {
+        match self {
+            NiriusResponse::Ok(message)
+            | NiriusResponse::Error { message, .. } => message.clone(),
+            NiriusResponse::Window(win) => win.id.to_string(),
+            NiriusResponse::Windows(wins) => format_window_list(wins),
+            NiriusResponse::WindowList(wins) => wins
+                .iter()
+                .map(WindowInfo::format_with_location)
+                .collect::<Vec<_>>()
+                .join("\n"),
+            NiriusResponse::Marks(marks) => {
+                let mut str = String::new();
+                for (mark, wins) in marks {
+                    str.push_str(&format!("-> {mark}:\n"));
+                    str.push_str(&format_window_list(wins));
+                }
+                str
+            }
+            NiriusResponse::Workspaces(workspaces) => {
+                let mut str = String::new();
+                for ws in workspaces {
+                    str.push_str(&ws.format());
+                    str.push('\n');
+                    for win in &ws.windows {
+                        str.push_str("  ");
+                        str.push_str(&win.format());
+                        str.push('\n');
+                    }
+                }
+                str
+            }
+            NiriusResponse::Count(count) => count.to_string(),
+            NiriusResponse::Waybar(module) => {
+                serde_json::to_string(module).unwrap_or_default()
+            }
+            NiriusResponse::StateDump(state) => {
+                serde_json::to_string_pretty(state).unwrap_or_default()
+            }
+            NiriusResponse::WindowDetails(details) => details.format(),
+            NiriusResponse::Status(status) => status.format(),
+            NiriusResponse::Stats(stats) => stats.format(),
+            NiriusResponse::TimeReport(report) => report.format(),
+            NiriusResponse::History(entries) => {
+                entries.iter().map(|e| e.format() + "\n").collect()
+            }
+            NiriusResponse::Batch(resps) => resps
+                .iter()
+                .map(|resp| resp.format_text().trim_end().to_owned())
+                .filter(|str| !str.is_empty())
+                .collect::<Vec<_>>()
+                .join("\n"),
+            NiriusResponse::Event(event) => {
+                serde_json::to_string(event).unwrap_or_default()
+            }
+            NiriusResponse::Part(part) => part.format_text(),
+        }
+    }
+
+    /// Returns the JSON representation of the response's data.
+    pub fn format_json(&self) -> Result<String, String> {
+        match self {
+            NiriusResponse::Ok(message)
+            | NiriusResponse::Error { message, .. } => {
+                serde_json::to_string_pretty(message)
+            }
+            NiriusResponse::Window(win) => serde_json::to_string_pretty(win),
+            NiriusResponse::Windows(wins)
+            | NiriusResponse::WindowList(wins) => {
+                serde_json::to_string_pretty(wins)
+            }
+            NiriusResponse::Marks(marks) => serde_json::to_string_pretty(marks),
+            NiriusResponse::Workspaces(workspaces) => {
+                serde_json::to_string_pretty(workspaces)
+            }
+            NiriusResponse::Count(count) => serde_json::to_string(count),
+            NiriusResponse::Waybar(module) => serde_json::to_string(module),
+            NiriusResponse::StateDump(state) => {
+                serde_json::to_string_pretty(state)
+            }
+            NiriusResponse::WindowDetails(details) => {
+                serde_json::to_string_pretty(details)
+            }
+            NiriusResponse::Status(status) => {
+                serde_json::to_string_pretty(status)
+            }
+            NiriusResponse::Stats(stats) => serde_json::to_string_pretty(stats),
+            NiriusResponse::TimeReport(report) => {
+                serde_json::to_string_pretty(report)
+            }
+            NiriusResponse::History(entries) => {
+                serde_json::to_string_pretty(entries)
+            }
+            NiriusResponse::Batch(resps) => serde_json::to_string_pretty(resps),
+            NiriusResponse::Event(event) => serde_json::to_string(event),
+            NiriusResponse::Part(part) => return part.format_json(),
+        }
+        .map_err(|e| e.to_string())
+    }
+}
+
+impl From<String> for NiriusResponse {
+    fn from(message: String) -> Self {
+        NiriusResponse::Ok(message)
+    }
+}
+
+/// Formats the given windows for list commands, one line per window.
+pub(crate) fn format_window_list(wins: &[WindowInfo]) -> String {
+    let mut str = String::new();
+    for win in wins {
+        str.push_str(&format!(
+            "id: {}, app-id: {:?}, title: {:?}, on workspace: {:?}\n",
+            win.id, win.app_id, win.title, win.workspace_id
+        ));
+    }
+    str
+}
+
+impl WindowInfo {
+    /// Formats the window with its nirius annotations such as marks.
+    pub fn format(&self) -> String {
+        let mut str = format!(
+            "id: {}, app-id: {:?}, title: {:?}",
+            self.id, self.app_id, self.title
+        );
+        if !self.marks.is_empty() {
+            str.push_str(&format!(", marks: {}", self.marks.join(" ")));
+        }
+        for (flag, name) in [
+            (self.is_focused, "focused"),
+            (self.is_floating, "floating"),
+            (self.scratchpad, "scratchpad"),
+            (self.follow_mode, "follow-mode"),
+        ] {
+            if flag {
+                str.push_str(", ");
+                str.push_str(name);
+            }
+        }
+        str
+    }
+
+    /// Formats the window like [`WindowInfo::format`] followed by its
+    /// workspace and output.
+    pub fn format_with_location(&self) -> String {
+        let mut str = self.format();
+        if let Some(id) = self.workspace_id {
+            str.push_str(&format!(", on workspace: {id}"));
+            if let Some(name) = &self.workspace_name {
+                str.push_str(&format!(" ({name:?})"));
+            }
+        }
+        if let Some(output) = &self.output {
+            str.push_str(&format!(", on output: {output}"));
+        }
+        str
+    }
+}
+
+impl WindowDetails {
+    fn format(&self) -> String {
+        let win = &self.window;
+        let layout = &self.layout;
+        let mut str = format!(
+            "id: {}\n\
+             app-id: {:?}\n\
+             title: {:?}\n\
+             pid: {:?}\n\
+             workspace: {:?} (name: {:?})\n\
+             output: {:?}\n\
+             focused: {}\n\
+             focus history position: {}\n\
+             floating: {}\n\
+             urgent: {}\n\
+             window size: {}x{}\n\
+             marks: {:?}\n\
+             scratchpad: {}\n\
+             follow-mode: {}\n\
+             pinned to output: {:?}\n\
+             pip: {}\n\
+             minimized: {}",
+            win.id,
+            win.app_id,
+            win.title,
+            self.pid,
+            win.workspace_id,
+            win.workspace_name,
+            win.output,
+            win.is_focused,
+            self.focus_history_pos,
+            win.is_floating,
+            self.is_urgent,
+            layout.window_size.0,
+            layout.window_size.1,
+            win.marks,
+            win.scratchpad,
+            win.follow_mode,
+            self.pinned_output,
+            self.pip,
+            self.minimized,
+        );
+        if let Some((column, tile)) = layout.pos_in_scrolling_layout {
+            str.push_str(&format!("\ncolumn: {column}, tile: {tile}"));
+        }
+        str
+    }
+}
+
+impl WorkspaceInfo {
+    fn format(&self) -> String {
+        let mut str = format!(
+            "workspace id: {}, idx: {}, name: {:?}, on output: {:?}",
+            self.id, self.idx, self.name, self.output
+        );
+        if self.is_focused {
+            str.push_str(", focused");
+        } else if self.is_active {
+            str.push_str(", active");
+        }
+        str
+    }
+}
+
+impl Status {
+    fn format(&self) -> String {
+        format!(
+            "uptime: {}\n\
+             niri: {}\n\
+             windows: {}\n\
+             workspaces: {}\n\
+             marks: {} on {} windows\n\
+             scratchpad windows: {}\n\
+             follow-mode windows: {}",
+            util::format_duration(Duration::from_secs(self.uptime_secs)),
+            match (&self.niri_version, &self.niri_error) {
+                (Some(version), _) => format!("connected, version {version}"),
+                (_, Some(err)) => format!("not reachable: {err}"),
+                _ => "unknown".to_owned(),
+            },
+            self.windows,
+            self.workspaces,
+            self.marks,
+            self.marked_windows,
+            self.scratchpad_windows,
+            self.follow_mode_windows,
+        )
+    }
+}
@@ -0,0 +1,133 @@
+// Copyright (C) 2025  Tassilo Horn <tsdh@gnu.org>
+//
+// This program is free software: you can redistribute it and/or modify it
+// under the terms of the GNU General Public License as published by the Free
+// Software Foundation, either version 3 of the License, or (at your option)
+// any later version.
+//
+// This program is distributed in the hope that it will be useful, but WITHOUT
+// ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or
+// FITNESS FOR A PARTICULAR PURPOSE.  See the GNU General Public License for
+// more details.
+//
+// You should have received a copy of the GNU General Public License along with
+// this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! A user script written in [Rhai](https://rhai.rs) which is notified about
+//! niri events and may execute nirius commands.
+
+use clap::Parser;
+use niri_ipc::Event;
+use rhai::{AST, Dynamic, Engine, EvalAltResult, Scope};
+
+use crate::cmds::{self, NiriusCmd};
+use crate::config::get_config;
+use crate::state;
+
+pub struct ScriptEngine {
+    engine: Engine,
+    ast: AST,
+    scope: Scope<'static>,
+}
+
+/// Parses the given arguments as nirius command line and executes the
+/// command.
+fn exec_nirius_cmd_line<I>(args: I) -> Result<String, Box<EvalAltResult>>
+where
+    I: IntoIterator<Item = String>,
+{
+    let cmd = NiriusCmd::try_parse_from(
+        std::iter::once("nirius".to_owned()).chain(args),
+    )
+    .map_err(|e| e.to_string())?;
+    Ok(cmds::exec_nirius_cmd(cmd).into_result()?)
+}
+
+impl ScriptEngine {
+    /// Loads and runs the script configured in the config file, if any.
+    pub fn load() -> Option<Self> {
+        let path = get_config().script.clone()?;
+        let mut engine = Engine::new();
+        engine.on_print(|s| tracing::info!("Script: {s}"));
+        engine.on_debug(|s, _, pos| tracing::debug!("Script ({pos}): {s}"));
+        engine.register_fn("nirius", |cmd_line: &str| {
+            exec_nirius_cmd_line(cmd_line.split_whitespace().map(str::to_owned))
+        });
+        engine.register_fn("nirius", |args: rhai::Array| {
+            exec_nirius_cmd_line(args.into_iter().map(|a| a.to_string()))
+        });
+
+        let ast = match engine.compile_file(path.clone()) {
+            Ok(ast) => ast,
+            Err(err) => {
+                tracing::error!("Could not compile {}: {err}", path.display());
+                return None;
+            }
+        };
+        let mut scope = Scope::new();
+        if let Err(err) = engine.run_ast_with_scope(&mut scope, &ast) {
+            tracing::error!("Error when running {}: {err}", path.display());
+            return None;
+        }
+        tracing::info!("Loaded script {}.", path.display());
+        Some(ScriptEngine { engine, ast, scope })
+    }
+
+    /// Calls the script function handling the given event if it's defined.
+    pub fn handle_event(&mut self, event: &Event) {
+        let (fn_name, arg) = match event {
+            Event::WindowOpenedOrChanged { window } => {
+                ("on_window_open_or_change", rhai::serde::to_dynamic(window))
+            }
+            Event::WindowClosed { id } => {
+                ("on_window_close", Ok(Dynamic::from(*id as rhai::INT)))
+            }
+            Event::WindowFocusChanged { id: Some(id) } => {
+                let id = *id;
+                match state::with_state(move |state| {
+                    Ok::<_, String>(
+                        state.all_windows.iter().find(|w| w.id == id).cloned(),
+                    )
+                }) {
+                    Ok(Some(win)) => {
+                        ("on_window_focus", rhai::serde::to_dynamic(win))
+                    }
+                    _ => return,
+                }
+            }
+            Event::WorkspaceActivated { id, focused } if *focused => {
+                let id = *id;
+                match state::with_state(move |state| {
+                    Ok::<_, String>(
+                        state
+                            .all_workspaces
+                            .iter()
+                            .find(|ws| ws.id == id)
+                            .cloned(),
+                    )
+                }) {
+                    Ok(Some(ws)) => {
+                        ("on_workspace_focus", rhai::serde::to_dynamic(ws))
+                    }
+                    _ => return,
+                }
+            }
+            _ => return,
+        };
+
+        if !self.ast.iter_functions().any(|f| f.name == fn_name) {
+            return;
+        }
+        let result = arg.and_then(|arg| {
+            self.engine.call_fn::<Dynamic>(
+                &mut self.scope,
+                &self.ast,
+                fn_name,
+                (arg,),
+            )
+        });
+        if let Err(err) = result {
+            tracing::error!("Error in script function {fn_name}: {err}");
+        }
+    }
+}
@@ -14,21 +14,164 @@
 // this program.  If not, see <https://www.gnu.org/licenses/>.
 
 use std::{
-    collections::{HashMap, VecDeque},
-    sync::{LazyLock, RwLock},
+    cell::Cell,
+    collections::{BTreeMap, BTreeSet, HashMap, VecDeque},
+    sync::{Condvar, LazyLock, Mutex, PoisonError, mpsc},
 };
 
 use niri_ipc::{Window, Workspace};
+use serde::{Deserialize, Serialize};
 
+use crate::cmds::MatchOptions;
+use crate::events;
+use crate::protocol::{WindowDetails, WindowInfo};
+use crate::{undo, util};
+
+#[derive(Serialize)]
 pub struct State {
     pub all_windows: VecDeque<Window>,
     pub all_workspaces: Vec<Workspace>,
     pub follow_mode_win_ids: Vec<u64>,
+    /// The urgent windows, the one which became urgent most recently last.
+    pub urgent_win_ids: Vec<u64>,
     pub scratchpad_win_ids: Vec<u64>,
     pub mark_to_win_ids: HashMap<String, Vec<u64>>,
+    /// Window groups created with `nirius group create` which may be empty.
+    pub group_to_win_ids: HashMap<String, Vec<u64>>,
+    pub pinned_win_id_to_output: HashMap<u64, String>,
+    pub pip_win_id_to_origin: HashMap<u64, PipOrigin>,
+    /// The last known geometry of windows while they were floating.
+    pub floating_win_id_to_geometry: HashMap<u64, FloatingGeometry>,
+    /// Maps workspaces in zen mode to the windows stashed away from them.
+    pub zen_ws_id_to_stashed_win_ids: HashMap<u64, Vec<u64>>,
+    /// The window shown with `nirius present`.
+    pub presentation: Option<Presentation>,
+    /// The minimized windows, the most recently minimized one last.
+    pub minimized_windows: Vec<MinimizedWindow>,
+    pub keyboard_layout_idx: Option<u8>,
+    pub win_id_to_keyboard_layout_idx: HashMap<u64, u8>,
+    pub previous_workspace_id: Option<u64>,
+    /// The ids of focused workspaces, the oldest first.
+    pub workspace_history: Vec<u64>,
+    /// The index of the current workspace in `workspace_history`.
+    pub workspace_history_pos: usize,
+    /// The workspaces named by nirius with the names it gave them.
+    pub auto_named_ws_id_to_name: HashMap<u64, String>,
+    /// Placements for spawned windows which haven't appeared yet.
+    pub pending_placements: Vec<PendingPlacement>,
+    /// Terminals hidden while the windows they launched exist.
+    pub swallowed_windows: Vec<SwallowedWindow>,
+    /// Commands registered with `nirius watch`.
+    pub watchers: Vec<Watcher>,
+    pub next_watcher_id: u64,
+}
+
+/// A terminal window hidden because it launched the window `child_id`.
+#[derive(Serialize)]
+pub struct SwallowedWindow {
+    pub id: u64,
+    pub child_id: u64,
+    pub workspace_id: u64,
+}
+
+/// A command run for every new window matching `match_opts`.
+#[derive(Serialize)]
+pub struct Watcher {
+    pub id: u64,
+    pub match_opts: MatchOptions,
+    pub command: Vec<String>,
+}
+
+/// Where a window matching `match_opts` should go once it appears.
+#[derive(Serialize)]
+pub struct PendingPlacement {
+    pub match_opts: MatchOptions,
+    pub workspace: String,
+    pub floating: bool,
+}
+
+const MAX_WORKSPACE_HISTORY_LEN: usize = 100;
+
+/// Where a window has been before it was minimized.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct MinimizedWindow {
+    pub id: u64,
+    pub workspace_id: u64,
+    pub was_floating: bool,
+}
+
+/// Where a window presented with `nirius present` came from.
+#[derive(Serialize)]
+pub struct Presentation {
+    pub id: u64,
+    pub workspace_id: u64,
+    pub was_floating: bool,
+    pub was_fullscreen: bool,
+}
+
+/// What a window looked like before it was put into picture-in-picture mode.
+#[derive(Serialize)]
+pub struct PipOrigin {
+    pub was_floating: bool,
+    pub was_following: bool,
+}
+
+/// The size and position of a floating window on its workspace.
+#[derive(Clone, Copy, Serialize)]
+pub struct FloatingGeometry {
+    pub x: f64,
+    pub y: f64,
+    pub width: i32,
+    pub height: i32,
 }
 
 impl State {
+    fn new() -> Self {
+        State {
+            all_windows: VecDeque::new(),
+            all_workspaces: Vec::new(),
+            follow_mode_win_ids: vec![],
+            urgent_win_ids: vec![],
+            scratchpad_win_ids: vec![],
+            mark_to_win_ids: HashMap::new(),
+            group_to_win_ids: HashMap::new(),
+            pinned_win_id_to_output: HashMap::new(),
+            pip_win_id_to_origin: HashMap::new(),
+            floating_win_id_to_geometry: HashMap::new(),
+            zen_ws_id_to_stashed_win_ids: HashMap::new(),
+            presentation: None,
+            minimized_windows: vec![],
+            keyboard_layout_idx: None,
+            win_id_to_keyboard_layout_idx: HashMap::new(),
+            previous_workspace_id: None,
+            workspace_history: vec![],
+            workspace_history_pos: 0,
+            auto_named_ws_id_to_name: HashMap::new(),
+            pending_placements: vec![],
+            swallowed_windows: vec![],
+            watchers: vec![],
+            next_watcher_id: 1,
+        }
+    }
+
+    /// Returns a state which only knows niri's windows and workspaces.  It's
+    /// used for executing commands without the daemon.
+    pub(crate) fn from_niri(
+        wins: Vec<Window>,
+        workspaces: Vec<Workspace>,
+    ) -> Result<Self, String> {
+        let mut state = State::new();
+        state.workspaces_changed(workspaces)?;
+        // Register the focused window last so that it's the most recently
+        // focused one.
+        let (focused, others): (Vec<_>, Vec<_>) =
+            wins.into_iter().partition(|w| w.is_focused);
+        for win in others.into_iter().chain(focused) {
+            state.register_window(win)?;
+        }
+        Ok(state)
+    }
+
     pub fn get_focused_win_id(&self) -> Option<u64> {
         self.all_windows.iter().find(|w| w.is_focused).map(|w| w.id)
     }
@@ -44,7 +187,56 @@ impl State {
             .map(|w| w.id)
     }
 
+    /// Returns the sorted marks of the window with the given id.
+    pub fn get_window_marks(&self, id: u64) -> Vec<&str> {
+        let mut marks: Vec<&str> = self
+            .mark_to_win_ids
+            .iter()
+            .filter(|(_, ids)| ids.contains(&id))
+            .map(|(mark, _)| mark.as_str())
+            .collect();
+        marks.sort();
+        marks
+    }
+
+    /// Records whether the window with the given id is urgent.  Returns true
+    /// if it has just become urgent.
+    pub fn set_urgent(&mut self, id: u64, urgent: bool) -> bool {
+        if !urgent {
+            self.urgent_win_ids.retain(|i| *i != id);
+            false
+        } else if !self.urgent_win_ids.contains(&id) {
+            self.urgent_win_ids.push(id);
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Remembers the geometry of the given window if it's floating.  Pip
+    /// windows are skipped so that they get their previous geometry back
+    /// once pip mode is disabled.
+    pub fn remember_floating_geometry(&mut self, win: &Window) {
+        if !win.is_floating || self.pip_win_id_to_origin.contains_key(&win.id) {
+            return;
+        }
+        if let Some((x, y)) = win.layout.tile_pos_in_workspace_view {
+            let (width, height) = win.layout.window_size;
+            self.floating_win_id_to_geometry.insert(
+                win.id,
+                FloatingGeometry {
+                    x,
+                    y,
+                    width,
+                    height,
+                },
+            );
+        }
+    }
+
     pub fn register_window(&mut self, win: Window) -> Result<String, String> {
+        self.set_urgent(win.id, win.is_urgent);
+        self.remember_floating_geometry(&win);
         if let Some(idx) = self.all_windows.iter().position(|w| w.id == win.id)
         {
             if win.is_focused {
@@ -70,10 +262,26 @@ impl State {
     pub fn remove_window(&mut self, id: &u64) -> Result<String, String> {
         self.all_windows.retain(|w| w.id != *id);
         self.follow_mode_win_ids.retain(|i| i != id);
+        self.urgent_win_ids.retain(|i| i != id);
         self.scratchpad_win_ids.retain(|i| i != id);
         for v in self.mark_to_win_ids.values_mut() {
             v.retain(|i| i != id);
         }
+        for v in self.group_to_win_ids.values_mut() {
+            v.retain(|i| i != id);
+        }
+        self.pinned_win_id_to_output.remove(id);
+        self.pip_win_id_to_origin.remove(id);
+        self.floating_win_id_to_geometry.remove(id);
+        for v in self.zen_ws_id_to_stashed_win_ids.values_mut() {
+            v.retain(|i| i != id);
+        }
+        self.minimized_windows.retain(|m| m.id != *id);
+        if self.presentation.as_ref().is_some_and(|p| p.id == *id) {
+            self.presentation = None;
+        }
+        self.swallowed_windows.retain(|s| s.id != *id);
+        self.win_id_to_keyboard_layout_idx.remove(id);
         Ok(format!(
             "Removed window with id {id}. Currently managing {} windows.",
             self.all_windows.len()
@@ -115,13 +323,67 @@ impl State {
         workspaces: Vec<Workspace>,
     ) -> Result<String, String> {
         self.all_workspaces = workspaces;
+
+        // Forget about workspaces which don't exist anymore.
+        let history = &mut self.workspace_history;
+        let current = history.get(self.workspace_history_pos).copied();
+        let all_workspaces = &self.all_workspaces;
+        history.retain(|id| all_workspaces.iter().any(|ws| ws.id == *id));
+        history.dedup();
+        self.workspace_history_pos = current
+            .and_then(|c| history.iter().position(|id| *id == c))
+            .unwrap_or(history.len().saturating_sub(1));
+
         Ok("Updated all workspaces.".to_owned())
     }
 
+    pub fn keyboard_layouts_changed(
+        &mut self,
+        current_idx: u8,
+    ) -> Result<String, String> {
+        // The indices refer to the old layouts, so they are meaningless now.
+        self.win_id_to_keyboard_layout_idx.clear();
+        self.keyboard_layout_idx = Some(current_idx);
+        Ok("Updated keyboard layouts.".to_owned())
+    }
+
+    pub fn keyboard_layout_switched(
+        &mut self,
+        idx: u8,
+    ) -> Result<String, String> {
+        self.keyboard_layout_idx = Some(idx);
+        if let Some(id) = self.get_focused_win_id() {
+            self.win_id_to_keyboard_layout_idx.insert(id, idx);
+            Ok(format!("Remembered keyboard layout {idx} for window {id}."))
+        } else {
+            Ok(format!("Switched to keyboard layout {idx}."))
+        }
+    }
+
     pub fn workspace_focused(&mut self, id: u64) {
+        if let Some(current) = self.get_focused_workspace_id()
+            && current != id
+        {
+            self.previous_workspace_id = Some(current);
+        }
         for ws in &mut self.all_workspaces {
             ws.is_focused = ws.id == id;
         }
+        self.record_workspace_history(id);
+    }
+
+    fn record_workspace_history(&mut self, id: u64) {
+        let pos = self.workspace_history_pos;
+        if self.workspace_history.get(pos) == Some(&id) {
+            // That's the result of navigating the history.
+            return;
+        }
+        self.workspace_history.truncate(pos.saturating_add(1));
+        self.workspace_history.push(id);
+        if self.workspace_history.len() > MAX_WORKSPACE_HISTORY_LEN {
+            self.workspace_history.remove(0);
+        }
+        self.workspace_history_pos = self.workspace_history.len() - 1;
     }
 
     pub fn get_focused_workspace(&self) -> Option<&Workspace> {
@@ -132,6 +394,18 @@ impl State {
         self.get_focused_workspace().map(|ws| ws.id)
     }
 
+    pub fn get_workspace_output(&self, ws_id: u64) -> Option<&str> {
+        self.all_workspaces
+            .iter()
+            .find(|ws| ws.id == ws_id)
+            .and_then(|ws| ws.output.as_deref())
+    }
+
+    pub fn get_window_output(&self, win: &Window) -> Option<&str> {
+        win.workspace_id
+            .and_then(|ws_id| self.get_workspace_output(ws_id))
+    }
+
     pub fn get_bottom_workspace_id_and_idx_of_output(
         &self,
         output: &str,
@@ -143,29 +417,422 @@ impl State {
             .map(|ws| (ws.id, ws.idx))
     }
 
+    pub fn get_active_workspace_of_output(
+        &self,
+        output: &str,
+    ) -> Option<&Workspace> {
+        self.all_workspaces.iter().find(|ws| {
+            ws.is_active && ws.output.as_ref().is_some_and(|o| o == output)
+        })
+    }
+
+    pub fn get_outputs(&self) -> Vec<&str> {
+        let mut outputs: Vec<&str> = self
+            .all_workspaces
+            .iter()
+            .filter_map(|ws| ws.output.as_deref())
+            .collect();
+        outputs.sort();
+        outputs.dedup();
+        outputs
+    }
+
+    pub fn get_first_empty_workspace_id_of_output(
+        &self,
+        output: &str,
+    ) -> Option<u64> {
+        self.all_workspaces
+            .iter()
+            .filter(|ws| {
+                ws.output.as_ref().is_some_and(|o| o == output)
+                    && !self
+                        .all_windows
+                        .iter()
+                        .any(|w| w.workspace_id == Some(ws.id))
+            })
+            .min_by_key(|ws| ws.idx)
+            .map(|ws| ws.id)
+    }
+
     pub fn is_bottom_workspace_focused(&self) -> bool {
-        if let Some(ws) = self.get_focused_workspace() {
-            let (_, ws_idx) = self
-                .get_bottom_workspace_id_and_idx_of_output(
-                    ws.output.as_ref().expect("Workspace without output."),
-                )
-                .expect("No bottom but a focused workspace.");
-            // It's the bottom workspace if the max index of all workspaces on
-            // the same output is this workspace's index + 1 because there is
-            // always one empty workspace at the bottom.
-            ws.idx + 1 == ws_idx
-        } else {
-            false
+        let Some(ws) = self.get_focused_workspace() else {
+            return false;
+        };
+        // It's the bottom workspace if the max index of all workspaces on the
+        // same output is this workspace's index + 1 because there is always
+        // one empty workspace at the bottom.
+        ws.output
+            .as_ref()
+            .and_then(|o| self.get_bottom_workspace_id_and_idx_of_output(o))
+            .is_some_and(|(_, ws_idx)| ws.idx + 1 == ws_idx)
+    }
+
+    /// Returns the given window with everything nirius knows about it.
+    pub fn window_info(&self, win: &Window) -> WindowInfo {
+        let ws = win
+            .workspace_id
+            .and_then(|id| self.all_workspaces.iter().find(|ws| ws.id == id));
+        WindowInfo {
+            id: win.id,
+            app_id: win.app_id.clone(),
+            title: win.title.clone(),
+            workspace_id: win.workspace_id,
+            workspace_name: ws.and_then(|ws| ws.name.clone()),
+            output: ws.and_then(|ws| ws.output.clone()),
+            is_focused: win.is_focused,
+            is_floating: win.is_floating,
+            marks: self
+                .get_window_marks(win.id)
+                .into_iter()
+                .map(str::to_owned)
+                .collect(),
+            scratchpad: self.scratchpad_win_ids.contains(&win.id),
+            follow_mode: self.follow_mode_win_ids.contains(&win.id),
+        }
+    }
+
+    pub fn window_details(&self, win: &Window) -> WindowDetails {
+        WindowDetails {
+            window: self.window_info(win),
+            pid: win.pid,
+            is_urgent: win.is_urgent,
+            layout: win.layout.clone(),
+            focus_history_pos: self
+                .all_windows
+                .iter()
+                .rev()
+                .position(|w| w.id == win.id)
+                .unwrap_or_default(),
+            pinned_output: self.pinned_win_id_to_output.get(&win.id).cloned(),
+            pip: self.pip_win_id_to_origin.contains_key(&win.id),
+            minimized: self.minimized_windows.iter().any(|m| m.id == win.id),
+        }
+    }
+
+    /// Saves the nirius-specific window state such as marks and the
+    /// scratchpad so that a restarted daemon can pick it up again.
+    pub fn save(&self) -> Result<(), String> {
+        let persisted = PersistedState {
+            niri_socket: util::get_niri_socket_path().ok(),
+            follow_mode_win_ids: self.follow_mode_win_ids.clone(),
+            scratchpad_win_ids: self.scratchpad_win_ids.clone(),
+            mark_to_win_ids: self.mark_to_win_ids.clone(),
+            group_to_win_ids: self.group_to_win_ids.clone(),
+            pinned_win_id_to_output: self.pinned_win_id_to_output.clone(),
+            minimized_windows: self.minimized_windows.clone(),
+        };
+        let path = get_persisted_state_file_path()?;
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir).map_err(|e| e.to_string())?;
+        }
+        let file = std::fs::File::create(&path).map_err(|e| e.to_string())?;
+        serde_json::to_writer_pretty(file, &persisted)
+            .map_err(|e| e.to_string())
+    }
+
+    /// Restores the state saved by [`State::save`] for all windows which
+    /// still exist.  The saved state is only used once and only if it has
+    /// been saved during the current niri session because window ids aren't
+    /// stable across sessions.
+    pub fn restore(&mut self) -> Result<(), String> {
+        let path = get_persisted_state_file_path()?;
+        let file = match std::fs::File::open(&path) {
+            Ok(file) => file,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => {
+                return Ok(());
+            }
+            Err(err) => {
+                return Err(format!(
+                    "Could not open {}: {err}",
+                    path.display()
+                ));
+            }
+        };
+        let persisted: Result<PersistedState, _> =
+            serde_json::from_reader(file);
+        if let Err(err) = std::fs::remove_file(&path) {
+            tracing::warn!("Could not delete {}: {err}", path.display());
+        }
+        let persisted = persisted.map_err(|e| e.to_string())?;
+        if persisted.niri_socket != util::get_niri_socket_path().ok() {
+            tracing::debug!("Ignoring state saved in another niri session.");
+            return Ok(());
+        }
+
+        let exists = |id: &u64| self.all_windows.iter().any(|w| w.id == *id);
+        let mut follow_mode_win_ids = persisted.follow_mode_win_ids;
+        follow_mode_win_ids.retain(exists);
+        let mut scratchpad_win_ids = persisted.scratchpad_win_ids;
+        scratchpad_win_ids.retain(exists);
+        let mut mark_to_win_ids = persisted.mark_to_win_ids;
+        for ids in mark_to_win_ids.values_mut() {
+            ids.retain(exists);
+        }
+        mark_to_win_ids.retain(|_, ids| !ids.is_empty());
+        // Groups are kept even if empty like freshly created ones.
+        let mut group_to_win_ids = persisted.group_to_win_ids;
+        for ids in group_to_win_ids.values_mut() {
+            ids.retain(exists);
+        }
+        let mut pinned_win_id_to_output = persisted.pinned_win_id_to_output;
+        pinned_win_id_to_output.retain(|id, _| exists(id));
+        let mut minimized_windows = persisted.minimized_windows;
+        minimized_windows.retain(|m| exists(&m.id));
+
+        self.follow_mode_win_ids = follow_mode_win_ids;
+        self.scratchpad_win_ids = scratchpad_win_ids;
+        self.mark_to_win_ids = mark_to_win_ids;
+        self.group_to_win_ids = group_to_win_ids;
+        self.pinned_win_id_to_output = pinned_win_id_to_output;
+        self.minimized_windows = minimized_windows;
+        Ok(())
+    }
+
+    /// Adds the marks, scratchpad and follow-mode assignments of a
+    /// `dump-state` dump.  Windows which don't exist anymore are
+    /// re-associated with a current window of the same app-id, preferably
+    /// one with the same title.
+    pub fn import(&mut self, import: &StateImport) -> String {
+        let mut id_map: HashMap<u64, u64> = HashMap::new();
+        let mut taken: Vec<u64> = vec![];
+        // Windows with unchanged ids first so that they aren't taken by
+        // others.
+        for old in &import.all_windows {
+            if self
+                .all_windows
+                .iter()
+                .any(|w| w.id == old.id && w.app_id == old.app_id)
+            {
+                id_map.insert(old.id, old.id);
+                taken.push(old.id);
+            }
+        }
+        for old in &import.all_windows {
+            if id_map.contains_key(&old.id) {
+                continue;
+            }
+            let candidates = || {
+                self.all_windows.iter().filter(|w| {
+                    w.app_id == old.app_id && !taken.contains(&w.id)
+                })
+            };
+            let new = candidates()
+                .find(|w| w.title == old.title)
+                .or_else(|| candidates().next())
+                .map(|w| w.id);
+            if let Some(new) = new {
+                id_map.insert(old.id, new);
+                taken.push(new);
+            }
+        }
+
+        let mut imported = BTreeSet::new();
+        let mut lost = BTreeSet::new();
+        let mut map_ids = |ids: &[u64]| -> Vec<u64> {
+            ids.iter()
+                .filter_map(|id| match id_map.get(id) {
+                    Some(new) => {
+                        imported.insert(*new);
+                        Some(*new)
+                    }
+                    None => {
+                        lost.insert(*id);
+                        None
+                    }
+                })
+                .collect()
+        };
+        for id in map_ids(&import.follow_mode_win_ids) {
+            if !self.follow_mode_win_ids.contains(&id) {
+                self.follow_mode_win_ids.push(id);
+            }
+        }
+        for id in map_ids(&import.scratchpad_win_ids) {
+            if !self.scratchpad_win_ids.contains(&id) {
+                self.scratchpad_win_ids.push(id);
+            }
         }
+        for (mark, ids) in &import.mark_to_win_ids {
+            for id in map_ids(ids) {
+                let marked =
+                    self.mark_to_win_ids.entry(mark.clone()).or_default();
+                if !marked.contains(&id) {
+                    marked.push(id);
+                }
+            }
+        }
+        let mut msg = format!(
+            "Imported marks, scratchpad and follow-mode of {} windows.",
+            imported.len()
+        );
+        if !lost.is_empty() {
+            msg.push_str(&format!(
+                "  No window found for {} windows of the dump.",
+                lost.len()
+            ));
+        }
+        msg
     }
 }
 
-pub static STATE: LazyLock<RwLock<State>> = LazyLock::new(|| {
-    RwLock::new(State {
-        all_windows: VecDeque::new(),
-        all_workspaces: Vec::new(),
-        follow_mode_win_ids: vec![],
-        scratchpad_win_ids: vec![],
-        mark_to_win_ids: HashMap::new(),
-    })
+/// The parts of a `dump-state` dump which `import-state` restores.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct StateImport {
+    pub all_windows: Vec<ImportedWindow>,
+    #[serde(default)]
+    pub follow_mode_win_ids: Vec<u64>,
+    #[serde(default)]
+    pub scratchpad_win_ids: Vec<u64>,
+    #[serde(default)]
+    pub mark_to_win_ids: BTreeMap<String, Vec<u64>>,
+}
+
+/// A window of a `dump-state` dump.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ImportedWindow {
+    pub id: u64,
+    pub app_id: Option<String>,
+    pub title: Option<String>,
+}
+
+/// The parts of the [`State`] which survive a restart of the daemon.
+#[derive(Serialize, Deserialize)]
+struct PersistedState {
+    /// Identifies the niri session the state belongs to.
+    niri_socket: Option<String>,
+    follow_mode_win_ids: Vec<u64>,
+    scratchpad_win_ids: Vec<u64>,
+    mark_to_win_ids: HashMap<String, Vec<u64>>,
+    #[serde(default)]
+    group_to_win_ids: HashMap<String, Vec<u64>>,
+    pinned_win_id_to_output: HashMap<u64, String>,
+    minimized_windows: Vec<MinimizedWindow>,
+}
+
+fn get_persisted_state_file_path() -> Result<std::path::PathBuf, String> {
+    Ok(util::get_nirius_state_dir()?.join("daemon-state.json"))
+}
+
+/// A job run by the state actor with exclusive access to the state.
+type StateJob = Box<dyn FnOnce(&mut State) + Send>;
+
+thread_local! {
+    static IS_STATE_ACTOR: Cell<bool> = const { Cell::new(false) };
+}
+
+/// The channel to the state actor, a thread which owns the [`State`] and
+/// runs the jobs it receives one after the other.  Started on first use.
+static STATE_ACTOR: LazyLock<mpsc::Sender<StateJob>> = LazyLock::new(|| {
+    let (tx, rx) = mpsc::channel::<StateJob>();
+    // Jobs run hooks and schedule timers which need the daemon's runtime.
+    let runtime = tokio::runtime::Handle::try_current().ok();
+    std::thread::Builder::new()
+        .name("state".to_owned())
+        .spawn(move || {
+            let _guard = runtime.as_ref().map(|rt| rt.enter());
+            IS_STATE_ACTOR.set(true);
+            let mut state = State::new();
+            let mut published = events::Published::default();
+            for job in rx {
+                // A panicking job must not take the state actor down.
+                if let Err(err) = std::panic::catch_unwind(
+                    std::panic::AssertUnwindSafe(|| job(&mut state)),
+                ) {
+                    tracing::error!("Panic in state job: {err:?}");
+                }
+                published.publish_changes(&state);
+            }
+        })
+        .expect("Could not spawn the state actor.");
+    tx
 });
+
+/// Runs `f` with exclusive access to the state on the state actor and returns
+/// its result.  Jobs are run one after the other in the order they have been
+/// sent, so `f` must not block for longer than necessary.  Calling this
+/// function from within `f` is an error because it would deadlock.
+pub fn with_state<F, T, E>(f: F) -> Result<T, E>
+where
+    F: FnOnce(&mut State) -> Result<T, E> + Send + 'static,
+    T: Send + 'static,
+    E: From<String> + Send + 'static,
+{
+    if IS_STATE_ACTOR.get() {
+        tracing::error!("Nested with_state() call!");
+        return Err("Internal error: nested state access.".to_owned().into());
+    }
+    let (tx, rx) = mpsc::sync_channel(1);
+    // Log messages of the job belong to the caller's request or event, and
+    // so do the window moves it might undo.
+    let span = tracing::Span::current();
+    let group = undo::current_group();
+    STATE_ACTOR
+        .send(Box::new(move |state| {
+            let _entered = span.enter();
+            // The receiver only goes away if the caller is gone.
+            let _ = tx.send(undo::with_group(group, || f(state)));
+        }))
+        .map_err(|_| "The state actor has stopped.".to_owned())?;
+    rx.recv()
+        .map_err(|_| "The state job has failed.".to_owned())?
+}
+
+/// Counts window changes so that clients can wait for windows to appear.
+pub static WINDOWS_CHANGED: LazyLock<(Mutex<u64>, Condvar)> =
+    LazyLock::new(|| (Mutex::new(0), Condvar::new()));
+
+/// Wakes up everyone waiting on [`WINDOWS_CHANGED`].  Must not be called
+/// from within [`with_state`].
+pub fn notify_windows_changed() {
+    let (count, cvar) = &*WINDOWS_CHANGED;
+    *count.lock().unwrap_or_else(PoisonError::into_inner) += 1;
+    cvar.notify_all();
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use niri_ipc::WindowLayout;
+
+    use super::*;
+
+    /// Returns a tiled window on the given workspace.
+    pub(crate) fn window(id: u64, workspace_id: u64) -> Window {
+        Window {
+            id,
+            title: Some(format!("window {id}")),
+            app_id: Some("foot".to_owned()),
+            pid: None,
+            workspace_id: Some(workspace_id),
+            is_focused: false,
+            is_floating: false,
+            is_urgent: false,
+            layout: WindowLayout {
+                pos_in_scrolling_layout: Some((id as usize, 1)),
+                tile_size: (800.0, 600.0),
+                window_size: (800, 600),
+                tile_pos_in_workspace_view: None,
+                window_offset_in_tile: (0.0, 0.0),
+            },
+        }
+    }
+
+    /// Returns the workspace with the given id on output `DP-1`.
+    pub(crate) fn workspace(id: u64, is_focused: bool) -> Workspace {
+        Workspace {
+            id,
+            idx: id as u8,
+            name: None,
+            output: Some("DP-1".to_owned()),
+            is_urgent: false,
+            is_active: is_focused,
+            is_focused,
+            active_window_id: None,
+        }
+    }
+
+    /// Returns a state with the given windows on focused workspace 1.
+    pub(crate) fn state(wins: Vec<Window>) -> State {
+        State::from_niri(wins, vec![workspace(1, true), workspace(2, false)])
+            .unwrap()
+    }
+}
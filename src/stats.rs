@@ -0,0 +1,175 @@
+// Copyright (C) 2025  Tassilo Horn <tsdh@gnu.org>
+//
+// This program is free software: you can redistribute it and/or modify it
+// under the terms of the GNU General Public License as published by the Free
+// Software Foundation, either version 3 of the License, or (at your option)
+// any later version.
+//
+// This program is distributed in the hope that it will be useful, but WITHOUT
+// ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or
+// FITNESS FOR A PARTICULAR PURPOSE.  See the GNU General Public License for
+// more details.
+//
+// You should have received a copy of the GNU General Public License along with
+// this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! Counters and latency histograms of the daemon shown by `nirius stats`.
+
+use std::collections::BTreeMap;
+use std::sync::{Mutex, PoisonError};
+use std::time::Duration;
+
+use niri_ipc::Event;
+use serde::{Deserialize, Serialize};
+
+/// The upper bounds of the histogram buckets in microseconds.  The last
+/// bucket counts everything slower.
+const BUCKET_BOUNDS_US: [u64; 5] = [100, 1_000, 10_000, 100_000, 1_000_000];
+
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct Histogram {
+    count: u64,
+    total_us: u64,
+    max_us: u64,
+    /// The counts per bucket, see [`BUCKET_BOUNDS_US`].
+    buckets: [u64; BUCKET_BOUNDS_US.len() + 1],
+}
+
+impl Histogram {
+    fn record(&mut self, elapsed: Duration) {
+        let us = u64::try_from(elapsed.as_micros()).unwrap_or(u64::MAX);
+        self.count += 1;
+        self.total_us = self.total_us.saturating_add(us);
+        self.max_us = self.max_us.max(us);
+        let idx = BUCKET_BOUNDS_US
+            .iter()
+            .position(|bound| us <= *bound)
+            .unwrap_or(BUCKET_BOUNDS_US.len());
+        self.buckets[idx] += 1;
+    }
+
+    fn format(&self) -> String {
+        let avg_us = self.total_us.checked_div(self.count).unwrap_or(0);
+        let buckets: Vec<String> = self
+            .buckets
+            .iter()
+            .enumerate()
+            .map(|(i, n)| match BUCKET_BOUNDS_US.get(i) {
+                Some(bound) => format!("<={} {n}", format_bound(*bound)),
+                None => {
+                    format!(">{} {n}", format_bound(BUCKET_BOUNDS_US[i - 1]))
+                }
+            })
+            .collect();
+        format!(
+            "count {}, avg {}, max {} ({})",
+            self.count,
+            format_us(avg_us),
+            format_us(self.max_us),
+            buckets.join(", ")
+        )
+    }
+}
+
+fn format_bound(us: u64) -> String {
+    if us.is_multiple_of(1_000_000) {
+        format!("{}s", us / 1_000_000)
+    } else if us.is_multiple_of(1_000) {
+        format!("{}ms", us / 1_000)
+    } else {
+        format!("{us}µs")
+    }
+}
+
+fn format_us(us: u64) -> String {
+    if us >= 1_000_000 {
+        format!("{:.2}s", us as f64 / 1_000_000.0)
+    } else if us >= 1_000 {
+        format!("{:.2}ms", us as f64 / 1_000.0)
+    } else {
+        format!("{us}µs")
+    }
+}
+
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct Stats {
+    /// The upper bounds of the histogram buckets in microseconds.
+    bucket_bounds_us: Vec<u64>,
+    /// Executed commands by command name.
+    commands: BTreeMap<String, Histogram>,
+    /// Handled niri events by event name.
+    events: BTreeMap<String, Histogram>,
+    /// Round-trips of queries to niri.
+    niri_queries: Histogram,
+}
+
+static STATS: Mutex<Option<Stats>> = Mutex::new(None);
+
+fn with_stats<T>(f: impl FnOnce(&mut Stats) -> T) -> T {
+    let mut stats = STATS.lock().unwrap_or_else(PoisonError::into_inner);
+    f(stats.get_or_insert_with(Stats::default))
+}
+
+/// Returns the name of the event's variant like `WindowOpenedOrChanged`.
+pub fn event_name(event: &Event) -> &'static str {
+    match event {
+        Event::WorkspacesChanged { .. } => "WorkspacesChanged",
+        Event::WorkspaceUrgencyChanged { .. } => "WorkspaceUrgencyChanged",
+        Event::WorkspaceActivated { .. } => "WorkspaceActivated",
+        Event::WorkspaceActiveWindowChanged { .. } => {
+            "WorkspaceActiveWindowChanged"
+        }
+        Event::WindowsChanged { .. } => "WindowsChanged",
+        Event::WindowOpenedOrChanged { .. } => "WindowOpenedOrChanged",
+        Event::WindowClosed { .. } => "WindowClosed",
+        Event::WindowFocusChanged { .. } => "WindowFocusChanged",
+        Event::WindowUrgencyChanged { .. } => "WindowUrgencyChanged",
+        Event::WindowLayoutsChanged { .. } => "WindowLayoutsChanged",
+        Event::KeyboardLayoutsChanged { .. } => "KeyboardLayoutsChanged",
+        Event::KeyboardLayoutSwitched { .. } => "KeyboardLayoutSwitched",
+        Event::OverviewOpenedOrClosed { .. } => "OverviewOpenedOrClosed",
+        Event::ConfigLoaded { .. } => "ConfigLoaded",
+    }
+}
+
+pub fn record_command(name: &str, elapsed: Duration) {
+    with_stats(|s| {
+        s.commands
+            .entry(name.to_owned())
+            .or_default()
+            .record(elapsed)
+    });
+}
+
+pub fn record_event(event: &Event, elapsed: Duration) {
+    let name = event_name(event);
+    with_stats(|s| {
+        s.events.entry(name.to_owned()).or_default().record(elapsed)
+    });
+}
+
+pub fn record_niri_query(elapsed: Duration) {
+    with_stats(|s| s.niri_queries.record(elapsed));
+}
+
+/// Returns the current statistics.
+pub fn snapshot() -> Stats {
+    let mut stats = with_stats(|s| s.clone());
+    stats.bucket_bounds_us = BUCKET_BOUNDS_US.to_vec();
+    stats
+}
+
+impl Stats {
+    pub fn format(&self) -> String {
+        let mut str = format!("niri queries: {}\n", self.niri_queries.format());
+        for (title, histograms) in
+            [("commands", &self.commands), ("events", &self.events)]
+        {
+            str.push_str(&format!("{title}:\n"));
+            for (name, histogram) in histograms {
+                str.push_str(&format!("  {name}: {}\n", histogram.format()));
+            }
+        }
+        str
+    }
+}
@@ -0,0 +1,273 @@
+// Copyright (C) 2025  Tassilo Horn <tsdh@gnu.org>
+//
+// This program is free software: you can redistribute it and/or modify it
+// under the terms of the GNU General Public License as published by the Free
+// Software Foundation, either version 3 of the License, or (at your option)
+// any later version.
+//
+// This program is distributed in the hope that it will be useful, but WITHOUT
+// ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or
+// FITNESS FOR A PARTICULAR PURPOSE.  See the GNU General Public License for
+// more details.
+//
+// You should have received a copy of the GNU General Public License along with
+// this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! Tracking how long windows of each app-id had focus for `nirius
+//! time-report`.  With `track_focus_time` enabled, the daemon appends every
+//! period a window had focus as line `<start> <end> <app-id>` with UNIX
+//! timestamps in seconds to `focus-time.log` in nirius' state directory.
+//! The periods are buffered and written by [`flush`] so that focus changes
+//! don't wait for the disk.
+
+use std::collections::HashMap;
+use std::io::Write;
+use std::sync::{Mutex, PoisonError};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+
+use crate::util::format_duration;
+
+const LOG_FILE: &str = "focus-time.log";
+
+/// How often the daemon writes the buffered periods to the log.
+pub const FLUSH_INTERVAL: Duration = Duration::from_secs(60);
+
+/// If the log grows larger, periods older than [`MAX_AGE_SECS`] are dropped
+/// and, if that's not enough, the oldest ones until half of it is left.
+const MAX_LOG_BYTES: u64 = 1024 * 1024;
+
+const MAX_AGE_SECS: u64 = 90 * 24 * 60 * 60;
+
+/// The app-id of the focused window and since when it has focus.
+struct Period {
+    app_id: String,
+    start: u64,
+}
+
+static CURRENT: Mutex<Option<Period>> = Mutex::new(None);
+
+/// The periods which have ended but aren't written to the log yet, with
+/// their end.
+static PENDING: Mutex<Vec<(Period, u64)>> = Mutex::new(vec![]);
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+/// Records that a window with the given app-id has focus now, or no window
+/// if `None`.  The period of the window which had focus before is buffered
+/// for the log if its app-id differs.
+pub fn focus_changed(app_id: Option<String>) {
+    let mut current = CURRENT.lock().unwrap_or_else(PoisonError::into_inner);
+    if current.as_ref().map(|p| &p.app_id) == app_id.as_ref() {
+        return;
+    }
+    let now = now();
+    if let Some(period) = current.take()
+        && period.start < now
+    {
+        PENDING
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .push((period, now));
+    }
+    *current = app_id.map(|app_id| Period { app_id, start: now });
+}
+
+/// Appends the buffered periods to the log.  It does file IO, so it must not
+/// be called from the state actor.
+pub fn flush() {
+    // Flushes by the timer and on shutdown mustn't interleave.
+    static WRITING: Mutex<()> = Mutex::new(());
+    let _writing = WRITING.lock().unwrap_or_else(PoisonError::into_inner);
+    let pending = std::mem::take(
+        &mut *PENDING.lock().unwrap_or_else(PoisonError::into_inner),
+    );
+    if pending.is_empty() {
+        return;
+    }
+    if let Err(err) = append_to_log(&pending) {
+        tracing::warn!("Could not log focus time: {err}");
+    }
+}
+
+fn append_to_log(periods: &[(Period, u64)]) -> Result<(), String> {
+    let dir = crate::util::get_nirius_state_dir()?;
+    std::fs::create_dir_all(&dir).map_err(|e| e.to_string())?;
+    let path = dir.join(LOG_FILE);
+    let mut file = std::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(&path)
+        .map_err(|e| e.to_string())?;
+    let mut lines = String::new();
+    for (period, end) in periods {
+        lines += &format!("{} {end} {}\n", period.start, period.app_id);
+    }
+    file.write_all(lines.as_bytes())
+        .map_err(|e| e.to_string())?;
+    let size = file.metadata().map_err(|e| e.to_string())?.len();
+    if size > MAX_LOG_BYTES {
+        truncate_log(&path)?;
+    }
+    Ok(())
+}
+
+/// Drops old periods from the log, see [`MAX_LOG_BYTES`].
+fn truncate_log(path: &std::path::Path) -> Result<(), String> {
+    let log = std::fs::read_to_string(path).map_err(|e| e.to_string())?;
+    let min_end = now().saturating_sub(MAX_AGE_SECS);
+    let mut lines: Vec<&str> = log
+        .lines()
+        .filter(|line| {
+            let end = line.split(' ').nth(1).and_then(|s| s.parse().ok());
+            end.is_some_and(|end: u64| end >= min_end)
+        })
+        .collect();
+    let mut size: usize = lines.iter().map(|l| l.len() + 1).sum();
+    let max_size = MAX_LOG_BYTES as usize / 2;
+    let mut skip = 0;
+    if size > MAX_LOG_BYTES as usize {
+        while size > max_size && skip < lines.len() {
+            size -= lines[skip].len() + 1;
+            skip += 1;
+        }
+    }
+    lines.drain(..skip);
+    let content: String = lines.iter().map(|l| format!("{l}\n")).collect();
+    let tmp = path.with_extension("log.tmp");
+    std::fs::write(&tmp, content).map_err(|e| e.to_string())?;
+    std::fs::rename(&tmp, path).map_err(|e| e.to_string())
+}
+
+/// How long the windows of an app-id had focus.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AppTime {
+    pub app_id: String,
+    pub secs: u64,
+}
+
+/// The focus times per app-id since some point in time, the longest first.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TimeReport {
+    pub since: u64,
+    pub apps: Vec<AppTime>,
+}
+
+impl TimeReport {
+    pub fn format(&self) -> String {
+        if self.apps.is_empty() {
+            return "No focus time recorded.".to_owned();
+        }
+        let width = self.apps.iter().map(|a| a.app_id.len()).max();
+        let width = width.unwrap_or_default().max("total".len());
+        let mut str = String::new();
+        for app in &self.apps {
+            let app_id = if app.app_id.is_empty() {
+                "?"
+            } else {
+                &app.app_id
+            };
+            str.push_str(&format!(
+                "{app_id:<width$}  {}\n",
+                format_duration(Duration::from_secs(app.secs))
+            ));
+        }
+        let total = self.apps.iter().map(|a| a.secs).sum();
+        str.push_str(&format!(
+            "{:<width$}  {}\n",
+            "total",
+            format_duration(Duration::from_secs(total))
+        ));
+        str
+    }
+}
+
+/// Returns the focus times since the given point in time, see
+/// [`parse_since`].
+pub fn report(since: &str) -> Result<TimeReport, String> {
+    let since = parse_since(since)?;
+    let mut secs: HashMap<String, u64> = HashMap::new();
+    let mut add = |app_id: &str, start: u64, end: u64| {
+        if end > since {
+            *secs.entry(app_id.to_owned()).or_default() +=
+                end - start.max(since);
+        }
+    };
+
+    let path = crate::util::get_nirius_state_dir()?.join(LOG_FILE);
+    match std::fs::read_to_string(&path) {
+        Ok(log) => {
+            for line in log.lines() {
+                let mut parts = line.splitn(3, ' ');
+                let start = parts.next().and_then(|s| s.parse().ok());
+                let end = parts.next().and_then(|s| s.parse().ok());
+                match (start, end) {
+                    (Some(start), Some(end)) => {
+                        add(parts.next().unwrap_or(""), start, end)
+                    }
+                    _ => tracing::warn!("Invalid line in {LOG_FILE}: {line}"),
+                }
+            }
+        }
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => (),
+        Err(err) => {
+            return Err(format!("Cannot read {}: {err}", path.display()));
+        }
+    }
+    for (period, end) in PENDING
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner)
+        .iter()
+    {
+        add(&period.app_id, period.start, *end);
+    }
+    if let Some(period) = CURRENT
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner)
+        .as_ref()
+    {
+        add(&period.app_id, period.start, now());
+    }
+
+    let mut apps: Vec<AppTime> = secs
+        .into_iter()
+        .filter(|(_, secs)| *secs > 0)
+        .map(|(app_id, secs)| AppTime { app_id, secs })
+        .collect();
+    apps.sort_by(|a, b| b.secs.cmp(&a.secs).then(a.app_id.cmp(&b.app_id)));
+    Ok(TimeReport { since, apps })
+}
+
+/// Parses `today`, `yesterday`, or a duration like `2h` or `7d` into the
+/// UNIX timestamp of the start of the day or the time that long ago.
+pub fn parse_since(s: &str) -> Result<u64, String> {
+    let now = now();
+    match s.trim() {
+        "today" => local_midnight(now, 0),
+        "yesterday" => local_midnight(now, 1),
+        s => Ok(now.saturating_sub(crate::util::parse_duration(s)?.as_secs())),
+    }
+}
+
+/// Returns the UNIX timestamp of the local midnight starting the day the
+/// given number of days before the one of `now`.
+fn local_midnight(now: u64, days_back: i32) -> Result<u64, String> {
+    let mut tm = crate::util::local_time(now)
+        .ok_or("Cannot determine the local time.")?;
+    tm.tm_hour = 0;
+    tm.tm_min = 0;
+    tm.tm_sec = 0;
+    tm.tm_mday -= days_back;
+    // Let mktime figure out whether daylight saving time applies.
+    tm.tm_isdst = -1;
+    // SAFETY: tm is valid, and mktime normalizes the day of the month.
+    let midnight = unsafe { libc::mktime(&mut tm) };
+    u64::try_from(midnight)
+        .map_err(|_| "Cannot determine the local midnight.".to_owned())
+}
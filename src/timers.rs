@@ -0,0 +1,93 @@
+// Copyright (C) 2025  Tassilo Horn <tsdh@gnu.org>
+//
+// This program is free software: you can redistribute it and/or modify it
+// under the terms of the GNU General Public License as published by the Free
+// Software Foundation, either version 3 of the License, or (at your option)
+// any later version.
+//
+// This program is distributed in the hope that it will be useful, but WITHOUT
+// ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or
+// FITNESS FOR A PARTICULAR PURPOSE.  See the GNU General Public License for
+// more details.
+//
+// You should have received a copy of the GNU General Public License along with
+// this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! Delayed and recurring jobs which run as tasks of the daemon's tokio
+//! runtime.
+
+use std::sync::{Arc, Mutex, PoisonError};
+use std::time::Duration;
+
+use tokio::task::AbortHandle;
+use tokio::time::Instant;
+
+use crate::history;
+
+pub type Job = Arc<dyn Fn() -> Result<String, String> + Send + Sync>;
+
+/// The names and handles of the scheduled timers.
+static TIMERS: Mutex<Vec<(String, AbortHandle)>> = Mutex::new(vec![]);
+
+/// Schedules the given job to run after `delay` and then every `every` if
+/// given.  The name is used for logging and cancelling.  Must be called from
+/// within the daemon's runtime.
+pub fn schedule(
+    delay: Duration,
+    every: Option<Duration>,
+    name: &str,
+    job: Job,
+) {
+    let task_name = name.to_owned();
+    let handle = tokio::spawn(async move {
+        let mut due = Instant::now() + delay;
+        loop {
+            tokio::time::sleep_until(due).await;
+            let job = job.clone();
+            let span = tracing::info_span!("timer", name = task_name);
+            // Jobs usually query niri or wait for the state actor, so they
+            // must not block the runtime.
+            let result =
+                tokio::task::spawn_blocking(move || span.in_scope(|| job()))
+                    .await
+                    .unwrap_or_else(|err| Err(format!("Failed: {err}")));
+            match &result {
+                Ok(msg) => tracing::info!("Ran timer {task_name}: {msg}"),
+                Err(err) => {
+                    tracing::error!("Error in timer {task_name}: {err}")
+                }
+            }
+            history::record(
+                history::Source::Timer,
+                &format_args!("{task_name}"),
+                &result,
+            );
+            match every {
+                Some(every) => due += every,
+                None => break,
+            }
+        }
+    });
+
+    let mut timers = TIMERS.lock().unwrap_or_else(PoisonError::into_inner);
+    timers.retain(|(_, h)| !h.is_finished());
+    timers.push((name.to_owned(), handle.abort_handle()));
+}
+
+/// Cancels all timers whose name satisfies the given predicate.
+pub fn cancel<F>(predicate: F)
+where
+    F: Fn(&str) -> bool,
+{
+    TIMERS
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner)
+        .retain(|(name, handle)| {
+            if predicate(name) {
+                handle.abort();
+                false
+            } else {
+                true
+            }
+        });
+}
@@ -0,0 +1,252 @@
+// Copyright (C) 2025  Tassilo Horn <tsdh@gnu.org>
+//
+// This program is free software: you can redistribute it and/or modify it
+// under the terms of the GNU General Public License as published by the Free
+// Software Foundation, either version 3 of the License, or (at your option)
+// any later version.
+//
+// This program is distributed in the hope that it will be useful, but WITHOUT
+// ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or
+// FITNESS FOR A PARTICULAR PURPOSE.  See the GNU General Public License for
+// more details.
+//
+// You should have received a copy of the GNU General Public License along with
+// this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! Undoing the window moves of nirius with `nirius undo`.  Everything a
+//! command, event or timer does is a group.  Before nirius moves a window or
+//! changes whether it floats within a group, the window's workspace and
+//! floating state are recorded, and `undo` restores them for the most recent
+//! group.
+
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Mutex, PoisonError};
+
+use niri_ipc::{Action, Request, Response, Window, WorkspaceReferenceArg};
+
+use crate::ipc;
+use crate::protocol::NiriusError;
+use crate::state::State;
+
+/// How many groups can be undone.
+const MAX_STEPS: usize = 50;
+
+/// Where a window was before nirius moved it.
+struct WindowPos {
+    id: u64,
+    workspace_id: Option<u64>,
+    is_floating: bool,
+}
+
+/// The windows moved by one command, event or timer.
+struct Step {
+    group: u64,
+    label: String,
+    windows: Vec<WindowPos>,
+}
+
+static STEPS: Mutex<VecDeque<Step>> = Mutex::new(VecDeque::new());
+static NEXT_GROUP: AtomicU64 = AtomicU64::new(1);
+
+/// A command, event or timer whose moves are undone together.
+#[derive(Debug, Clone)]
+pub struct Group {
+    id: u64,
+    label: String,
+}
+
+thread_local! {
+    /// The group whose moves are recorded on this thread.
+    static GROUP: RefCell<Option<Group>> = const { RefCell::new(None) };
+}
+
+/// Runs `f` in a new group named `label` unless the thread is already in
+/// one, e.g., when a batch executes its commands.
+pub fn in_group<T>(label: String, f: impl FnOnce() -> T) -> T {
+    if current_group().is_some() {
+        return f();
+    }
+    let id = NEXT_GROUP.fetch_add(1, Ordering::Relaxed);
+    with_group(Some(Group { id, label }), f)
+}
+
+/// Returns the group of the thread so that jobs running elsewhere on its
+/// behalf can join it with [`with_group`].
+pub fn current_group() -> Option<Group> {
+    GROUP.with_borrow(Clone::clone)
+}
+
+/// Runs `f` in the given group, or in none.
+pub fn with_group<T>(group: Option<Group>, f: impl FnOnce() -> T) -> T {
+    let prev = GROUP.replace(group);
+    let result = f();
+    GROUP.set(prev);
+    result
+}
+
+/// Records where the window the action moves is if the thread is in a group
+/// and the action moves a window to another workspace or output, or changes
+/// whether it floats.  The window is looked up in the state rather than
+/// queried from niri.
+pub(crate) fn before_action(action: &Action, state: &State) {
+    let Some(group) = current_group() else {
+        return;
+    };
+    let id = match action {
+        Action::MoveWindowToWorkspace { window_id: id, .. }
+        | Action::MoveWindowToMonitor { id, .. }
+        | Action::MoveWindowToFloating { id }
+        | Action::MoveWindowToTiling { id }
+        | Action::ToggleWindowFloating { id } => *id,
+        _ => return,
+    };
+    let Some(win) = state.all_windows.iter().find(|w| match id {
+        Some(id) => w.id == id,
+        None => w.is_focused,
+    }) else {
+        return;
+    };
+
+    let mut steps = STEPS.lock().unwrap_or_else(PoisonError::into_inner);
+    if steps.back().is_none_or(|s| s.group != group.id) {
+        if steps.len() == MAX_STEPS {
+            steps.pop_front();
+        }
+        steps.push_back(Step {
+            group: group.id,
+            label: group.label,
+            windows: vec![],
+        });
+    }
+    let Some(step) = steps.back_mut() else {
+        return;
+    };
+    // Only the position before the group's first move matters.
+    if !step.windows.iter().any(|w| w.id == win.id) {
+        step.windows.push(WindowPos {
+            id: win.id,
+            workspace_id: win.workspace_id,
+            is_floating: win.is_floating,
+        });
+    }
+}
+
+/// Moves the windows of the most recent group back to where they were.
+pub fn undo() -> Result<String, NiriusError> {
+    let step = STEPS
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner)
+        .pop_back()
+        .ok_or("Nothing to undo.")?;
+    // Restoring mustn't be recorded itself.
+    with_group(None, || restore(step))
+}
+
+fn restore(step: Step) -> Result<String, NiriusError> {
+    let wins: Vec<Window> = match ipc::query_niri(Request::Windows)? {
+        Response::Windows(wins) => wins,
+        x => return Err(ipc::unexpected_reply(x)),
+    };
+    let mut restored = 0;
+    for pos in step.windows.iter().rev() {
+        let Some(win) = wins.iter().find(|w| w.id == pos.id) else {
+            continue;
+        };
+        if let Some(ws_id) = pos.workspace_id
+            && win.workspace_id != Some(ws_id)
+        {
+            crate::cmds::exec_niri_action(Action::MoveWindowToWorkspace {
+                window_id: Some(win.id),
+                reference: WorkspaceReferenceArg::Id(ws_id),
+                focus: false,
+            })?;
+        }
+        if win.is_floating != pos.is_floating {
+            crate::cmds::exec_niri_action(if pos.is_floating {
+                Action::MoveWindowToFloating { id: Some(win.id) }
+            } else {
+                Action::MoveWindowToTiling { id: Some(win.id) }
+            })?;
+        }
+        restored += 1;
+    }
+    Ok(format!(
+        "Undid {}: restored {restored} of {} windows.",
+        step.label,
+        step.windows.len()
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::state::tests::{state, window};
+
+    fn move_to_workspace(id: u64, ws_id: u64) -> Action {
+        Action::MoveWindowToWorkspace {
+            window_id: Some(id),
+            reference: WorkspaceReferenceArg::Id(ws_id),
+            focus: false,
+        }
+    }
+
+    /// The label of a step with the id, workspace and floating state of its
+    /// windows.
+    type RecordedStep = (String, Vec<(u64, Option<u64>, bool)>);
+
+    fn recorded() -> Vec<RecordedStep> {
+        STEPS
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|s| {
+                let wins = s
+                    .windows
+                    .iter()
+                    .map(|w| (w.id, w.workspace_id, w.is_floating))
+                    .collect();
+                (s.label.clone(), wins)
+            })
+            .collect()
+    }
+
+    // Everything is checked in one test because the steps are global.
+    #[test]
+    fn before_action_records_first_positions() {
+        let mut state = state(vec![window(1, 1), window(2, 1)]);
+        state.window_focus_changed(Some(2)).unwrap();
+
+        // Outside of groups, nothing is recorded.
+        before_action(&move_to_workspace(1, 2), &state);
+        assert!(recorded().is_empty());
+
+        in_group("move".to_owned(), || {
+            before_action(&move_to_workspace(1, 2), &state);
+            state.all_windows[0].workspace_id = Some(2);
+            before_action(&move_to_workspace(1, 1), &state);
+            // Without id, the focused window is moved.
+            before_action(&Action::MoveWindowToFloating { id: None }, &state);
+            // Actions which don't move windows aren't recorded.
+            before_action(&Action::CloseWindow { id: Some(1) }, &state);
+        });
+        assert_eq!(
+            recorded(),
+            [(
+                "move".to_owned(),
+                vec![(1, Some(1), false), (2, Some(1), false)]
+            )]
+        );
+
+        for i in 0..MAX_STEPS {
+            in_group(format!("step {i}"), || {
+                before_action(&move_to_workspace(2, 2), &state);
+            });
+        }
+        let steps = recorded();
+        assert_eq!(steps.len(), MAX_STEPS);
+        assert_eq!(steps[0].0, "step 0");
+        assert_eq!(steps[MAX_STEPS - 1].0, format!("step {}", MAX_STEPS - 1));
+    }
+}
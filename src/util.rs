@@ -13,7 +13,53 @@
 // You should have received a copy of the GNU General Public License along with
 // this program.  If not, see <https://www.gnu.org/licenses/>.
 
+use std::sync::OnceLock;
+
+/// The socket path given by `--socket`.
+static SOCKET_PATH_OVERRIDE: OnceLock<String> = OnceLock::new();
+
+/// Makes [`get_nirius_socket_path`] return the given path instead of the one
+/// derived from the environment.  Only the first call has an effect.
+pub fn set_nirius_socket_path(path: String) {
+    let _ = SOCKET_PATH_OVERRIDE.set(path);
+}
+
+/// The niri socket given by `--niri-socket`.
+static NIRI_SOCKET_OVERRIDE: OnceLock<String> = OnceLock::new();
+
+/// The environment variable selecting the niri socket like `--niri-socket`.
+const NIRI_SOCKET_OVERRIDE_ENV: &str = "NIRIUS_NIRI_SOCKET";
+
+/// Makes niriusd connect to the niri instance listening on the given socket
+/// instead of the one in `NIRI_SOCKET`.  Only the first call has an effect.
+pub fn set_niri_socket_path(path: String) {
+    let _ = NIRI_SOCKET_OVERRIDE.set(path);
+}
+
+/// Returns the niri socket explicitly selected using `--niri-socket` or
+/// `NIRIUS_NIRI_SOCKET`.
+pub(crate) fn get_niri_socket_override() -> Option<String> {
+    NIRI_SOCKET_OVERRIDE
+        .get()
+        .cloned()
+        .or_else(|| std::env::var(NIRI_SOCKET_OVERRIDE_ENV).ok())
+}
+
+/// Returns the socket of the niri instance to talk to.
+pub fn get_niri_socket_path() -> Result<String, String> {
+    get_niri_socket_override().map(Ok).unwrap_or_else(|| {
+        std::env::var(niri_ipc::socket::SOCKET_PATH_ENV)
+            .map_err(|e| format!("{}: {e}", niri_ipc::socket::SOCKET_PATH_ENV))
+    })
+}
+
+/// Returns the socket niriusd listens on.  It's named after the wayland
+/// display, or after the niri socket if one has been selected explicitly so
+/// that daemons for several niri instances can coexist.
 pub fn get_nirius_socket_path() -> String {
+    if let Some(path) = SOCKET_PATH_OVERRIDE.get() {
+        return path.clone();
+    }
     // TODO: Is the comment below still accurrate?  I think so.  At least in
     // directories 6.0.0 it is still checked that the runtime dir is absolute.
     // That might be dictated by the spec but it's not really important for
@@ -24,22 +70,267 @@ pub fn get_nirius_socket_path() -> String {
     // directories errors if the XDG_RUNTIME_DIR isn't set or set to a relative
     // path which actually works fine for us.
     let xdg_runtime_dir = std::env::var("XDG_RUNTIME_DIR");
-    let wayland_display = std::env::var("WAYLAND_DISPLAY");
+    let instance = match get_niri_socket_override() {
+        Some(niri_socket) => std::path::Path::new(&niri_socket)
+            .file_stem()
+            .map(|stem| stem.to_string_lossy().into_owned())
+            .unwrap_or(niri_socket),
+        None => match std::env::var("WAYLAND_DISPLAY") {
+            Ok(val) => val,
+            Err(_e) => {
+                tracing::error!("Couldn't get WAYLAND_DISPLAY!");
+                String::from("unknown")
+            }
+        },
+    };
     format!(
-        "{}/nirius-{}.sock",
+        "{}/nirius-{instance}.sock",
         match xdg_runtime_dir {
             Ok(val) => val,
             Err(_e) => {
-                log::error!("Couldn't get XDG_RUNTIME_DIR!");
+                tracing::error!("Couldn't get XDG_RUNTIME_DIR!");
                 String::from("/tmp")
             }
         },
-        match wayland_display {
-            Ok(val) => val,
-            Err(_e) => {
-                log::error!("Couldn't get WAYLAND_DISPLAY!");
-                String::from("unknown")
+    )
+}
+
+/// Returns the directory where nirius persists state across daemon restarts,
+/// i.e., `$XDG_STATE_HOME/nirius`.
+pub fn get_nirius_state_dir() -> Result<std::path::PathBuf, String> {
+    let dirs = directories::BaseDirs::new()
+        .ok_or("Couldn't determine the home directory.")?;
+    Ok(dirs
+        .state_dir()
+        .unwrap_or(dirs.data_local_dir())
+        .join("nirius"))
+}
+
+/// Returns the local time of the given UNIX timestamp in seconds.
+pub(crate) fn local_time(secs: u64) -> Option<libc::tm> {
+    let time = libc::time_t::try_from(secs).ok()?;
+    // SAFETY: tm is plain old data which localtime_r fills in.
+    let mut tm: libc::tm = unsafe { std::mem::zeroed() };
+    // SAFETY: Both pointers are valid for the duration of the call.
+    let result = unsafe { libc::localtime_r(&time, &mut tm) };
+    (!result.is_null()).then_some(tm)
+}
+
+/// Formats the UNIX timestamp in seconds as local time like `2025-06-01
+/// 13:37:00`.
+pub(crate) fn format_local_time(secs: u64) -> String {
+    match local_time(secs) {
+        Some(tm) => format!(
+            "{}-{:02}-{:02} {:02}:{:02}:{:02}",
+            tm.tm_year + 1900,
+            tm.tm_mon + 1,
+            tm.tm_mday,
+            tm.tm_hour,
+            tm.tm_min,
+            tm.tm_sec
+        ),
+        None => secs.to_string(),
+    }
+}
+
+/// Parses durations like `500ms`, `30s`, `5m`, `1h` or `7d`.  A plain number is
+/// interpreted as seconds.
+pub fn parse_duration(s: &str) -> Result<std::time::Duration, String> {
+    let s = s.trim();
+    let idx = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+    let (num, unit) = s.split_at(idx);
+    let num: u64 = num
+        .parse()
+        .map_err(|_| format!("Invalid duration {s:?}."))?;
+    let factor = match unit.trim() {
+        "ms" => 1,
+        "" | "s" => 1000,
+        "m" => 60 * 1000,
+        "h" => 60 * 60 * 1000,
+        "d" => 24 * 60 * 60 * 1000,
+        _ => return Err(format!("Invalid duration unit in {s:?}.")),
+    };
+    let millis = num
+        .checked_mul(factor)
+        .ok_or_else(|| format!("Duration {s:?} is too long."))?;
+    Ok(std::time::Duration::from_millis(millis))
+}
+
+/// Splits command lines like `focus -t 'Foo Bar' ; toggle-mark` into commands
+/// and their arguments.  Commands are separated by `;`, and arguments can be
+/// quoted with single or double quotes or escaped with a backslash.
+pub fn split_command_lines(s: &str) -> Result<Vec<Vec<String>>, String> {
+    let mut cmds = vec![];
+    let mut args = vec![];
+    let mut arg: Option<String> = None;
+    let mut chars = s.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\'' | '"' => {
+                let arg = arg.get_or_insert_with(String::new);
+                loop {
+                    match chars.next() {
+                        Some(q) if q == c => break,
+                        Some('\\') if c == '"' => arg.extend(chars.next()),
+                        Some(x) => arg.push(x),
+                        None => {
+                            return Err(format!(
+                                "Unterminated quote in {s:?}."
+                            ));
+                        }
+                    }
+                }
+            }
+            '\\' => arg.get_or_insert_with(String::new).extend(chars.next()),
+            ';' => {
+                args.extend(arg.take());
+                if !args.is_empty() {
+                    cmds.push(std::mem::take(&mut args));
+                }
             }
+            c if c.is_whitespace() => args.extend(arg.take()),
+            c => arg.get_or_insert_with(String::new).push(c),
         }
-    )
+    }
+    args.extend(arg.take());
+    if !args.is_empty() {
+        cmds.push(args);
+    }
+    Ok(cmds)
+}
+
+/// Replaces `${VAR}` with the value of the environment variable `VAR`.
+/// References to variables which aren't set are kept, and `$${` yields a
+/// literal `${`.
+pub fn expand_env_vars(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    let mut rest = s;
+    while let Some(idx) = rest.find('$') {
+        out.push_str(&rest[..idx]);
+        rest = &rest[idx..];
+        if let Some(r) = rest.strip_prefix("$${") {
+            out.push_str("${");
+            rest = r;
+        } else if let Some(r) = rest.strip_prefix("${")
+            && let Some(end) = r.find('}')
+            && let Ok(value) = std::env::var(&r[..end])
+        {
+            out.push_str(&value);
+            rest = &r[end + 1..];
+        } else {
+            out.push('$');
+            rest = &rest[1..];
+        }
+    }
+    out.push_str(rest);
+    out
+}
+
+/// Formats a duration like `2h 5m 30s` with second precision.
+pub fn format_duration(d: std::time::Duration) -> String {
+    let secs = d.as_secs();
+    let (h, m, s) = (secs / 3600, secs / 60 % 60, secs % 60);
+    if h > 0 {
+        format!("{h}h {m}m {s}s")
+    } else if m > 0 {
+        format!("{m}m {s}s")
+    } else {
+        format!("{s}s")
+    }
+}
+
+/// Returns the parent process id of the given process by reading
+/// `/proc/<pid>/stat`.
+pub fn get_parent_pid(pid: i32) -> Option<i32> {
+    let stat = std::fs::read_to_string(format!("/proc/{pid}/stat")).ok()?;
+    // The command name in parentheses may contain spaces, so the fields after
+    // it are split.  They are the state and then the parent pid.
+    let (_, rest) = stat.rsplit_once(')')?;
+    rest.split_whitespace().nth(1)?.parse().ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    #[test]
+    fn parse_duration_units() {
+        assert_eq!(parse_duration("500ms"), Ok(Duration::from_millis(500)));
+        assert_eq!(parse_duration("30"), Ok(Duration::from_secs(30)));
+        assert_eq!(parse_duration("30s"), Ok(Duration::from_secs(30)));
+        assert_eq!(parse_duration(" 5m "), Ok(Duration::from_secs(300)));
+        assert_eq!(parse_duration("1h"), Ok(Duration::from_secs(3600)));
+        assert_eq!(parse_duration("7d"), Ok(Duration::from_secs(604800)));
+        assert_eq!(parse_duration("0s"), Ok(Duration::ZERO));
+    }
+
+    #[test]
+    fn parse_duration_invalid() {
+        assert!(parse_duration("").is_err());
+        assert!(parse_duration("s").is_err());
+        assert!(parse_duration("-5s").is_err());
+        assert!(parse_duration("5w").is_err());
+        assert!(parse_duration("1.5h").is_err());
+    }
+
+    #[test]
+    fn parse_duration_overflow() {
+        assert!(parse_duration("18446744073709551615d").is_err());
+        assert!(parse_duration("18446744073709551615ms").is_ok());
+    }
+
+    fn cmds(cmds: &[&[&str]]) -> Result<Vec<Vec<String>>, String> {
+        Ok(cmds
+            .iter()
+            .map(|args| args.iter().map(|a| a.to_string()).collect())
+            .collect())
+    }
+
+    #[test]
+    fn split_command_lines_separators() {
+        assert_eq!(
+            split_command_lines("focus -a foot; toggle-mark"),
+            cmds(&[&["focus", "-a", "foot"], &["toggle-mark"]])
+        );
+        assert_eq!(split_command_lines(" ;a;; b ;"), cmds(&[&["a"], &["b"]]));
+        assert_eq!(split_command_lines("  "), cmds(&[]));
+    }
+
+    #[test]
+    fn split_command_lines_quoting() {
+        assert_eq!(
+            split_command_lines(r#"focus -t 'Foo Bar' -a "a\"b;c""#),
+            cmds(&[&["focus", "-t", "Foo Bar", "-a", "a\"b;c"]])
+        );
+        assert_eq!(
+            split_command_lines(r"a\ b\;c 'x\y' ''"),
+            cmds(&[&["a b;c", "x\\y", ""]])
+        );
+        assert!(split_command_lines("focus -t 'Foo").is_err());
+        assert!(split_command_lines(r#"focus -t "Foo\""#).is_err());
+    }
+
+    // Cargo sets `CARGO_PKG_NAME` when running the tests, too.
+    #[test]
+    fn expand_env_vars_set_and_unset() {
+        assert_eq!(expand_env_vars("${CARGO_PKG_NAME}.toml"), "nirius.toml");
+        assert_eq!(
+            expand_env_vars("a ${CARGO_PKG_NAME} b ${CARGO_PKG_NAME}"),
+            "a nirius b nirius"
+        );
+        assert_eq!(
+            expand_env_vars("${NIRIUS_UNSET_TEST_VAR}/x"),
+            "${NIRIUS_UNSET_TEST_VAR}/x"
+        );
+    }
+
+    #[test]
+    fn expand_env_vars_escapes_and_plain_dollars() {
+        assert_eq!(expand_env_vars("$${CARGO_PKG_NAME}"), "${CARGO_PKG_NAME}");
+        assert_eq!(expand_env_vars("$$5 $HOME $"), "$$5 $HOME $");
+        assert_eq!(expand_env_vars("${CARGO_PKG_NAME"), "${CARGO_PKG_NAME");
+        assert_eq!(expand_env_vars("€${}"), "€${}");
+    }
 }
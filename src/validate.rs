@@ -0,0 +1,466 @@
+// Copyright (C) 2025  Tassilo Horn <tsdh@gnu.org>
+//
+// This program is free software: you can redistribute it and/or modify it
+// under the terms of the GNU General Public License as published by the Free
+// Software Foundation, either version 3 of the License, or (at your option)
+// any later version.
+//
+// This program is distributed in the hope that it will be useful, but WITHOUT
+// ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or
+// FITNESS FOR A PARTICULAR PURPOSE.  See the GNU General Public License for
+// more details.
+//
+// You should have received a copy of the GNU General Public License along with
+// this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! Checking the config with `nirius validate-config` without having to dig
+//! through the daemon log.
+
+use std::path::{Path, PathBuf};
+
+use clap::{CommandFactory, Parser};
+use regex::Regex;
+use serde::Deserialize;
+use serde::de::Visitor;
+
+use crate::cmds::{MatchOptions, NiriusCmd};
+use crate::config::{
+    self, AutoNameWorkspacesConfig, Config, HooksConfig, OutputProfile,
+    PipConfig, Preset, Project, RuleAction, SwallowConfig, TimerConfig,
+};
+use crate::util;
+
+/// Checks the config file and its drop-in files, or only the given file.
+/// Returns a summary if the config is valid, or all problems found.
+pub fn validate_config(path: Option<&Path>) -> Result<String, String> {
+    let files = match path {
+        Some(path) => vec![path.to_owned()],
+        None => config::get_config_files()?,
+    };
+
+    let mut problems = vec![];
+    let mut parsed = true;
+    let mut checked: Vec<&PathBuf> = vec![];
+    for file in &files {
+        let content = match std::fs::read_to_string(file) {
+            Ok(content) => content,
+            // The config file is optional.
+            Err(err)
+                if path.is_none()
+                    && err.kind() == std::io::ErrorKind::NotFound =>
+            {
+                continue;
+            }
+            Err(err) => {
+                problems.push(format!("{}: {err}", file.display()));
+                parsed = false;
+                continue;
+            }
+        };
+        checked.push(file);
+        match toml::from_str::<toml::Table>(&content) {
+            Ok(table) => {
+                if let Err(err) = config::parse_config_file(&content) {
+                    problems.push(format!("{}: {err}", file.display()));
+                    parsed = false;
+                }
+                for problem in check_keys(&table) {
+                    problems.push(format!("{}: {problem}", file.display()));
+                }
+            }
+            Err(err) => {
+                problems.push(format!("{}: {err}", file.display()));
+                parsed = false;
+            }
+        }
+    }
+    if parsed && checked.is_empty() {
+        return Ok("There is no config file.".to_owned());
+    }
+
+    // Problems with rules and references are only meaningful for the merged
+    // config.
+    if parsed {
+        match config::read_config_files(&files) {
+            Ok(config) => problems.extend(check_config(&config)),
+            Err(err) => problems.push(err),
+        }
+    }
+
+    if problems.is_empty() {
+        let names: Vec<String> =
+            checked.iter().map(|p| p.display().to_string()).collect();
+        Ok(format!("The config in {} is valid.", names.join(", ")))
+    } else {
+        Err(problems.join("\n"))
+    }
+}
+
+/// Returns the names of the fields of a struct as known to serde.  Structs
+/// with flattened fields don't reveal them.
+fn fields<'de, T: Deserialize<'de>>() -> Vec<&'static str> {
+    let mut fields = vec![];
+    let _ = T::deserialize(FieldsProbe(&mut fields));
+    fields
+}
+
+/// A deserializer which only records the fields of the struct which is
+/// deserialized.
+struct FieldsProbe<'a>(&'a mut Vec<&'static str>);
+
+impl<'de> serde::Deserializer<'de> for FieldsProbe<'_> {
+    type Error = serde::de::value::Error;
+
+    fn deserialize_any<V: Visitor<'de>>(
+        self,
+        _visitor: V,
+    ) -> Result<V::Value, Self::Error> {
+        Err(serde::de::Error::custom("Not a struct."))
+    }
+
+    fn deserialize_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        fields: &'static [&'static str],
+        _visitor: V,
+    ) -> Result<V::Value, Self::Error> {
+        self.0.extend(fields);
+        Err(serde::de::Error::custom("Probed the fields."))
+    }
+
+    serde::forward_to_deserialize_any! {
+        bool i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 f32 f64 char str string
+        bytes byte_buf option unit unit_struct newtype_struct seq tuple
+        tuple_struct map enum identifier ignored_any
+    }
+}
+
+/// Returns the keys of entries with flattened match options, e.g., rules.
+fn with_match_opts(keys: &[&'static str]) -> Vec<&'static str> {
+    let mut keys = keys.to_vec();
+    keys.extend(fields::<MatchOptions>());
+    keys
+}
+
+/// Returns the known keys of the given top-level table or array of tables.
+/// Tables with arbitrary keys like `aliases` have none.
+fn known_keys(section: &str) -> Option<Vec<&'static str>> {
+    Some(match section {
+        "pip" => fields::<PipConfig>(),
+        "auto_name_workspaces" => fields::<AutoNameWorkspacesConfig>(),
+        "swallow" => fields::<SwallowConfig>(),
+        "hooks" => fields::<HooksConfig>(),
+        "timer" => fields::<TimerConfig>(),
+        "project" => fields::<Project>(),
+        "output_profile" => fields::<OutputProfile>(),
+        "follow_mode_rule" => with_match_opts(&["focus", "scope"]),
+        "workspace_rule" => with_match_opts(&["workspace"]),
+        "rule" => with_match_opts(&["actions"]),
+        "urgency_rule" => with_match_opts(&["notify", "command"]),
+        "close_hook" => with_match_opts(&["command"]),
+        _ => return None,
+    })
+}
+
+/// Returns the tables of a table or an array of tables with their paths.
+fn tables<'a>(
+    path: &str,
+    value: &'a toml::Value,
+) -> Vec<(String, &'a toml::Table)> {
+    match value {
+        toml::Value::Table(table) => vec![(path.to_owned(), table)],
+        toml::Value::Array(values) => values
+            .iter()
+            .enumerate()
+            .filter_map(|(i, v)| Some((format!("{path}[{i}]"), v.as_table()?)))
+            .collect(),
+        _ => vec![],
+    }
+}
+
+fn unknown_keys(
+    path: &str,
+    table: &toml::Table,
+    known: &[&str],
+) -> Vec<String> {
+    table
+        .keys()
+        .filter(|key| !known.contains(&key.as_str()))
+        .map(|key| {
+            if path.is_empty() {
+                format!("Unknown key {key}.")
+            } else {
+                format!("Unknown key {key} in {path}.")
+            }
+        })
+        .collect()
+}
+
+/// Returns the keys of the config file serde would silently ignore.
+fn check_keys(table: &toml::Table) -> Vec<String> {
+    let mut problems = unknown_keys("", table, &fields::<Config>());
+    for (section, value) in table {
+        let Some(known) = known_keys(section) else {
+            continue;
+        };
+        for (path, table) in tables(section, value) {
+            problems.extend(unknown_keys(&path, table, &known));
+            let app_keys = match section.as_str() {
+                "project" => with_match_opts(&["command", "floating"]),
+                "output_profile" => with_match_opts(&["output"]),
+                _ => continue,
+            };
+            if let Some(apps) = table.get("app") {
+                for (path, app) in tables(&format!("{path}.app"), apps) {
+                    problems.extend(unknown_keys(&path, app, &app_keys));
+                }
+            }
+        }
+    }
+    if let Some(toml::Value::Table(presets)) = table.get("presets") {
+        for (name, preset) in presets {
+            if let Some(preset) = preset.as_table() {
+                let path = format!("presets.{name}");
+                problems.extend(unknown_keys(
+                    &path,
+                    preset,
+                    &fields::<Preset>(),
+                ));
+            }
+        }
+    }
+    // Profiles contain settings like the top level.
+    if let Some(toml::Value::Table(profiles)) = table.get("profiles") {
+        for (name, profile) in profiles {
+            let Some(profile) = profile.as_table() else {
+                continue;
+            };
+            if let Err(err) =
+                toml::Value::Table(profile.clone()).try_into::<Config>()
+            {
+                problems.push(format!("Profile {name}: {err}"));
+            }
+            for problem in check_keys(profile) {
+                problems.push(format!("Profile {name}: {problem}"));
+            }
+        }
+    }
+    problems
+}
+
+/// Parses a nirius command line without applying the configured defaults.
+fn parse_cmd(args: &[String]) -> Result<NiriusCmd, String> {
+    NiriusCmd::try_parse_from(
+        std::iter::once("nirius").chain(args.iter().map(String::as_str)),
+    )
+    .map_err(|e| {
+        let msg = e.to_string();
+        let msg = msg.lines().next().unwrap_or_default();
+        msg.trim_start_matches("error: ").to_owned()
+    })
+}
+
+/// Returns invalid regexes, rules which never apply or contradict
+/// themselves, and references to unknown commands, aliases and outputs.
+fn check_config(config: &Config) -> Vec<String> {
+    let mut problems = vec![];
+    let mut check_regexes = |path: String, match_opts: &MatchOptions| {
+        if let Err(err) = match_opts.check_regexes() {
+            problems.push(format!("Invalid regex in {path}: {err}"));
+        }
+    };
+    for (i, rule) in config.follow_mode_rules.iter().enumerate() {
+        check_regexes(format!("follow_mode_rule[{i}]"), &rule.match_opts);
+    }
+    for (i, rule) in config.workspace_rules.iter().enumerate() {
+        check_regexes(format!("workspace_rule[{i}]"), &rule.match_opts);
+    }
+    for (i, rule) in config.rules.iter().enumerate() {
+        check_regexes(format!("rule[{i}]"), &rule.match_opts);
+    }
+    for (i, rule) in config.urgency_rules.iter().enumerate() {
+        check_regexes(format!("urgency_rule[{i}]"), &rule.match_opts);
+    }
+    for (i, hook) in config.close_hooks.iter().enumerate() {
+        check_regexes(format!("close_hook[{i}]"), &hook.match_opts);
+    }
+    for (i, project) in config.projects.iter().enumerate() {
+        for (j, app) in project.apps.iter().enumerate() {
+            check_regexes(format!("project[{i}].app[{j}]"), &app.match_opts);
+        }
+    }
+    for (i, profile) in config.output_profiles.iter().enumerate() {
+        for (j, app) in profile.apps.iter().enumerate() {
+            let path = format!("output_profile[{i}].app[{j}]");
+            check_regexes(path, &app.match_opts);
+        }
+    }
+    if let Err(err) = Regex::new(&config.swallow.terminal_app_id) {
+        problems.push(format!("Invalid regex in swallow: {err}"));
+    }
+
+    // Only the first of several rules with the same match options applies.
+    let shadowed = |opts: Vec<&MatchOptions>, section: &str| {
+        let mut problems = vec![];
+        for (j, b) in opts.iter().enumerate() {
+            if let Some(i) = opts[..j].iter().position(|a| a == b) {
+                problems.push(format!(
+                    "{section}[{j}] never applies because {section}[{i}] \
+                     has the same matching options."
+                ));
+            }
+        }
+        problems
+    };
+    problems.extend(shadowed(
+        config
+            .follow_mode_rules
+            .iter()
+            .map(|r| &r.match_opts)
+            .collect(),
+        "follow_mode_rule",
+    ));
+    problems.extend(shadowed(
+        config
+            .workspace_rules
+            .iter()
+            .map(|r| &r.match_opts)
+            .collect(),
+        "workspace_rule",
+    ));
+    for (i, rule) in config.rules.iter().enumerate() {
+        if rule.actions.contains(&RuleAction::Float)
+            && rule.actions.contains(&RuleAction::Tile)
+        {
+            problems.push(format!("rule[{i}] both floats and tiles windows."));
+        }
+        let workspaces = rule
+            .actions
+            .iter()
+            .filter(|a| {
+                matches!(a, RuleAction::Workspace(_) | RuleAction::Output(_))
+            })
+            .count();
+        if workspaces > 1 {
+            problems.push(format!(
+                "rule[{i}] moves windows to {workspaces} workspaces."
+            ));
+        }
+    }
+    for (j, project) in config.projects.iter().enumerate() {
+        if let Some(i) = config.projects[..j]
+            .iter()
+            .position(|p| p.name == project.name)
+        {
+            problems.push(format!(
+                "project[{j}] has the same name {} as project[{i}].",
+                project.name
+            ));
+        }
+    }
+
+    let sorted = |outputs: &[String]| {
+        let mut outputs = outputs.to_vec();
+        outputs.sort();
+        outputs
+    };
+    for (j, profile) in config.output_profiles.iter().enumerate() {
+        if let Some(i) = config.output_profiles[..j]
+            .iter()
+            .position(|p| sorted(&p.outputs) == sorted(&profile.outputs))
+        {
+            problems.push(format!(
+                "output_profile[{j}] never applies because \
+                 output_profile[{i}] has the same outputs."
+            ));
+        }
+        let mut outputs: Vec<(String, &String)> = profile
+            .workspaces
+            .iter()
+            .map(|(ws, o)| (format!("workspace {ws}"), o))
+            .collect();
+        outputs.sort();
+        for (k, app) in profile.apps.iter().enumerate() {
+            outputs.push((format!("app[{k}]"), &app.output));
+        }
+        for (what, output) in outputs {
+            if !profile.outputs.contains(output) {
+                problems.push(format!(
+                    "The output {output} of {what} of output_profile[{j}] \
+                     isn't one of its outputs."
+                ));
+            }
+        }
+    }
+
+    let mut aliases: Vec<_> = config.aliases.iter().collect();
+    aliases.sort();
+    for (name, lines) in aliases {
+        for line in lines {
+            let cmd_lines = match util::split_command_lines(line) {
+                Ok(cmd_lines) => cmd_lines,
+                Err(err) => {
+                    problems.push(format!("Alias {name}: {err}"));
+                    continue;
+                }
+            };
+            for args in cmd_lines {
+                match parse_cmd(&args) {
+                    Ok(NiriusCmd::Run { alias })
+                        if !config.aliases.contains_key(&alias) =>
+                    {
+                        problems.push(format!(
+                            "Alias {name} runs the unknown alias {alias}."
+                        ))
+                    }
+                    Ok(cmd) if !cmd.runs_on_state() => problems.push(format!(
+                        "Alias {name}: {} can't be part of a batch.",
+                        args[0]
+                    )),
+                    Ok(_) => (),
+                    Err(err) => problems.push(format!("Alias {name}: {err}")),
+                }
+            }
+        }
+    }
+    for (i, timer) in config.timers.iter().enumerate() {
+        if let Err(err) = parse_cmd(&timer.command) {
+            problems.push(format!("timer[{i}]: {err}"));
+        }
+    }
+    let mut defaults: Vec<&String> = config.defaults.keys().collect();
+    defaults.sort();
+    let cmd = NiriusCmd::command();
+    for name in defaults {
+        match cmd.find_subcommand(name) {
+            None => {
+                problems.push(format!("Defaults for unknown command {name}."))
+            }
+            Some(sub) if sub.has_subcommands() => problems.push(format!(
+                "Defaults for {name} are ignored because it has subcommands."
+            )),
+            Some(_) => (),
+        }
+    }
+    let mut presets: Vec<_> = config.presets.iter().collect();
+    presets.sort_by_key(|(name, _)| *name);
+    for (name, preset) in presets {
+        if preset.x.is_none()
+            && preset.y.is_none()
+            && preset.width.is_none()
+            && preset.height.is_none()
+        {
+            problems.push(format!("Preset {name} changes nothing."));
+        }
+    }
+    if config.default_mark.as_ref().is_some_and(String::is_empty) {
+        problems.push("The default_mark is empty.".to_owned());
+    }
+    if let Some(script) = &config.script
+        && !script.exists()
+    {
+        problems
+            .push(format!("The script {} doesn't exist.", script.display()));
+    }
+    problems
+}